@@ -0,0 +1,22 @@
+use config::CaesiumAdminConfig;
+use modules::authentication::AuthenticationUserInfo;
+
+/// The users, and members of groups, who can perform actions on any crate.
+pub struct Administrators {
+    users: Vec<String>,
+    groups: Vec<String>,
+}
+
+impl Administrators {
+    pub fn new(config: &CaesiumAdminConfig) -> Administrators {
+        Administrators {
+            users: config.users.clone().unwrap_or_default(),
+            groups: config.groups.clone().unwrap_or_default(),
+        }
+    }
+
+    pub fn is_admin(&self, userinfo: &AuthenticationUserInfo) -> bool {
+        self.users.contains(&userinfo.sub) ||
+            userinfo.groups.iter().any(|group| self.groups.contains(group))
+    }
+}
@@ -3,6 +3,7 @@ use ::modules;
 
 use std;
 use std::io::Read;
+use std::sync::Arc;
 
 use toml;
 
@@ -11,18 +12,44 @@ pub struct CaesiumConfig {
     pub registry: CeasiumRegistryConfig,
     pub storage: CaesiumStorageConfig,
     pub authentication: Option<CaesiumAuthenticationConfig>,
+    pub authorization: Option<CaesiumAuthorizationConfig>,
     pub server: Option<CaesiumServerConfig>,
+    pub policy: Option<CaesiumPolicyConfig>,
+    pub admin: Option<CaesiumAdminConfig>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CeasiumRegistryConfig {
     pub index: String,
+    pub allowed_registries: Option<Vec<String>>,
+    pub auth_required: Option<bool>,
+    pub hide_unreadable_crates: Option<bool>,
+}
+
+const CRATES_IO_INDEX: &'static str = "https://github.com/rust-lang/crates.io-index";
+
+impl CeasiumRegistryConfig {
+    /// The registries, other than this one, that crates may depend on. When
+    /// this is not configured only crates.io is allowed.
+    pub fn allowed_registries(&self) -> Vec<String> {
+        match self.allowed_registries {
+            Some(ref registries) => registries.clone(),
+            None => vec![CRATES_IO_INDEX.to_string()],
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CaesiumStorageConfig {
     pub file: Option<CaesiumFileStorageConfig>,
     pub artifactory: Option<CaesiumArtifactoryStorageConfig>,
+    pub s3: Option<CaesiumS3StorageConfig>,
+    pub azure: Option<CaesiumAzureStorageConfig>,
+    pub gcs: Option<CaesiumGcsStorageConfig>,
+    pub oci: Option<CaesiumOciStorageConfig>,
+    pub http: Option<CaesiumHttpStorageConfig>,
+    pub replication: Option<CaesiumReplicationStorageConfig>,
+    pub cache: Option<CaesiumCacheStorageConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -33,18 +60,175 @@ pub struct CaesiumFileStorageConfig {
 #[derive(Debug, Deserialize)]
 pub struct CaesiumArtifactoryStorageConfig {
     pub base_url: String,
-    pub api_key: String,
+
+    // Exactly one of these must be set, the username also needs the password
+    pub api_key: Option<String>,
+    pub access_token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+
+    // Needed to find the Artifactory API from the base URL, for signed URLs
+    // and checking whether crates exist
+    pub repository: Option<String>,
+
+    // Redirects downloads to signed URLs which are valid for this long
+    pub presign_expiry: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumS3StorageConfig {
+    pub bucket: String,
+    pub prefix: Option<String>,
+    pub region: Option<String>,
+
+    // Needed for S3 compatible stores, such as MinIO
+    pub endpoint: Option<String>,
+    pub path_style: Option<bool>,
+
+    // Taken from the AWS environment variables when not set
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+
+    // Redirects downloads to pre-signed URLs which are valid for this long
+    pub presign_expiry: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumAzureStorageConfig {
+    pub account: String,
+    pub container: String,
+    pub prefix: Option<String>,
+
+    // Needed for the Azurite emulator
+    pub endpoint: Option<String>,
+
+    // Exactly one of these must be set
+    pub access_key: Option<String>,
+    pub sas_token: Option<String>,
+
+    // Redirects downloads to SAS URLs which are valid for this long, this
+    // needs the access key
+    pub presign_expiry: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumGcsStorageConfig {
+    pub bucket: String,
+    pub prefix: Option<String>,
+
+    // Needed for fake-gcs-server
+    pub endpoint: Option<String>,
+
+    // The service account JSON key, requests are anonymous without it
+    pub credentials_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumOciStorageConfig {
+    pub url: String,
+    pub namespace: Option<String>,
+
+    // Used directly, or to get a token from the registry's token service
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumHttpStorageConfig {
+    pub base_url: String,
+    pub auth: Option<modules::storage::http::HttpAuthentication>,
+
+    // Needed for WebDAV servers
+    pub create_collections: Option<bool>,
+
+    // The status codes which mean that each request succeeded
+    pub upload_status: Option<Vec<u16>>,
+    pub download_status: Option<Vec<u16>>,
+    pub delete_status: Option<Vec<u16>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumReplicationStorageConfig {
+    // The storage to write to, in the order that it is read from
+    pub backends: Vec<String>,
+    pub policy: Option<modules::storage::replicated::ReplicationPolicy>,
+
+    // Where to record crates which are missing from some of the backends
+    pub under_replicated_location: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumCacheStorageConfig {
+    pub location: String,
+    pub max_size_mb: u64,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CaesiumAuthenticationConfig {
+    // The backends to try, in order
+    pub backends: Option<Vec<String>>,
     pub openid: Option<CaesiumOpenIdConfig>,
+    pub tokens: Option<CaesiumTokensConfig>,
+    pub trusted_publishing: Option<CaesiumTrustedPublishingConfig>,
+    pub paseto: Option<CaesiumPasetoConfig>,
+    pub ldap: Option<CaesiumLdapConfig>,
+    pub certificate: Option<CaesiumCertificateConfig>,
     pub oauth2: Option<CaesiumOAuth2Config>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CaesiumOpenIdConfig {
     pub openid_configuration_url: String,
+    pub groups_claims: Option<Vec<String>>,
+    pub claims: Option<Vec<String>>,
+
+    // Used to log users in from a browser to issue API tokens
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub redirect_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumTokensConfig {
+    pub location: String,
+    pub max_lifetime: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumTrustedPublishingConfig {
+    pub issuer: String,
+    pub audience: String,
+    pub jwks_url: Option<String>,
+    pub location: String,
+    pub token_lifetime: Option<u64>,
+    pub repository_claim: Option<String>,
+    pub ref_claim: Option<String>,
+    pub workflow_claim: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumPasetoConfig {
+    pub keys_file: String,
+    pub registry_url: Option<String>,
+    pub max_age: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumLdapConfig {
+    pub url: String,
+    pub user_dn: String,
+    pub service_account_dn: Option<String>,
+    pub name_attribute: Option<String>,
+    pub email_attribute: Option<String>,
+    pub group_base_dn: Option<String>,
+    pub group_filter: Option<String>,
+    pub group_attribute: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumCertificateConfig {
+    pub identity: Option<modules::authentication::certificate::CertificateIdentity>,
+    pub groups: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -56,9 +240,65 @@ pub struct CaesiumOAuth2Config {
     pub scope: Vec<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct CaesiumPolicyConfig {
+    pub upstream: Option<CaesiumUpstreamPolicyConfig>,
+    pub typosquatting: Option<CaesiumTyposquattingPolicyConfig>,
+    pub namespaces: Option<Vec<CaesiumNamespacePolicyConfig>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumUpstreamPolicyConfig {
+    pub index_location: String,
+    pub allowed: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumTyposquattingPolicyConfig {
+    pub popular_names: Option<Vec<String>>,
+    pub popular_names_file: Option<String>,
+    pub max_distance: Option<usize>,
+    pub action: Option<::typosquatting::TyposquattingAction>,
+    pub hold_location: Option<String>,
+    pub approved: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumNamespacePolicyConfig {
+    pub pattern: String,
+    pub users: Option<Vec<String>>,
+    pub groups: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumAuthorizationConfig {
+    pub opa: Option<CaesiumOpaAuthorizationConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumOpaAuthorizationConfig {
+    pub url: String,
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumAdminConfig {
+    pub users: Option<Vec<String>>,
+    pub groups: Option<Vec<String>>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CaesiumServerConfig {
     pub port: Option<u16>,
+    pub tls: Option<CaesiumTlsConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CaesiumTlsConfig {
+    pub certificate: String,
+    pub key: String,
+    pub client_ca: Option<String>,
+    pub client_certificate_required: Option<bool>,
 }
 
 impl CaesiumConfig {
@@ -70,21 +310,193 @@ impl CaesiumConfig {
     }
 
     pub fn create_storage_module(&self) -> Box<modules::storage::CrateStorage> {
-        if let Some(ref file) = self.storage.file {
-            Box::new(modules::storage::file::FileCrateStorage::new(&file.location))
-        } else if let Some(ref artifactory) = self.storage.artifactory {
-            Box::new(modules::storage::artifactory::ArtifactoryCrateStorage::new(&artifactory.base_url, &artifactory.api_key))
+        let storage = self.create_uncached_storage_module();
+
+        match self.storage.cache {
+            Some(ref cache) => Box::new(modules::storage::cache::CachedCrateStorage::new(cache, storage)),
+            None => storage,
+        }
+    }
+
+    fn create_uncached_storage_module(&self) -> Box<modules::storage::CrateStorage> {
+        if let Some(ref replication) = self.storage.replication {
+            let backends = replication.backends.iter()
+                                               .map(|name| (name.clone(), self.create_named_storage_module(name)))
+                                               .collect();
+            return Box::new(modules::storage::replicated::ReplicatedCrateStorage::new(replication, backends));
+        }
+
+        // Without replication the first storage which is configured is used
+        let name = vec![("file", self.storage.file.is_some()),
+                        ("artifactory", self.storage.artifactory.is_some()),
+                        ("s3", self.storage.s3.is_some()),
+                        ("azure", self.storage.azure.is_some()),
+                        ("gcs", self.storage.gcs.is_some()),
+                        ("oci", self.storage.oci.is_some()),
+                        ("http", self.storage.http.is_some())]
+            .into_iter()
+            .find(|&(_, configured)| configured)
+            .map(|(name, _)| name)
+            .expect("No storage config present");
+
+        self.create_named_storage_module(name)
+    }
+
+    fn create_named_storage_module(&self, name: &str) -> Box<modules::storage::CrateStorage> {
+        match name {
+            "file" => {
+                let file = self.storage.file.as_ref().expect("The file storage requires [storage.file] to be configured");
+                Box::new(modules::storage::file::FileCrateStorage::new(&file.location))
+            }
+            "artifactory" => {
+                let artifactory = self.storage.artifactory.as_ref().expect("The artifactory storage requires [storage.artifactory] to be configured");
+                Box::new(modules::storage::artifactory::ArtifactoryCrateStorage::new(artifactory))
+            }
+            "s3" => {
+                let s3 = self.storage.s3.as_ref().expect("The s3 storage requires [storage.s3] to be configured");
+                Box::new(modules::storage::s3::S3CrateStorage::new(s3))
+            }
+            "azure" => {
+                let azure = self.storage.azure.as_ref().expect("The azure storage requires [storage.azure] to be configured");
+                Box::new(modules::storage::azure::AzureCrateStorage::new(azure))
+            }
+            "gcs" => {
+                let gcs = self.storage.gcs.as_ref().expect("The gcs storage requires [storage.gcs] to be configured");
+                Box::new(modules::storage::gcs::GcsCrateStorage::new(gcs))
+            }
+            "oci" => {
+                let oci = self.storage.oci.as_ref().expect("The oci storage requires [storage.oci] to be configured");
+                Box::new(modules::storage::oci::OciCrateStorage::new(oci))
+            }
+            "http" => {
+                let http = self.storage.http.as_ref().expect("The http storage requires [storage.http] to be configured");
+                Box::new(modules::storage::http::HttpCrateStorage::new(http))
+            }
+            _ => panic!("Unknown storage backend '{}'", name),
+        }
+    }
+
+    pub fn create_upstream_index(&self) -> Option<::upstream::UpstreamIndex> {
+        self.policy.as_ref()
+                   .and_then(|policy| policy.upstream.as_ref())
+                   .map(|upstream| ::upstream::UpstreamIndex::new(&upstream.index_location))
+    }
+
+    pub fn create_typosquatting_detector(&self) -> Option<::typosquatting::TyposquattingDetector> {
+        self.policy.as_ref()
+                   .and_then(|policy| policy.typosquatting.as_ref())
+                   .map(|typosquatting| ::typosquatting::TyposquattingDetector::new(typosquatting))
+    }
+
+    pub fn create_namespace_policy(&self) -> Option<::namespaces::NamespacePolicy> {
+        self.policy.as_ref()
+                   .and_then(|policy| policy.namespaces.as_ref())
+                   .map(|namespaces| ::namespaces::NamespacePolicy::new(namespaces))
+    }
+
+    fn create_openid_module(&self, openid: &CaesiumOpenIdConfig) -> modules::authentication::openid::OpenIdAuthentication {
+        let groups_claims = openid.groups_claims.clone().unwrap_or(vec!["groups".to_string()]);
+        let claims = openid.claims.clone().unwrap_or_default();
+        modules::authentication::openid::OpenIdAuthentication::new(&openid.openid_configuration_url,
+                                                                   groups_claims,
+                                                                   claims)
+    }
+
+    pub fn create_token_store(&self) -> Option<Arc<modules::authentication::tokens::ApiTokenStore>> {
+        self.authentication.as_ref()
+                           .and_then(|auth| auth.tokens.as_ref())
+                           .map(|tokens| Arc::new(modules::authentication::tokens::ApiTokenStore::new(&tokens.location, tokens.max_lifetime)))
+    }
+
+    pub fn create_authentication_module(&self, tokens: Option<Arc<modules::authentication::tokens::ApiTokenStore>>) -> Option<Box<modules::authentication::Authentication>> {
+        let auth = match self.authentication {
+            Some(ref auth) => auth,
+            None => return None,
+        };
+
+        // By default client certificates and tokens which can be recognised
+        // from their format are checked first, and anything else is passed on
+        // to OpenID or LDAP.
+        let names = auth.backends.clone().unwrap_or_else(|| {
+            vec![("certificate", auth.certificate.is_some()),
+                 ("tokens", tokens.is_some()),
+                 ("paseto", auth.paseto.is_some()),
+                 ("ldap", auth.ldap.is_some()),
+                 ("openid", auth.openid.is_some())]
+                .into_iter()
+                .filter(|&(_, configured)| configured)
+                .map(|(name, _)| name.to_string())
+                .collect()
+        });
+
+        let mut backends = Vec::new();
+        for name in names {
+            let backend: Box<modules::authentication::Authentication> = match name.as_str() {
+                "certificate" => {
+                    let certificate = auth.certificate.as_ref().expect("The certificate backend requires [authentication.certificate] to be configured");
+                    Box::new(modules::authentication::certificate::CertificateAuthentication::new(certificate))
+                }
+                "tokens" => {
+                    let tokens = tokens.clone().expect("The tokens backend requires [authentication.tokens] to be configured");
+                    Box::new(modules::authentication::tokens::ApiTokenAuthentication::new(tokens))
+                }
+                "paseto" => {
+                    let paseto = auth.paseto.as_ref().expect("The paseto backend requires [authentication.paseto] to be configured");
+                    Box::new(modules::authentication::paseto::PasetoAuthentication::new(paseto, &self.registry.index))
+                }
+                "openid" => {
+                    let openid = auth.openid.as_ref().expect("The openid backend requires [authentication.openid] to be configured");
+                    Box::new(self.create_openid_module(openid))
+                }
+                "ldap" => {
+                    let ldap = auth.ldap.as_ref().expect("The ldap backend requires [authentication.ldap] to be configured");
+                    Box::new(modules::authentication::ldap::LdapAuthentication::new(ldap))
+                }
+                // "oauth2" => Box::new(modules::authentication::oauth2::OAuth2Authentication::new()),
+                _ => panic!("Unknown authentication backend '{}'", name),
+            };
+            backends.push((name, backend));
+        }
+
+        if backends.is_empty() {
+            None
         } else {
-            panic!("No storage config present");
+            Some(Box::new(modules::authentication::chain::ChainedAuthentication::new(backends)))
+        }
+    }
+
+    pub fn create_trusted_publishing(&self, tokens: Option<Arc<modules::authentication::tokens::ApiTokenStore>>) -> Option<modules::authentication::trusted_publishing::TrustedPublishing> {
+        let trusted_publishing = match self.authentication.as_ref().and_then(|auth| auth.trusted_publishing.as_ref()) {
+            Some(trusted_publishing) => trusted_publishing,
+            None => return None,
+        };
+
+        let tokens = tokens.expect("Trusted publishing requires [authentication.tokens] to be configured");
+        Some(modules::authentication::trusted_publishing::TrustedPublishing::new(trusted_publishing, tokens))
+    }
+
+    pub fn create_login_flow(&self, tokens: Option<Arc<modules::authentication::tokens::ApiTokenStore>>) -> Option<::login::LoginFlow> {
+        let openid = match self.authentication.as_ref().and_then(|auth| auth.openid.as_ref()) {
+            Some(openid) => openid,
+            None => return None,
+        };
+
+        match (tokens, &openid.client_id, &openid.client_secret, &openid.redirect_url) {
+            (Some(tokens), &Some(ref client_id), &Some(ref client_secret), &Some(ref redirect_url)) => {
+                Some(::login::LoginFlow::new(self.create_openid_module(openid),
+                                             client_id,
+                                             client_secret,
+                                             redirect_url,
+                                             tokens))
+            }
+            _ => None,
         }
     }
 
-    pub fn create_authentication_module(&self) -> Option<Box<modules::authentication::Authentication>> {
-        if let Some(ref auth) = self.authentication {
-            if let Some(ref openid) = auth.openid {
-                Some(Box::new(modules::authentication::openid::OpenIdAuthentication::new(&openid.openid_configuration_url)))
-            // } else if let Some(ref oauth) = auth.oauth2 {
-            //     Some(Box::new(modules::authentication::oauth2::OAuth2Authentication::new()))
+    pub fn create_authorization_module(&self) -> Option<Box<modules::authorization::Authorization>> {
+        if let Some(ref authorization) = self.authorization {
+            if let Some(ref opa) = authorization.opa {
+                Some(Box::new(modules::authorization::opa::OpaAuthorization::new(&opa.url, opa.timeout)))
             } else {
                 None
             }
@@ -8,12 +8,53 @@ error_chain! {
         Serde(::serde_json::Error);
         Hyper(::hyper::Error);
         UriError(::hyper::error::UriError);
+        Openssl(::openssl::error::ErrorStack);
     }
 
     errors {
+        AuthenticationRequired(v: String) {
+            description("Authentication required"),
+            display("Authentication required: '{}'", v),
+        }
+
         AuthenticationError(v: String) {
             description("Failed to authenticate"),
             display("Failed to authenticate: '{}'", v),
         }
+
+        UnrecognisedToken(v: String) {
+            description("Token not recognised"),
+            display("Token not recognised: '{}'", v),
+        }
+
+        AuthenticationBackendError(v: String) {
+            description("Authentication backend unavailable"),
+            display("Authentication backend unavailable: '{}'", v),
+        }
+
+        AuthorizationError(v: String) {
+            description("Not authorized"),
+            display("Not authorized: '{}'", v),
+        }
+
+        PolicyServiceError(v: String) {
+            description("Policy service unavailable"),
+            display("Policy service unavailable: '{}'", v),
+        }
+
+        ValidationError(v: String) {
+            description("Crate failed validation"),
+            display("Crate failed validation: '{}'", v),
+        }
+
+        PublishHeld(v: String) {
+            description("Crate held for approval"),
+            display("Crate held for approval by an administrator: '{}'", v),
+        }
+
+        IndexPushError(v: String) {
+            description("Failed to push the index"),
+            display("Failed to push the index: '{}'", v),
+        }
     }
 }
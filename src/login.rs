@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use hyper;
+use hyper::Client;
+use hyper::header::ContentType;
+use hyper_tls::HttpsConnector;
+use rand::{OsRng, Rng};
+use serde_json;
+use serde_json::Value;
+use tokio_core;
+use url;
+
+use errors::*;
+use modules::authentication::Authentication;
+use modules::authentication::chain;
+use modules::authentication::openid::OpenIdAuthentication;
+use modules::authentication::tokens::{ApiTokenOptions, ApiTokenStore};
+
+// How long a user has to log in with the provider before the login expires.
+const LOGIN_TIMEOUT_SECS: u64 = 600;
+
+/// Logs users in with the OpenID authorization code flow, so that they can be
+/// issued with an API token for cargo.
+pub struct LoginFlow {
+    openid: OpenIdAuthentication,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    tokens: Arc<ApiTokenStore>,
+
+    // The state for each login which has been started, along with when it
+    // was started.
+    logins: Mutex<HashMap<String, Instant>>,
+}
+
+impl LoginFlow {
+    pub fn new(openid: OpenIdAuthentication,
+               client_id: &str,
+               client_secret: &str,
+               redirect_url: &str,
+               tokens: Arc<ApiTokenStore>) -> LoginFlow {
+        LoginFlow {
+            openid: openid,
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            redirect_url: redirect_url.to_string(),
+            tokens: tokens,
+            logins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts a login, returning the URL of the provider's authorization
+    /// endpoint that the user should be redirected to.
+    pub fn start(&self) -> Result<String> {
+        let mut bytes = [0u8; 16];
+        OsRng::new().map_err(|e| format!("Failed to create random number generator: {}", e))?
+                    .fill_bytes(&mut bytes);
+        let state = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+        let mut logins = self.logins.lock().unwrap();
+        logins.retain(|_, started| started.elapsed() < Duration::from_secs(LOGIN_TIMEOUT_SECS));
+        logins.insert(state.clone(), Instant::now());
+
+        let mut url = url::Url::parse(&self.openid.authorization_endpoint.to_string())?;
+        url.query_pairs_mut()
+           .append_pair("response_type", "code")
+           .append_pair("client_id", &self.client_id)
+           .append_pair("redirect_uri", &self.redirect_url)
+           .append_pair("scope", "openid profile")
+           .append_pair("state", &state);
+
+        Ok(url.into_string())
+    }
+
+    /// Completes a login once the provider has redirected back to us, and
+    /// issues a new API token for the user.
+    pub fn finish(&self, code: &str, state: &str) -> Result<String> {
+        match self.logins.lock().unwrap().remove(state) {
+            Some(ref started) if started.elapsed() < Duration::from_secs(LOGIN_TIMEOUT_SECS) => {}
+            _ => bail!(ErrorKind::AuthenticationError("Login has expired, please try again".to_string())),
+        }
+
+        let access_token = self.exchange_code(code)?;
+        let userinfo = chain::namespaced("openid", self.openid.authenticate(&access_token)?);
+
+        let token = self.tokens.create(&userinfo, ApiTokenOptions::default())?;
+
+        println!("Issued API token to {}", userinfo.sub);
+
+        Ok(token)
+    }
+
+    // Exchanges the authorization code at the token endpoint for an access
+    // token, which can be used to get the user info.
+    fn exchange_code(&self, code: &str) -> Result<String> {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+
+        let client = Client::configure()
+            .connector(HttpsConnector::new(4, &handle).expect("Failed to setup HTTPS"))
+            .build(&handle);
+
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("client_id", &self.client_id)
+            .append_pair("client_secret", &self.client_secret)
+            .finish();
+
+        let mut request = hyper::Request::new(hyper::Method::Post, self.openid.token_endpoint.clone());
+        request.headers_mut().set(ContentType::form_url_encoded());
+        request.set_body(body);
+
+        let work = client.request(request).and_then(|res| {
+            let status = res.status();
+            res.body().concat2().map(move |body| (status, body))
+        });
+
+        let (status, body) = core.run(work)?;
+        if status != hyper::StatusCode::Ok {
+            bail!(ErrorKind::AuthenticationError(format!("Token endpoint returned {}", status)));
+        }
+
+        let response: Value = serde_json::from_slice(&body)?;
+        match response["access_token"].as_str() {
+            Some(access_token) => Ok(access_token.to_string()),
+            None => bail!(ErrorKind::AuthenticationError("No access token returned".to_string())),
+        }
+    }
+}
+
+/// Returns the value of a parameter in a query string.
+pub fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query.and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|&(ref key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    })
+}
+
+/// Builds the page shown to the user once they have logged in, this is the
+/// only time that the token is shown.
+pub fn token_page(token: &str) -> String {
+    format!("<!DOCTYPE html>
+<html>
+<head><title>Caesium API token</title></head>
+<body>
+<h1>Your new API token</h1>
+<p>This token will not be shown again, run the following to use it with cargo:</p>
+<pre>cargo login --registry &lt;registry&gt; {}</pre>
+</body>
+</html>
+", token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::test_dir;
+    use test_server::{TestResponse, TestServer};
+
+    /// An OpenID provider which issues an access token for any code, and
+    /// returns the user info for that access token.
+    fn provider() -> TestServer {
+        let url = Arc::new(Mutex::new(String::new()));
+
+        let server_url = url.clone();
+        let server = TestServer::start(move |request| {
+            let url = server_url.lock().unwrap().clone();
+            match request.path.as_str() {
+                "/.well-known/openid-configuration" => {
+                    TestResponse::json(&format!(r#"{{"authorization_endpoint": "{}/authorize",
+                                                     "token_endpoint": "{}/token",
+                                                     "userinfo_endpoint": "{}/userinfo"}}"#, url, url, url))
+                }
+                "/token" if String::from_utf8_lossy(&request.body).contains("code=good-code") => {
+                    TestResponse::json(r#"{"access_token": "access-token", "token_type": "Bearer"}"#)
+                }
+                "/token" => TestResponse::new(400, r#"{"error": "invalid_grant"}"#),
+                "/userinfo" if request.header("Authorization") == Some("Bearer access-token") => {
+                    TestResponse::json(r#"{"sub": "alice", "name": "Alice"}"#)
+                }
+                _ => TestResponse::new(401, ""),
+            }
+        });
+        *url.lock().unwrap() = server.url.clone();
+
+        server
+    }
+
+    fn login_flow(name: &str, provider: &TestServer) -> (LoginFlow, Arc<ApiTokenStore>) {
+        let dir = test_dir(name);
+
+        let openid = OpenIdAuthentication::new(&format!("{}/.well-known/openid-configuration", provider.url),
+                                               Vec::new(),
+                                               Vec::new());
+        let tokens = Arc::new(ApiTokenStore::new(&dir.join("tokens.json").to_string_lossy(), None));
+        let login = LoginFlow::new(openid, "caesium", "secret", "https://caesium.example.com/me", tokens.clone());
+
+        (login, tokens)
+    }
+
+    fn state(authorization_url: &str) -> String {
+        let url = url::Url::parse(authorization_url).unwrap();
+        query_param(url.query(), "state").unwrap()
+    }
+
+    #[test]
+    fn login_redirects_to_the_provider() {
+        let provider = provider();
+        let (login, _) = login_flow("login-start", &provider);
+
+        let url = login.start().unwrap();
+        assert!(url.starts_with(&format!("{}/authorize?", provider.url)));
+        assert_eq!(query_param(url::Url::parse(&url).unwrap().query(), "client_id"), Some("caesium".to_string()));
+        assert_eq!(query_param(url::Url::parse(&url).unwrap().query(), "redirect_uri"),
+                   Some("https://caesium.example.com/me".to_string()));
+
+        // Every login gets its own state
+        assert!(state(&url) != state(&login.start().unwrap()));
+    }
+
+    #[test]
+    fn unknown_states_are_rejected() {
+        let provider = provider();
+        let (login, tokens) = login_flow("login-state", &provider);
+        login.start().unwrap();
+
+        let e = login.finish("good-code", "made-up-state").unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthenticationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+
+        // The code is never sent to the provider
+        assert!(provider.requests().iter().all(|request| request.path != "/token"));
+        assert!(tokens.list("openid:alice").is_empty());
+    }
+
+    #[test]
+    fn codes_are_exchanged_for_a_token() {
+        let provider = provider();
+        let (login, tokens) = login_flow("login-exchange", &provider);
+        let state = state(&login.start().unwrap());
+
+        let token = login.finish("good-code", &state).unwrap();
+
+        let requests = provider.requests();
+        let exchange = requests.iter().find(|request| request.path == "/token").unwrap();
+        assert_eq!(exchange.method, "POST");
+        let body = String::from_utf8_lossy(&exchange.body).into_owned();
+        assert!(body.contains("grant_type=authorization_code"));
+        assert!(body.contains("client_id=caesium"));
+        assert!(body.contains("client_secret=secret"));
+        assert!(requests.iter().any(|request| request.path == "/userinfo"));
+
+        // The token belongs to the user who logged in
+        let api_token = tokens.find(&token).unwrap();
+        assert_eq!(api_token.userinfo.sub, "openid:alice");
+        assert_eq!(api_token.userinfo.backend, Some("openid".to_string()));
+    }
+
+    #[test]
+    fn failed_exchanges_do_not_issue_tokens() {
+        let provider = provider();
+        let (login, tokens) = login_flow("login-failed", &provider);
+        let state = state(&login.start().unwrap());
+
+        let e = login.finish("bad-code", &state).unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthenticationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert!(tokens.list("openid:alice").is_empty());
+    }
+
+    #[test]
+    fn tokens_are_only_shown_once() {
+        let provider = provider();
+        let (login, tokens) = login_flow("login-once", &provider);
+        let state = state(&login.start().unwrap());
+
+        let token = login.finish("good-code", &state).unwrap();
+        assert!(token_page(&token).contains(&token));
+
+        // The state can't be used to get another token
+        assert!(login.finish("good-code", &state).is_err());
+        assert_eq!(tokens.list("openid:alice").len(), 1);
+
+        // Only the hash of the token is kept
+        let stored = tokens.list("openid:alice").remove(0);
+        assert!(stored.hash != token);
+        assert!(!serde_json::to_string(&stored).unwrap().contains(&token));
+    }
+}
@@ -16,23 +16,56 @@ extern crate crypto;
 extern crate git2;
 extern crate toml;
 extern crate tokio_core;
+extern crate semver;
+extern crate flate2;
+extern crate tar;
+extern crate glob;
+extern crate base64;
+extern crate rand;
+extern crate openssl;
+extern crate chrono;
+extern crate ldap3;
+extern crate tokio_openssl;
 
+mod admin;
 mod config;
 mod errors;
+mod login;
 mod parser;
 mod modules;
+mod namespaces;
+mod owners;
 mod registry;
+mod tls;
+mod typosquatting;
+mod upstream;
+mod validation;
+
+#[cfg(test)]
+mod test_helpers;
+#[cfg(test)]
+mod test_server;
 
 use errors::*;
 
-use hyper::{Put, StatusCode};
+use hyper::{Get, Post, Put, Delete, StatusCode};
 use hyper::server::{Http, Service, Request, Response};
-use hyper::header::Authorization;
+use hyper::header::{Authorization, ContentType, Location};
 
 use futures::Stream;
 use futures::Future;
 
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use openssl::ssl::SslAcceptor;
+use tokio_openssl::SslAcceptorExt;
+
+use modules::authentication::{AuthenticationRequest, TokenScope};
+
+header! { (WwwAuthenticate, "WWW-Authenticate") => [String] }
+use modules::authentication::tokens::{ApiToken, ApiTokenOptions, ApiTokenStore};
+use modules::authentication::trusted_publishing::{TrustedPublisher, TrustedPublishing};
 
 
 struct Caesium {
@@ -43,9 +76,27 @@ struct Caesium {
 
     authentication: Option<Box<modules::authentication::Authentication>>,
 
-    // authorization: Option<modules::authorization::Authorisor>,
+    authorization: Option<Box<modules::authorization::Authorization>>,
 
     storage: Box<modules::storage::CrateStorage>,
+
+    upstream: Option<upstream::UpstreamIndex>,
+
+    typosquatting: Option<typosquatting::TyposquattingDetector>,
+
+    namespaces: Option<namespaces::NamespacePolicy>,
+
+    admins: Option<admin::Administrators>,
+
+    login: Option<login::LoginFlow>,
+
+    tokens: Option<Arc<ApiTokenStore>>,
+
+    trusted_publishing: Option<TrustedPublishing>,
+
+    // The crates which are being published, so that two publishes of a crate
+    // can't both be checked against the index before either is added
+    publishing: Mutex<HashSet<String>>,
 }
 
 impl Caesium {
@@ -53,119 +104,1853 @@ impl Caesium {
 
         let config = config::CaesiumConfig::new("registry.toml");
         let storage = config.create_storage_module();
-        let authentication = config.create_authentication_module();
+        let registry = registry::Registry::new(&config.registry.index.clone());
+
+        Caesium::with_storage(config, registry, storage)
+    }
+
+    fn with_storage(config: config::CaesiumConfig,
+                    registry: registry::Registry,
+                    storage: Box<modules::storage::CrateStorage>) -> Caesium {
+        let tokens = config.create_token_store();
+        let authentication = config.create_authentication_module(tokens.clone());
+        let login = config.create_login_flow(tokens.clone());
+        let trusted_publishing = config.create_trusted_publishing(tokens.clone());
+        let authorization = config.create_authorization_module();
+        let upstream = config.create_upstream_index();
+        let typosquatting = config.create_typosquatting_detector();
+        let namespaces = config.create_namespace_policy();
+        let admins = config.admin.as_ref().map(admin::Administrators::new);
+
+        // Without these every read would be refused, or no crate hidden
+        if config.registry.auth_required.unwrap_or(false) && authentication.is_none() {
+            panic!("auth_required needs [authentication] to be configured");
+        }
+        if config.registry.hide_unreadable_crates.unwrap_or(false) {
+            if !config.registry.auth_required.unwrap_or(false) {
+                panic!("hide_unreadable_crates needs auth_required to be set");
+            }
+            if authorization.is_none() {
+                panic!("hide_unreadable_crates needs a policy service in [authorization.opa]");
+            }
+        }
+
+        if let Some(ref upstream) = upstream {
+            Caesium::report_upstream_collisions(&registry, upstream);
+        }
 
         Caesium {
-            registry: registry::Registry::new(&config.registry.index.clone()),
+            registry: registry,
             config: config,
             storage: storage,
             authentication: authentication,
+            authorization: authorization,
+            upstream: upstream,
+            typosquatting: typosquatting,
+            namespaces: namespaces,
+            admins: admins,
+            login: login,
+            tokens: tokens,
+            trusted_publishing: trusted_publishing,
+            publishing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Reports any crates already in the index which share a name with a
+    /// crate in the upstream index.
+    fn report_upstream_collisions(registry: &registry::Registry, upstream: &upstream::UpstreamIndex) {
+        let names = registry.crate_names().expect("Failed to read crates from index");
+
+        for name in names {
+            if let Some(upstream_name) = upstream.find(&name) {
+                println!("WARNING: Crate {} collides with upstream crate {}", name, upstream_name);
+            }
+        }
+    }
+
+    fn authenticate(&self, credentials: &Credentials) -> Result<Option<modules::authentication::AuthenticationUserInfo>> {
+        self.authenticate_request(credentials, AuthenticationRequest::default())
+    }
+
+    /// Authenticates a token which is being used to make a change, so that
+    /// tokens signed for a particular change can be checked against it.
+    fn authenticate_request<'a>(&self, credentials: &'a Credentials, mut request: AuthenticationRequest<'a>) -> Result<Option<modules::authentication::AuthenticationUserInfo>> {
+        request.certificate = credentials.certificate.as_ref();
+
+        if let Some(ref authentication) = self.authentication {
+            Ok(Some(authentication.authenticate_request(&credentials.token, &request)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Checks that the user owns an existing crate, or is a CI pipeline which
+    /// the crate trusts. Registries without authentication are open to
+    /// everyone, but crates without recorded owners are closed until an
+    /// administrator sets them.
+    fn authorize_owner(&self, name: &str, userinfo: Option<&modules::authentication::AuthenticationUserInfo>) -> Result<()> {
+        let userinfo = match userinfo {
+            Some(userinfo) => userinfo,
+            None => return Ok(()),
+        };
+
+        if let Some(ref trusted_publishing) = self.trusted_publishing {
+            if trusted_publishing.is_trusted(name, userinfo) {
+                return Ok(());
+            }
+        }
+
+        match self.registry.crate_owners(name)? {
+            Some(ref owners) if owners.is_owner(userinfo) => Ok(()),
+            Some(_) => bail!(ErrorKind::AuthorizationError(format!("'{}' is not an owner of crate '{}'", userinfo.sub, name))),
+            None => bail!(ErrorKind::AuthorizationError(format!("crate '{}' has no owners, ask an administrator to set them", name))),
+        }
+    }
+
+    fn authorize(&self, request: &modules::authorization::AuthorizationRequest) -> Result<()> {
+        if let Some(ref authorization) = self.authorization {
+            authorization.authorize(request)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the caller can read from the registry, which needs a token
+    /// when the registry requires authentication. Returns false if the caller
+    /// is not allowed to read the named crate, so that it can be hidden.
+    fn can_read(&self, name: Option<&str>, credentials: &Credentials) -> Result<bool> {
+        if !self.config.registry.auth_required.unwrap_or(false) {
+            return Ok(true);
+        }
+
+        if credentials.token.is_empty() && credentials.certificate.is_none() {
+            bail!(ErrorKind::AuthenticationRequired("this registry requires a token".to_string()));
+        }
+
+        let userinfo = match self.authenticate(credentials)? {
+            Some(userinfo) => userinfo,
+            None => bail!(ErrorKind::AuthenticationError("the registry requires authentication but none is configured".to_string())),
+        };
+
+        if let Some(name) = name {
+            if self.config.registry.hide_unreadable_crates.unwrap_or(false) {
+                if let Err(e) = self.authorize(&modules::authorization::AuthorizationRequest::crate_action("read", name, Some(&userinfo))) {
+                    if let ErrorKind::AuthorizationError(_) = *e.kind() {
+                        return Ok(false);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Returns the index's `config.json`, telling cargo to send its token when
+    /// the registry requires authentication.
+    fn index_config(&self, credentials: &Credentials) -> Result<String> {
+        self.can_read(None, credentials)?;
+
+        let mut config = self.registry.index_config()?;
+        if self.config.registry.auth_required.unwrap_or(false) {
+            config.insert("auth-required".to_string(), serde_json::Value::Bool(true));
+        }
+
+        Ok(serde_json::to_string(&config)?)
+    }
+
+    /// Returns the index file of a crate for the sparse protocol, or `None` if
+    /// the crate does not exist or is hidden from the caller.
+    fn index_file(&self, name: &str, credentials: &Credentials) -> Result<Option<String>> {
+        if validation::validate_name(name).is_err() || !self.can_read(Some(name), credentials)? {
+            return Ok(None);
+        }
+
+        self.registry.index_contents(name)
+    }
+
+    /// Returns the crate file for a version of a crate, or where to download
+    /// it from if the storage supports it. This is `None` if the version does
+    /// not exist or the crate is hidden from the caller.
+    fn download(&self, name: &str, vers: &str, credentials: &Credentials) -> Result<Option<Download>> {
+        if validation::validate_name(name).is_err() || !self.can_read(Some(name), credentials)? {
+            return Ok(None);
+        }
+
+        match self.registry.crate_entries(name)?.into_iter().find(|entry| entry.vers == vers) {
+            Some(entry) => {
+                // A URL is only an optimisation, if one can't be made then the
+                // crate is sent from here instead.
+                match self.storage.download_url(&entry.name, &entry.vers) {
+                    Ok(Some(url)) => return Ok(Some(Download::Redirect(url))),
+                    Ok(None) => {}
+                    Err(e) => println!("Failed to get a download URL for {} {}, sending it instead: {}", entry.name, entry.vers, e),
+                }
+
+                Ok(Some(Download::Contents(self.storage.download_verified(&entry.name, &entry.vers, &entry.cksum)?)))
+            }
+            None => Ok(None),
         }
     }
 
-    fn publish(&self, manifest: &str, crate_tar: &[u8], token: &str) -> Result<()> {
+    /// Runs all of the checks that a publish goes through, without storing the
+    /// crate or updating the index.
+    fn check(&self, manifest: &registry::CargoManifest, crate_tar: &[u8], credentials: &Credentials) -> Result<Publication> {
 
-        let manifest: registry::CargoManifest = serde_json::from_str(&manifest).unwrap();
+        // The name is used to find the crate in the index, so it is checked
+        // before anything else
+        validation::validate_name(&manifest.name)?;
 
         // Authenticate
-        let userinfo = if let Some(ref authentication) = self.authentication {
-            Some(authentication.authenticate(token)?)
+        let cksum = registry::checksum(crate_tar);
+        let userinfo = self.authenticate_request(credentials, AuthenticationRequest {
+            mutation: Some("publish"),
+            name: Some(&manifest.name),
+            vers: Some(&manifest.vers),
+            cksum: Some(&cksum),
+            ..AuthenticationRequest::default()
+        })?;
+
+        // Authorize
+        let is_new_crate = self.registry.crate_entries(&manifest.name)?.is_empty();
+        if let Some(ref userinfo) = userinfo {
+            let scope = if is_new_crate { TokenScope::PublishNew } else { TokenScope::PublishUpdate };
+            userinfo.check_scope(scope, &manifest.name)?;
+        }
+
+        if let Some(ref namespaces) = self.namespaces {
+            namespaces.authorize(&manifest.name, userinfo.as_ref())?;
+        }
+
+        let owners = if is_new_crate {
+            userinfo.as_ref().map(owners::CrateOwners::new)
         } else {
+            self.authorize_owner(&manifest.name, userinfo.as_ref())?;
             None
         };
 
-        // Authorize
+        self.authorize(&modules::authorization::AuthorizationRequest::publish(manifest, userinfo.as_ref()))?;
+
+        // Validate
+        validation::validate_version(manifest, &self.registry)?;
+        validation::validate_dependencies(manifest,
+                                          &self.registry,
+                                          &self.config.registry.index,
+                                          &self.config.registry.allowed_registries())?;
+
+        if let Some(ref upstream) = self.upstream {
+            let allowed = self.config.policy.as_ref()
+                                            .and_then(|policy| policy.upstream.as_ref())
+                                            .and_then(|upstream| upstream.allowed.clone())
+                                            .unwrap_or_default();
+            validation::validate_upstream_name(manifest, &self.registry, upstream, &allowed)?;
+        }
+        validation::validate_tarball(manifest, crate_tar)?;
+
+        // New crates are compared against the existing crates, to catch names
+        // which imitate them
+        let held = match self.typosquatting {
+            Some(ref typosquatting) if is_new_crate => typosquatting.check(&manifest.name, &self.registry.crate_names()?)?,
+            _ => None,
+        };
+
+        Ok(Publication {
+            userinfo: userinfo,
+            warnings: validation::metadata_warnings(manifest),
+            held: held,
+            owners: owners,
+        })
+    }
+
+    fn validate(&self, manifest: &str, crate_tar: &[u8], credentials: &Credentials) -> Result<validation::PublishWarnings> {
+
+        let manifest: registry::CargoManifest = serde_json::from_str(&manifest)?;
+
+        let publication = self.check(&manifest, crate_tar, credentials)?;
+
+        if let Some(reason) = publication.held.clone() {
+            bail!(ErrorKind::PublishHeld(reason));
+        }
+
+        println!("Crate {} v{} passed validation for {}", manifest.name, manifest.vers, publication.username());
+
+        Ok(publication.warnings)
+    }
+
+    fn publish(&self, manifest: &str, crate_tar: &[u8], credentials: &Credentials) -> Result<validation::PublishWarnings> {
+
+        let manifest: registry::CargoManifest = serde_json::from_str(&manifest)?;
+
+        self.while_publishing(&manifest.name, || self.publish_crate(&manifest, crate_tar, credentials))
+    }
+
+    /// Runs `publish` while no other version of the crate is being published.
+    fn while_publishing<T, F: FnOnce() -> Result<T>>(&self, name: &str, publish: F) -> Result<T> {
+        // Crate names are unique regardless of case
+        let key = name.to_lowercase();
+        if !self.publishing.lock().unwrap().insert(key.clone()) {
+            bail!(ErrorKind::ValidationError(format!("crate '{}' is already being published, try again later", name)));
+        }
+
+        let result = publish();
+        self.publishing.lock().unwrap().remove(&key);
+
+        result
+    }
+
+    fn publish_crate(&self, manifest: &registry::CargoManifest, crate_tar: &[u8], credentials: &Credentials) -> Result<validation::PublishWarnings> {
+
+        let publication = self.check(manifest, crate_tar, credentials)?;
+
+        let publisher = publication.userinfo.as_ref().map(|userinfo| userinfo.sub.as_str());
+
+        if let Some(reason) = publication.held.clone() {
+            if let Some(ref typosquatting) = self.typosquatting {
+                typosquatting.hold(manifest, crate_tar, publisher, publication.owners.as_ref())?;
+            }
+
+            println!("Crate {} v{} from {} was held for approval: {}",
+                     manifest.name, manifest.vers, publication.username(), reason);
+
+            bail!(ErrorKind::PublishHeld(reason));
+        }
+
+        self.store_crate(manifest, crate_tar, publisher, publication.owners.as_ref())?;
+
+        println!("Crate {} v{} was uploaded by {}", manifest.name, manifest.vers, publication.username());
+
+        Ok(publication.warnings)
+    }
+
+    /// Stores a crate which has passed all checks and adds it to the index.
+    fn store_crate(&self,
+                   manifest: &registry::CargoManifest,
+                   crate_tar: &[u8],
+                   publisher: Option<&str>,
+                   owners: Option<&owners::CrateOwners>) -> Result<()> {
 
         // Now call into the storage driver to store the crate
-        self.storage.upload(&manifest, crate_tar)?;
+        self.storage.upload(manifest, crate_tar, publisher)?;
 
         // Now that everything is stored, we need to update the index file so
-        // that the crate is available.
-        self.registry.add_crate(&manifest, crate_tar)?;
-
-        let username = userinfo.and_then(|userinfo| userinfo.name)
-                               .unwrap_or("an anonymous user".to_string());
+        // that the crate is available. If the version didn't make it into the
+        // index the crate is removed from storage again, so that it can be
+        // published later, but once committed it must stay.
+        if let Err(e) = self.registry.add_crate(manifest, crate_tar, owners) {
+            match *e.kind() {
+                ErrorKind::IndexPushError(_) => {}
+                _ => {
+                    if let Err(delete_error) = self.storage.delete(&manifest.name, &manifest.vers) {
+                        println!("Failed to remove crate {} v{} from storage: {}", manifest.name, manifest.vers, delete_error);
+                    }
+                }
+            }
 
-        println!("Crate {} v{} was uploaded by {}", manifest.name, manifest.vers, username);
+            return Err(e);
+        }
 
         Ok(())
     }
-}
 
-struct CaesiumService {
-    caesium: Arc<Caesium>
-}
+    /// Returns the owners of a crate, or `None` if the crate does not exist
+    /// or is hidden from the caller.
+    fn list_owners(&self, name: &str, credentials: &Credentials) -> Result<Option<Vec<String>>> {
+        if validation::validate_name(name).is_err() || !self.can_read(Some(name), credentials)? {
+            return Ok(None);
+        }
 
-impl CaesiumService {
-    pub fn new(caesium: Arc<Caesium>) -> CaesiumService {
-        CaesiumService {
-            caesium: caesium,
+        if self.registry.crate_entries(name)?.is_empty() {
+            return Ok(None);
         }
+
+        Ok(Some(self.registry.crate_owners(name)?.map(|owners| owners.logins()).unwrap_or_default()))
     }
-}
 
-impl Service for CaesiumService {
-    type Request = Request;
-    type Response = Response;
-    type Error = hyper::Error;
-    type Future = Box<futures::Future<Item = Response, Error = Self::Error>>;
+    /// Adds or removes owners of a crate, which can only be done by an
+    /// existing owner.
+    fn change_owners(&self, name: &str, credentials: &Credentials, logins: &[String], add: bool) -> Result<String> {
+        let request = AuthenticationRequest {
+            mutation: Some("owners"),
+            name: Some(name),
+            ..AuthenticationRequest::default()
+        };
+        let userinfo = match self.authenticate_request(credentials, request)? {
+            Some(userinfo) => userinfo,
+            None => bail!(ErrorKind::AuthorizationError("owners can only be changed when authentication is configured".to_string())),
+        };
+        userinfo.check_scope(TokenScope::ChangeOwners, name)?;
 
-    fn call(&self, req: Request) -> Self::Future {
-        match (req.method(), req.path()) {
-            (&Put, "/api/v1/crates/new") => {
+        if self.registry.crate_entries(name)?.is_empty() {
+            bail!(ErrorKind::ValidationError(format!("crate '{}' does not exist", name)));
+        }
 
-                println!("Handling new upload request");
+        let mut owners = match self.registry.crate_owners(name)? {
+            Some(owners) => owners,
+            None => bail!(ErrorKind::AuthorizationError(format!("crate '{}' has no owners, ask an administrator to set them", name))),
+        };
+        self.authorize_owner(name, Some(&userinfo))?;
+        self.authorize(&modules::authorization::AuthorizationRequest::crate_action("change-owners", name, Some(&userinfo)))?;
 
-                let mut caesium = self.caesium.clone();
-
-                let token = match req.headers().get::<Authorization<String>>() {
-                    Some(auth_header) => auth_header.0.clone(),
-                    None => panic!("No authorization header found")
-                };
-
-                Box::new(req.body()
-                    .fold(Vec::new(), |mut acc, chunk| {
-                        acc.extend_from_slice(&*chunk);
-                        futures::future::ok::<_, Self::Error>(acc)
-                    })
-                    .map(move |body| {
-                        let (manifest, tar) = parser::parse_crate_upload(body.as_slice()).unwrap();
-
-                        match caesium.publish(manifest, tar, &token) {
-                            Ok(_) => Response::new().with_status(StatusCode::Ok),
-                            Err(e) => {
-                                match *e.kind() {
-                                    ErrorKind::AuthenticationError(_) => Response::new().with_status(StatusCode::Forbidden),
-                                    _ => Response::new().with_status(StatusCode::InternalServerError),
-                                }
-                            }
-                        }
-                    }))
-            },
-            _ => {
-                Box::new(futures::future::ok(Response::new().with_status(StatusCode::NotFound)))
+        for login in logins {
+            if add {
+                owners.add(login);
+            } else {
+                owners.remove(login);
             }
         }
+
+        if owners.is_empty() {
+            bail!(ErrorKind::ValidationError(format!("crate '{}' must have at least one owner", name)));
+        }
+
+        let message = format!("{} owners {} {} {} by {}",
+                              if add { "Adding" } else { "Removing" },
+                              logins.join(", "),
+                              if add { "to" } else { "from" },
+                              name,
+                              userinfo.sub);
+        self.registry.set_crate_owners(name, &owners, message.clone())?;
+
+        println!("{}", message);
+
+        Ok(message)
     }
-}
 
-fn main() {
-    let caesium = Arc::new(Caesium::new());
+    /// Yanks or unyanks a version of a crate, which can only be done by an
+    /// owner of the crate.
+    fn yank(&self, name: &str, vers: &str, credentials: &Credentials, yanked: bool) -> Result<()> {
+        let userinfo = self.authenticate_request(credentials, AuthenticationRequest {
+            mutation: Some(if yanked { "yank" } else { "unyank" }),
+            name: Some(name),
+            vers: Some(vers),
+            ..AuthenticationRequest::default()
+        })?;
 
-    let port = match caesium.config.server {
-        Some(ref server) => server.port,
-        None => None
-    };
+        if let Some(ref userinfo) = userinfo {
+            userinfo.check_scope(TokenScope::Yank, name)?;
+        }
+        self.authorize_owner(name, userinfo.as_ref())?;
+        self.authorize(&modules::authorization::AuthorizationRequest::crate_action(
+            if yanked { "yank" } else { "unyank" }, name, userinfo.as_ref()))?;
 
-    let port = if let Some(port) = port {
-        port
-    } else {
-        3000
-    };
+        let username = userinfo.as_ref().map(|userinfo| userinfo.sub.clone()).unwrap_or("an anonymous user".to_string());
+        let message = format!("{} {} {} by {}", if yanked { "Yanking" } else { "Unyanking" }, name, vers, username);
+        self.registry.set_yanked(name, vers, yanked, message.clone())?;
 
-    let addr = format!("0.0.0.0:{}", port).parse().unwrap();
-    let mut server = Http::new().bind(&addr, move || Ok(CaesiumService::new(caesium.clone()))).unwrap();
-    server.no_proto();
-    println!("Listening on http://{} with 1 thread.", server.local_addr().unwrap());
-    server.run().unwrap();
+        println!("{}", message);
+
+        Ok(())
+    }
+
+    /// Authenticates a user and checks that they are an administrator.
+    fn authenticate_admin(&self, credentials: &Credentials) -> Result<modules::authentication::AuthenticationUserInfo> {
+        let admins = match self.admins {
+            Some(ref admins) => admins,
+            None => bail!(ErrorKind::AuthorizationError("no administrators are configured".to_string())),
+        };
+
+        match self.authenticate(credentials)? {
+            Some(ref userinfo) if admins.is_admin(userinfo) => Ok(userinfo.clone()),
+            Some(userinfo) => bail!(ErrorKind::AuthorizationError(format!("'{}' is not an administrator", userinfo.sub))),
+            None => bail!(ErrorKind::AuthorizationError("administrators require authentication to be configured".to_string())),
+        }
+    }
+
+    fn admin_yank(&self, name: &str, vers: &str, credentials: &Credentials, yanked: bool) -> Result<String> {
+        let admin = self.authenticate_admin(credentials)?;
+        admin.check_scope(TokenScope::Yank, name)?;
+
+        let message = format!("{} {} {} (admin action by {})",
+                              if yanked { "Yanking" } else { "Unyanking" }, name, vers, admin.sub);
+        self.registry.set_yanked(name, vers, yanked, message.clone())?;
+
+        println!("{}", message);
+
+        Ok(message)
+    }
+
+    /// Replaces the owners of a crate, for example when the owners have left.
+    fn admin_set_owners(&self, name: &str, credentials: &Credentials, logins: &[String]) -> Result<String> {
+        let admin = self.authenticate_admin(credentials)?;
+        admin.check_scope(TokenScope::ChangeOwners, name)?;
+
+        if self.registry.crate_entries(name)?.is_empty() {
+            bail!(ErrorKind::ValidationError(format!("crate '{}' does not exist", name)));
+        }
+
+        let mut owners = owners::CrateOwners::default();
+        for login in logins {
+            owners.add(login);
+        }
+
+        if owners.is_empty() {
+            bail!(ErrorKind::ValidationError(format!("crate '{}' must have at least one owner", name)));
+        }
+
+        let message = format!("Setting owners of {} to {} (admin action by {})", name, logins.join(", "), admin.sub);
+        self.registry.set_crate_owners(name, &owners, message.clone())?;
+
+        println!("{}", message);
+
+        Ok(message)
+    }
+
+    /// Authenticates a user for managing their API tokens, which can only be
+    /// done using a token without any scopes.
+    fn authenticate_token_owner(&self, credentials: &Credentials) -> Result<(&ApiTokenStore, modules::authentication::AuthenticationUserInfo)> {
+        let store = match self.tokens {
+            Some(ref store) => &**store,
+            None => bail!(ErrorKind::AuthenticationError("API tokens are not configured".to_string())),
+        };
+
+        let userinfo = match self.authenticate(credentials)? {
+            Some(userinfo) => userinfo,
+            None => bail!(ErrorKind::AuthenticationError("API tokens require authentication to be configured".to_string())),
+        };
+        userinfo.check_unrestricted()?;
+
+        Ok((store, userinfo))
+    }
+
+    fn list_tokens(&self, credentials: &Credentials) -> Result<Vec<ApiToken>> {
+        let (store, userinfo) = self.authenticate_token_owner(credentials)?;
+
+        Ok(store.list(&userinfo.sub))
+    }
+
+    /// Issues a new token for the user, returning its details along with the
+    /// token itself, which is not available again.
+    fn create_token(&self, credentials: &Credentials, options: ApiTokenOptions) -> Result<(ApiToken, String)> {
+        let (store, userinfo) = self.authenticate_token_owner(credentials)?;
+
+        // Tokens can't be used to mint more tokens, otherwise a leaked token
+        // could be kept alive forever
+        if userinfo.backend.as_ref().map(|backend| backend.as_str()) == Some("tokens") {
+            bail!(ErrorKind::AuthorizationError("API tokens can only be created after logging in with the identity provider".to_string()));
+        }
+
+        let new_token = store.create(&userinfo, options)?;
+        let api_token = store.find(&new_token).expect("Failed to find new token");
+
+        println!("Issued API token {} to {}", api_token.id(), userinfo.sub);
+
+        Ok((api_token, new_token))
+    }
+
+    fn revoke_token(&self, credentials: &Credentials, id: &str) -> Result<String> {
+        let (store, userinfo) = self.authenticate_token_owner(credentials)?;
+
+        store.revoke(&userinfo.sub, id)?;
+
+        let message = format!("Revoked API token {} for {}", id, userinfo.sub);
+        println!("{}", message);
+
+        Ok(message)
+    }
+
+    /// Authenticates a user who is managing an existing crate, they must be
+    /// one of its recorded owners and use a token without any scopes.
+    fn authenticate_crate_owner(&self, name: &str, credentials: &Credentials) -> Result<modules::authentication::AuthenticationUserInfo> {
+        let userinfo = match self.authenticate(credentials)? {
+            Some(userinfo) => userinfo,
+            None => bail!(ErrorKind::AuthorizationError("authentication is not configured".to_string())),
+        };
+        userinfo.check_unrestricted()?;
+
+        match self.registry.crate_owners(name)? {
+            Some(ref owners) if owners.is_owner(&userinfo) => Ok(userinfo),
+            Some(_) => bail!(ErrorKind::AuthorizationError(format!("'{}' is not an owner of crate '{}'", userinfo.sub, name))),
+            None => bail!(ErrorKind::AuthorizationError(format!("crate '{}' has no owners, ask an administrator to set them", name))),
+        }
+    }
+
+    fn trusted_publishing(&self) -> Result<&TrustedPublishing> {
+        match self.trusted_publishing {
+            Some(ref trusted_publishing) => Ok(trusted_publishing),
+            None => bail!(ErrorKind::AuthenticationError("Trusted publishing is not configured".to_string())),
+        }
+    }
+
+    fn list_trusted_publishers(&self, name: &str, credentials: &Credentials) -> Result<Vec<TrustedPublisher>> {
+        let trusted_publishing = self.trusted_publishing()?;
+        self.authenticate_crate_owner(name, credentials)?;
+
+        Ok(trusted_publishing.publishers().list(name))
+    }
+
+    fn add_trusted_publisher(&self, name: &str, credentials: &Credentials, publisher: TrustedPublisher) -> Result<TrustedPublisher> {
+        let trusted_publishing = self.trusted_publishing()?;
+        let userinfo = self.authenticate_crate_owner(name, credentials)?;
+
+        let publisher = trusted_publishing.publishers().add(name, publisher)?;
+
+        println!("{} added trusted publisher {} for {} from {}", userinfo.sub, publisher.id, name, publisher.repository);
+
+        Ok(publisher)
+    }
+
+    fn remove_trusted_publisher(&self, name: &str, credentials: &Credentials, id: u64) -> Result<String> {
+        let trusted_publishing = self.trusted_publishing()?;
+        let userinfo = self.authenticate_crate_owner(name, credentials)?;
+
+        trusted_publishing.publishers().remove(name, id)?;
+
+        let message = format!("Removed trusted publisher {} from {} by {}", id, name, userinfo.sub);
+        println!("{}", message);
+
+        Ok(message)
+    }
+
+    /// Exchanges a CI identity token for a short lived publish token.
+    fn exchange_trusted_publishing_token(&self, jwt: &str) -> Result<String> {
+        let token = self.trusted_publishing()?.exchange(jwt)?;
+
+        println!("Issued trusted publishing token");
+
+        Ok(token)
+    }
+
+    fn start_login(&self) -> Result<String> {
+        match self.login {
+            Some(ref login) => login.start(),
+            None => bail!(ErrorKind::AuthenticationError("Logging in is not configured".to_string())),
+        }
+    }
+
+    fn finish_login(&self, code: &str, state: &str) -> Result<String> {
+        match self.login {
+            Some(ref login) => login.finish(code, state),
+            None => bail!(ErrorKind::AuthenticationError("Logging in is not configured".to_string())),
+        }
+    }
+
+    /// Publishes a crate which was held for approval, as the user who
+    /// uploaded it.
+    fn admin_approve(&self, name: &str, vers: &str, credentials: &Credentials) -> Result<String> {
+        let admin = self.authenticate_admin(credentials)?;
+        admin.check_unrestricted()?;
+
+        let held_crates = match self.typosquatting.as_ref().and_then(|typosquatting| typosquatting.held_crates()) {
+            Some(held_crates) => held_crates,
+            None => bail!(ErrorKind::ValidationError("crates are not held for approval".to_string())),
+        };
+        let held = held_crates.load(name, vers)?;
+        let manifest = &held.manifest;
+
+        self.while_publishing(&manifest.name, || {
+            // The index may have changed while the crate was held
+            if !self.registry.crate_entries(&manifest.name)?.is_empty() {
+                bail!(ErrorKind::ValidationError(format!("crate '{}' was published while it was held", manifest.name)));
+            }
+            validation::validate_dependencies(manifest,
+                                              &self.registry,
+                                              &self.config.registry.index,
+                                              &self.config.registry.allowed_registries())?;
+
+            self.store_crate(manifest, &held.tar, held.publisher.as_ref().map(|sub| sub.as_str()), held.owners.as_ref())
+        })?;
+        held_crates.remove(name, vers)?;
+
+        let message = format!("Approved held crate {} v{} (admin action by {})", manifest.name, manifest.vers, admin.sub);
+        println!("{}", message);
+
+        Ok(message)
+    }
+
+    fn admin_repair_storage(&self, credentials: &Credentials) -> Result<modules::storage::RepairSummary> {
+        let admin = self.authenticate_admin(credentials)?;
+        admin.check_unrestricted()?;
+
+        println!("Repairing under-replicated crates (admin action by {})", admin.sub);
+        let summary = self.storage.repair()?;
+        println!("Repaired {} crates, {} still need repairing", summary.repaired.len(), summary.remaining.len());
+
+        Ok(summary)
+    }
+
+    fn admin_unlock_index(&self, credentials: &Credentials) -> Result<String> {
+        let admin = self.authenticate_admin(credentials)?;
+        admin.check_unrestricted()?;
+
+        let message = if self.registry.force_unlock()? {
+            format!("Removed index lock (admin action by {})", admin.sub)
+        } else {
+            "Index was not locked".to_string()
+        };
+
+        println!("{}", message);
+
+        Ok(message)
+    }
+}
+
+/// What the client has identified itself with.
+struct Credentials {
+    token: String,
+    certificate: Option<tls::ClientCertificate>,
+}
+
+/// How a crate file is served to the client.
+enum Download {
+    // Clients are redirected to download from the storage directly
+    Redirect(String),
+    Contents(Vec<u8>),
+}
+
+/// The outcome of a publish request which has passed all checks.
+struct Publication {
+    userinfo: Option<modules::authentication::AuthenticationUserInfo>,
+    warnings: validation::PublishWarnings,
+
+    // Set with the reason if the crate needs approval before it is published
+    held: Option<String>,
+
+    // The owners to record if this is the first version of the crate
+    owners: Option<owners::CrateOwners>,
+}
+
+impl Publication {
+    fn username(&self) -> String {
+        self.userinfo.as_ref()
+                     .and_then(|userinfo| userinfo.name.clone())
+                     .unwrap_or("an anonymous user".to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct PublishResponse {
+    warnings: validation::PublishWarnings,
+}
+
+#[derive(Deserialize)]
+struct OwnersRequest {
+    users: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct OwnersResponse {
+    users: Vec<OwnerDetail>,
+}
+
+#[derive(Serialize)]
+struct OwnerDetail {
+    id: usize,
+    login: String,
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct YankResponse {
+    ok: bool,
+}
+
+#[derive(Serialize)]
+struct OkResponse {
+    ok: bool,
+    msg: String,
+}
+
+#[derive(Deserialize)]
+struct NewTokenRequest {
+    api_token: ApiTokenOptions,
+}
+
+#[derive(Serialize)]
+struct TokenDetail {
+    id: String,
+    name: Option<String>,
+    created_at: u64,
+    endpoint_scopes: Option<Vec<TokenScope>>,
+    crate_scopes: Option<Vec<String>>,
+    expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl TokenDetail {
+    fn new(api_token: ApiToken, token: Option<String>) -> TokenDetail {
+        TokenDetail {
+            id: api_token.id().to_string(),
+            name: api_token.options.name,
+            created_at: api_token.created,
+            endpoint_scopes: api_token.options.endpoint_scopes,
+            crate_scopes: api_token.options.crate_scopes,
+            expires_at: api_token.options.expires_at,
+            token: token,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    api_token: TokenDetail,
+}
+
+#[derive(Serialize)]
+struct TokensResponse {
+    api_tokens: Vec<TokenDetail>,
+}
+
+#[derive(Deserialize)]
+struct TrustedPublishingTokenRequest {
+    jwt: String,
+}
+
+#[derive(Serialize)]
+struct TrustedPublishingTokenResponse {
+    token: String,
+}
+
+#[derive(Serialize)]
+struct TrustedPublisherResponse {
+    trusted_publisher: TrustedPublisher,
+}
+
+#[derive(Serialize)]
+struct TrustedPublishersResponse {
+    trusted_publishers: Vec<TrustedPublisher>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    errors: Vec<ErrorDetail>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail {
+    detail: String,
+}
+
+fn json_response<T: serde::Serialize>(status: StatusCode, body: &T) -> Response {
+    Response::new().with_status(status)
+                   .with_header(ContentType::json())
+                   .with_body(serde_json::to_string(body).unwrap())
+}
+
+fn error_response(e: &Error) -> Response {
+    let status = match *e.kind() {
+        ErrorKind::AuthenticationRequired(_) => StatusCode::Unauthorized,
+        ErrorKind::AuthenticationError(_) => StatusCode::Forbidden,
+        ErrorKind::UnrecognisedToken(_) => StatusCode::Unauthorized,
+        ErrorKind::AuthenticationBackendError(_) => StatusCode::ServiceUnavailable,
+        ErrorKind::AuthorizationError(_) => StatusCode::Forbidden,
+        ErrorKind::ValidationError(_) => StatusCode::BadRequest,
+        ErrorKind::PublishHeld(_) => StatusCode::Forbidden,
+        ErrorKind::PolicyServiceError(_) => StatusCode::ServiceUnavailable,
+        _ => StatusCode::InternalServerError,
+    };
+
+    let response = json_response(status, &ErrorResponse {
+        errors: vec![ErrorDetail { detail: e.to_string() }],
+    });
+
+    // Cargo only sends its token to a registry which asks for it
+    match *e.kind() {
+        ErrorKind::AuthenticationRequired(_) |
+        ErrorKind::UnrecognisedToken(_) => response.with_header(WwwAuthenticate("Cargo".to_string())),
+        _ => response,
+    }
+}
+
+fn not_found() -> Response {
+    Response::new().with_status(StatusCode::NotFound)
+}
+
+struct CaesiumService {
+    caesium: Arc<Caesium>,
+
+    // The verified certificate that the client connected with, if any
+    certificate: Option<tls::ClientCertificate>,
+}
+
+impl CaesiumService {
+    pub fn new(caesium: Arc<Caesium>, certificate: Option<tls::ClientCertificate>) -> CaesiumService {
+        CaesiumService {
+            caesium: caesium,
+            certificate: certificate,
+        }
+    }
+
+    /// Returns the token that cargo sends in the Authorization header, which
+    /// is empty if there is no header, along with the client certificate.
+    fn credentials(&self, req: &Request) -> Credentials {
+        Credentials {
+            token: req.headers().get::<Authorization<String>>()
+                                .map(|auth_header| auth_header.0.clone())
+                                .unwrap_or_default(),
+            certificate: self.certificate.clone(),
+        }
+    }
+}
+
+fn read_body(req: Request) -> Box<futures::Future<Item = Vec<u8>, Error = hyper::Error>> {
+    Box::new(req.body()
+        .fold(Vec::new(), |mut acc, chunk| {
+            acc.extend_from_slice(&*chunk);
+            futures::future::ok::<_, hyper::Error>(acc)
+        }))
+}
+
+impl CaesiumService {
+    /// Handles a crate upload, either publishing it or only checking that it
+    /// would be accepted if `dry_run` is set.
+    fn upload(&self, req: Request, dry_run: bool) -> <Self as Service>::Future {
+
+        let caesium = self.caesium.clone();
+        let credentials = self.credentials(&req);
+
+        Box::new(read_body(req)
+            .map(move |body| {
+                let result = parser::parse_crate_upload(body.as_slice()).and_then(|(manifest, tar)| {
+                    if dry_run {
+                        caesium.validate(manifest, tar, &credentials)
+                    } else {
+                        caesium.publish(manifest, tar, &credentials)
+                    }
+                });
+
+                match result {
+                    Ok(warnings) => json_response(StatusCode::Ok, &PublishResponse { warnings: warnings }),
+                    Err(e) => error_response(&e),
+                }
+            }))
+    }
+
+    fn index_config(&self, req: Request) -> <Self as Service>::Future {
+        let response = match self.caesium.index_config(&self.credentials(&req)) {
+            Ok(config) => Response::new().with_status(StatusCode::Ok).with_header(ContentType::json()).with_body(config),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn index_file(&self, req: Request, name: &str) -> <Self as Service>::Future {
+        let response = match self.caesium.index_file(name, &self.credentials(&req)) {
+            Ok(Some(contents)) => Response::new().with_status(StatusCode::Ok).with_body(contents),
+            Ok(None) => not_found(),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn download(&self, req: Request, name: &str, vers: &str) -> <Self as Service>::Future {
+        let response = match self.caesium.download(name, vers, &self.credentials(&req)) {
+            Ok(Some(Download::Redirect(url))) => Response::new().with_status(StatusCode::Found).with_header(Location::new(url)),
+            Ok(Some(Download::Contents(tar))) => Response::new().with_status(StatusCode::Ok).with_header(ContentType::octet_stream()).with_body(tar),
+            Ok(None) => not_found(),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn list_owners(&self, req: Request, name: &str) -> <Self as Service>::Future {
+        let response = match self.caesium.list_owners(name, &self.credentials(&req)) {
+            Ok(Some(logins)) => {
+                let users = logins.into_iter().enumerate().map(|(id, login)| {
+                    OwnerDetail {
+                        id: id + 1,
+                        login: login,
+                        name: None,
+                    }
+                }).collect();
+                json_response(StatusCode::Ok, &OwnersResponse { users: users })
+            }
+            Ok(None) => not_found(),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn change_owners(&self, req: Request, name: &str, add: bool) -> <Self as Service>::Future {
+
+        let caesium = self.caesium.clone();
+        let credentials = self.credentials(&req);
+        let name = name.to_string();
+
+        Box::new(read_body(req)
+            .map(move |body| {
+                let result = serde_json::from_slice::<OwnersRequest>(&body)
+                    .map_err(Error::from)
+                    .and_then(|request| caesium.change_owners(&name, &credentials, &request.users, add));
+
+                match result {
+                    Ok(msg) => json_response(StatusCode::Ok, &OkResponse { ok: true, msg: msg }),
+                    Err(e) => error_response(&e),
+                }
+            }))
+    }
+
+    fn yank(&self, req: Request, name: &str, vers: &str, yanked: bool) -> <Self as Service>::Future {
+        let response = match self.caesium.yank(name, vers, &self.credentials(&req), yanked) {
+            Ok(()) => json_response(StatusCode::Ok, &YankResponse { ok: true }),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn admin_yank(&self, req: Request, name: &str, vers: &str, yanked: bool) -> <Self as Service>::Future {
+        let response = match self.caesium.admin_yank(name, vers, &self.credentials(&req), yanked) {
+            Ok(msg) => json_response(StatusCode::Ok, &OkResponse { ok: true, msg: msg }),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn admin_approve(&self, req: Request, name: &str, vers: &str) -> <Self as Service>::Future {
+        let response = match self.caesium.admin_approve(name, vers, &self.credentials(&req)) {
+            Ok(msg) => json_response(StatusCode::Ok, &OkResponse { ok: true, msg: msg }),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn admin_set_owners(&self, req: Request, name: &str) -> <Self as Service>::Future {
+
+        let caesium = self.caesium.clone();
+        let credentials = self.credentials(&req);
+        let name = name.to_string();
+
+        Box::new(read_body(req)
+            .map(move |body| {
+                let result = serde_json::from_slice::<OwnersRequest>(&body)
+                    .map_err(Error::from)
+                    .and_then(|request| caesium.admin_set_owners(&name, &credentials, &request.users));
+
+                match result {
+                    Ok(msg) => json_response(StatusCode::Ok, &OkResponse { ok: true, msg: msg }),
+                    Err(e) => error_response(&e),
+                }
+            }))
+    }
+
+    fn list_tokens(&self, req: Request) -> <Self as Service>::Future {
+        let response = match self.caesium.list_tokens(&self.credentials(&req)) {
+            Ok(api_tokens) => {
+                let api_tokens = api_tokens.into_iter().map(|api_token| TokenDetail::new(api_token, None)).collect();
+                json_response(StatusCode::Ok, &TokensResponse { api_tokens: api_tokens })
+            }
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn create_token(&self, req: Request) -> <Self as Service>::Future {
+
+        let caesium = self.caesium.clone();
+        let credentials = self.credentials(&req);
+
+        Box::new(read_body(req)
+            .map(move |body| {
+                let result = serde_json::from_slice::<NewTokenRequest>(&body)
+                    .map_err(Error::from)
+                    .and_then(|request| caesium.create_token(&credentials, request.api_token));
+
+                match result {
+                    Ok((api_token, new_token)) => {
+                        json_response(StatusCode::Ok, &TokenResponse { api_token: TokenDetail::new(api_token, Some(new_token)) })
+                    }
+                    Err(e) => error_response(&e),
+                }
+            }))
+    }
+
+    fn revoke_token(&self, req: Request, id: &str) -> <Self as Service>::Future {
+        let response = match self.caesium.revoke_token(&self.credentials(&req), id) {
+            Ok(msg) => json_response(StatusCode::Ok, &OkResponse { ok: true, msg: msg }),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn list_trusted_publishers(&self, req: Request, name: &str) -> <Self as Service>::Future {
+        let response = match self.caesium.list_trusted_publishers(name, &self.credentials(&req)) {
+            Ok(publishers) => json_response(StatusCode::Ok, &TrustedPublishersResponse { trusted_publishers: publishers }),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn add_trusted_publisher(&self, req: Request, name: &str) -> <Self as Service>::Future {
+
+        let caesium = self.caesium.clone();
+        let credentials = self.credentials(&req);
+        let name = name.to_string();
+
+        Box::new(read_body(req)
+            .map(move |body| {
+                let result = serde_json::from_slice::<TrustedPublisher>(&body)
+                    .map_err(Error::from)
+                    .and_then(|publisher| caesium.add_trusted_publisher(&name, &credentials, publisher));
+
+                match result {
+                    Ok(publisher) => json_response(StatusCode::Ok, &TrustedPublisherResponse { trusted_publisher: publisher }),
+                    Err(e) => error_response(&e),
+                }
+            }))
+    }
+
+    fn remove_trusted_publisher(&self, req: Request, name: &str, id: &str) -> <Self as Service>::Future {
+        let result = id.parse::<u64>()
+            .map_err(|_| Error::from(ErrorKind::ValidationError(format!("invalid trusted publisher id '{}'", id))))
+            .and_then(|id| self.caesium.remove_trusted_publisher(name, &self.credentials(&req), id));
+
+        let response = match result {
+            Ok(msg) => json_response(StatusCode::Ok, &OkResponse { ok: true, msg: msg }),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn exchange_trusted_publishing_token(&self, req: Request) -> <Self as Service>::Future {
+
+        let caesium = self.caesium.clone();
+
+        Box::new(read_body(req)
+            .map(move |body| {
+                let result = serde_json::from_slice::<TrustedPublishingTokenRequest>(&body)
+                    .map_err(Error::from)
+                    .and_then(|request| caesium.exchange_trusted_publishing_token(&request.jwt));
+
+                match result {
+                    Ok(token) => json_response(StatusCode::Ok, &TrustedPublishingTokenResponse { token: token }),
+                    Err(e) => error_response(&e),
+                }
+            }))
+    }
+
+    /// Logs the user in with the OpenID provider, then shows them a new API
+    /// token once the provider redirects back here.
+    fn me(&self, req: Request) -> <Self as Service>::Future {
+        let code = login::query_param(req.query(), "code");
+        let state = login::query_param(req.query(), "state");
+
+        let response = match (code, state) {
+            (Some(code), Some(state)) => {
+                match self.caesium.finish_login(&code, &state) {
+                    Ok(token) => {
+                        Response::new().with_status(StatusCode::Ok)
+                                       .with_header(ContentType::html())
+                                       .with_body(login::token_page(&token))
+                    }
+                    Err(e) => error_response(&e),
+                }
+            }
+            _ => {
+                match self.caesium.start_login() {
+                    Ok(url) => Response::new().with_status(StatusCode::Found).with_header(Location::new(url)),
+                    Err(e) => error_response(&e),
+                }
+            }
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn admin_repair_storage(&self, req: Request) -> <Self as Service>::Future {
+        let response = match self.caesium.admin_repair_storage(&self.credentials(&req)) {
+            Ok(summary) => json_response(StatusCode::Ok, &summary),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+
+    fn admin_unlock_index(&self, req: Request) -> <Self as Service>::Future {
+        let response = match self.caesium.admin_unlock_index(&self.credentials(&req)) {
+            Ok(msg) => json_response(StatusCode::Ok, &OkResponse { ok: true, msg: msg }),
+            Err(e) => error_response(&e),
+        };
+
+        Box::new(futures::future::ok(response))
+    }
+}
+
+impl Service for CaesiumService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<futures::Future<Item = Response, Error = Self::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        let method = req.method().clone();
+        let path = req.path().to_string();
+        let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+        match (&method, segments.as_slice()) {
+            (&Get, &["me"]) => {
+                self.me(req)
+            },
+            (&Get, &["api", "v1", "me", "tokens"]) => {
+                self.list_tokens(req)
+            },
+            (&Put, &["api", "v1", "me", "tokens"]) => {
+                println!("Handling new token request");
+                self.create_token(req)
+            },
+            (&Delete, &["api", "v1", "me", "tokens", id]) => {
+                println!("Handling revoke token request for {}", id);
+                self.revoke_token(req, id)
+            },
+            (&Put, &["api", "v1", "trusted_publishing", "tokens"]) => {
+                println!("Handling trusted publishing token request");
+                self.exchange_trusted_publishing_token(req)
+            },
+            (&Get, &["api", "v1", "crates", name, "trusted_publishers"]) => {
+                self.list_trusted_publishers(req, name)
+            },
+            (&Put, &["api", "v1", "crates", name, "trusted_publishers"]) => {
+                println!("Handling add trusted publisher request for {}", name);
+                self.add_trusted_publisher(req, name)
+            },
+            (&Delete, &["api", "v1", "crates", name, "trusted_publishers", id]) => {
+                println!("Handling remove trusted publisher request for {}", name);
+                self.remove_trusted_publisher(req, name, id)
+            },
+            (&Put, &["api", "v1", "crates", "new"]) => {
+                println!("Handling new upload request");
+                self.upload(req, false)
+            },
+            (&Put, &["api", "v1", "crates", "validate"]) => {
+                println!("Handling validation request");
+                self.upload(req, true)
+            },
+            (&Get, &["index", "config.json"]) => {
+                self.index_config(req)
+            },
+            (&Get, &["index", _, name]) | (&Get, &["index", _, _, name]) => {
+                self.index_file(req, name)
+            },
+            (&Get, &["api", "v1", "crates", name, vers, "download"]) => {
+                self.download(req, name, vers)
+            },
+            (&Get, &["api", "v1", "crates", name, "owners"]) => {
+                self.list_owners(req, name)
+            },
+            (&Put, &["api", "v1", "crates", name, "owners"]) => {
+                println!("Handling add owners request for {}", name);
+                self.change_owners(req, name, true)
+            },
+            (&Delete, &["api", "v1", "crates", name, "owners"]) => {
+                println!("Handling remove owners request for {}", name);
+                self.change_owners(req, name, false)
+            },
+            (&Delete, &["api", "v1", "crates", name, vers, "yank"]) => {
+                println!("Handling yank request for {} {}", name, vers);
+                self.yank(req, name, vers, true)
+            },
+            (&Put, &["api", "v1", "crates", name, vers, "unyank"]) => {
+                println!("Handling unyank request for {} {}", name, vers);
+                self.yank(req, name, vers, false)
+            },
+            (&Delete, &["api", "admin", "crates", name, vers, "yank"]) => {
+                println!("Handling admin yank request for {} {}", name, vers);
+                self.admin_yank(req, name, vers, true)
+            },
+            (&Put, &["api", "admin", "crates", name, vers, "unyank"]) => {
+                println!("Handling admin unyank request for {} {}", name, vers);
+                self.admin_yank(req, name, vers, false)
+            },
+            (&Put, &["api", "admin", "crates", name, vers, "approve"]) => {
+                println!("Handling admin approve request for {} {}", name, vers);
+                self.admin_approve(req, name, vers)
+            },
+            (&Put, &["api", "admin", "crates", name, "owners"]) => {
+                println!("Handling admin set owners request for {}", name);
+                self.admin_set_owners(req, name)
+            },
+            (&Post, &["api", "admin", "index", "unlock"]) => {
+                println!("Handling admin index unlock request");
+                self.admin_unlock_index(req)
+            },
+            (&Post, &["api", "admin", "storage", "repair"]) => {
+                println!("Handling admin storage repair request");
+                self.admin_repair_storage(req)
+            },
+            _ => {
+                Box::new(futures::future::ok(not_found()))
+            }
+        }
+    }
+}
+
+/// Serves HTTPS, each connection has its own service so that requests can be
+/// authenticated with the client's certificate.
+fn serve_tls(caesium: Arc<Caesium>, addr: &std::net::SocketAddr, acceptor: SslAcceptor) {
+    let mut core = tokio_core::reactor::Core::new().unwrap();
+    let handle = core.handle();
+    let listener = tokio_core::net::TcpListener::bind(addr, &handle).unwrap();
+    println!("Listening on https://{} with 1 thread.", listener.local_addr().unwrap());
+
+    // A failed accept, such as running out of file descriptors, shouldn't
+    // stop the server, so log it and carry on.
+    let incoming = listener.incoming().then(|accepted| {
+        match accepted {
+            Ok(accepted) => Ok::<_, std::io::Error>(Some(accepted)),
+            Err(e) => {
+                println!("Failed to accept connection: {}", e);
+                Ok(None)
+            }
+        }
+    });
+
+    let server = incoming.filter_map(|accepted| accepted).for_each(|(socket, _)| {
+        let caesium = caesium.clone();
+
+        let connection = acceptor.accept_async(socket)
+            .map_err(|e| e.to_string())
+            .and_then(move |stream| {
+                let certificate = tls::client_certificate(stream.get_ref().ssl());
+                Http::<hyper::Chunk>::new().serve_connection(stream, CaesiumService::new(caesium, certificate))
+                                            .map_err(|e| e.to_string())
+            });
+
+        handle.spawn(connection.map(|_| ()).map_err(|e| println!("Connection failed: {}", e)));
+        Ok(())
+    });
+
+    core.run(server).unwrap();
+}
+
+fn main() {
+    let caesium = Arc::new(Caesium::new());
+
+    let port = match caesium.config.server {
+        Some(ref server) => server.port,
+        None => None
+    };
+
+    let port = if let Some(port) = port {
+        port
+    } else {
+        3000
+    };
+
+    let addr = format!("0.0.0.0:{}", port).parse().unwrap();
+
+    let acceptor = caesium.config.server.as_ref()
+                                        .and_then(|server| server.tls.as_ref())
+                                        .map(|tls| tls::create_acceptor(tls).expect("Failed to setup TLS"));
+    if let Some(acceptor) = acceptor {
+        return serve_tls(caesium, &addr, acceptor);
+    }
+
+    let mut server = Http::new().bind(&addr, move || Ok(CaesiumService::new(caesium.clone(), None))).unwrap();
+    server.no_proto();
+    println!("Listening on http://{} with 1 thread.", server.local_addr().unwrap());
+    server.run().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_helpers::{manifest_json, test_dir, test_location};
+
+    use std::path::Path;
+
+    use flate2;
+    use git2;
+    use tar;
+    use toml;
+
+    /// Storage which only records the crates uploaded to it, and not deleted
+    /// since.
+    struct RecordingStorage {
+        uploads: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl modules::storage::CrateStorage for RecordingStorage {
+        fn upload(&self, manifest: &registry::CargoManifest, _tar: &[u8], _publisher: Option<&str>) -> Result<()> {
+            self.uploads.lock().unwrap().push(format!("{} {}", manifest.name, manifest.vers));
+            Ok(())
+        }
+
+        fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+            bail!("{} {} is not stored", name, vers)
+        }
+
+        fn delete(&self, name: &str, vers: &str) -> Result<()> {
+            let stored = format!("{} {}", name, vers);
+            self.uploads.lock().unwrap().retain(|upload| *upload != stored);
+            Ok(())
+        }
+    }
+
+    /// Storage which holds one crate file, but can't give URLs for it.
+    struct NoUrlStorage {
+        tar: Vec<u8>,
+    }
+
+    impl modules::storage::CrateStorage for NoUrlStorage {
+        fn upload(&self, _manifest: &registry::CargoManifest, _tar: &[u8], _publisher: Option<&str>) -> Result<()> {
+            bail!("read only")
+        }
+
+        fn download(&self, _name: &str, _vers: &str) -> Result<Vec<u8>> {
+            Ok(self.tar.clone())
+        }
+
+        fn download_url(&self, name: &str, vers: &str) -> Result<Option<String>> {
+            bail!("no URL for {} {}", name, vers)
+        }
+    }
+
+    /// Creates a bare index repository containing only `config.json`, and
+    /// returns a registry cloned from it.
+    fn test_registry(dir: &Path) -> (String, registry::Registry) {
+        let origin = dir.join("index.git");
+        {
+            let repo = git2::Repository::init_bare(&origin).unwrap();
+            let config = repo.blob(br#"{"dl": "http://localhost:3000/api/v1/crates"}"#).unwrap();
+            let mut tree = repo.treebuilder(None).unwrap();
+            tree.insert("config.json", config, 0o100644).unwrap();
+            let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+            let signature = git2::Signature::now("Caesium", "caesium@example.com").unwrap();
+            repo.commit(Some("refs/heads/master"), &signature, &signature, "Create index", &tree, &[]).unwrap();
+        }
+
+        let index = origin.to_string_lossy().into_owned();
+        let registry = registry::Registry::clone_to(&index, &dir.join("repo"));
+
+        // Commits to the index need an identity
+        let mut config = git2::Repository::open(dir.join("repo")).unwrap().config().unwrap();
+        config.set_str("user.name", "Caesium").unwrap();
+        config.set_str("user.email", "caesium@example.com").unwrap();
+
+        (index, registry)
+    }
+
+    /// Creates a Caesium with an empty index, the given config is added to
+    /// the registry and file storage sections that every config needs.
+    fn test_caesium(name: &str, config: &str) -> (Caesium, Arc<Mutex<Vec<String>>>) {
+        let dir = test_dir(name);
+        let (index, registry) = test_registry(&dir);
+
+        let config = format!("[registry]\nindex = {:?}\n{}\n[storage.file]\nlocation = {:?}\n",
+                             index,
+                             config,
+                             dir.join("crates").to_string_lossy());
+        let config: config::CaesiumConfig = toml::from_str(&config).unwrap();
+
+        let uploads = Arc::new(Mutex::new(Vec::new()));
+        let storage = Box::new(RecordingStorage { uploads: uploads.clone() });
+
+        (Caesium::with_storage(config, registry, storage), uploads)
+    }
+
+    /// Authentication which takes the token as the user's sub.
+    struct TokenIsUser;
+
+    impl modules::authentication::Authentication for TokenIsUser {
+        fn authenticate(&self, token: &str) -> Result<modules::authentication::AuthenticationUserInfo> {
+            Ok(userinfo(token))
+        }
+    }
+
+    fn userinfo(sub: &str) -> modules::authentication::AuthenticationUserInfo {
+        modules::authentication::AuthenticationUserInfo {
+            sub: sub.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn user(sub: &str) -> Credentials {
+        Credentials {
+            token: sub.to_string(),
+            certificate: None,
+        }
+    }
+
+    fn anonymous() -> Credentials {
+        Credentials {
+            token: String::new(),
+            certificate: None,
+        }
+    }
+
+    /// A manifest with a single dependency, which is on this registry when
+    /// `registry` is `None`.
+    fn manifest_with_dep(name: &str, vers: &str, dep: &str, version_req: &str, registry: Option<&str>) -> String {
+        let dep = registry::CargoManifestDependency {
+            optional: false,
+            default_features: true,
+            name: dep.to_string(),
+            features: Vec::new(),
+            version_req: version_req.to_string(),
+            target: None,
+            kind: "normal".to_string(),
+            registry: registry.map(|registry| registry.to_string()),
+        };
+
+        let mut manifest: serde_json::Value = serde_json::from_str(&manifest_json(name, vers)).unwrap();
+        manifest["deps"] = serde_json::to_value(vec![dep]).unwrap();
+        manifest.to_string()
+    }
+
+    /// Builds a crate file in the same layout as `cargo package`.
+    fn crate_tar(name: &str, vers: &str) -> Vec<u8> {
+        let cargo_toml = format!("[package]\nname = \"{}\"\nversion = \"{}\"\n", name, vers);
+
+        let mut header = tar::Header::new_gnu();
+        header.set_path(format!("{}-{}/Cargo.toml", name, vers)).unwrap();
+        header.set_size(cargo_toml.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        builder.append(&header, cargo_toml.as_bytes()).unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn validate_does_not_store_or_index_crate() {
+        let (caesium, uploads) = test_caesium("validate-ok", "");
+        let tar = crate_tar("example", "0.1.0");
+
+        let validated = caesium.validate(&manifest_json("example", "0.1.0"), &tar, &anonymous()).unwrap();
+        assert!(uploads.lock().unwrap().is_empty());
+        assert!(caesium.registry.crate_entries("example").unwrap().is_empty());
+
+        let published = caesium.publish(&manifest_json("example", "0.1.0"), &tar, &anonymous()).unwrap();
+        assert_eq!(*uploads.lock().unwrap(), vec!["example 0.1.0".to_string()]);
+        assert_eq!(caesium.registry.crate_entries("example").unwrap().len(), 1);
+
+        assert_eq!(serde_json::to_string(&validated).unwrap(), serde_json::to_string(&published).unwrap());
+        assert!(!validated.other.is_empty());
+    }
+
+    #[test]
+    fn validate_returns_same_errors_as_publish() {
+        let (caesium, uploads) = test_caesium("validate-errors", "");
+        let tar = crate_tar("example", "0.1.0");
+        caesium.publish(&manifest_json("example", "0.1.0"), &tar, &anonymous()).unwrap();
+
+        let invalid = vec![(manifest_json("example", "0.1.0"), tar.clone()),
+                           (manifest_json("example", "0.2.0"), tar.clone()),
+                           (manifest_json("0example", "0.1.0"), crate_tar("0example", "0.1.0"))];
+
+        for (manifest, tar) in invalid {
+            let validated = caesium.validate(&manifest, &tar, &anonymous()).unwrap_err();
+            let published = caesium.publish(&manifest, &tar, &anonymous()).unwrap_err();
+
+            assert_eq!(validated.to_string(), published.to_string());
+            assert_eq!(error_response(&validated).status(), error_response(&published).status());
+        }
+
+        assert_eq!(uploads.lock().unwrap().len(), 1);
+        assert_eq!(caesium.registry.crate_entries("example").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn names_are_validated_before_reading_the_index() {
+        let (mut caesium, uploads) = test_caesium("validate-names", "");
+        caesium.authentication = Some(Box::new(TokenIsUser));
+
+        for name in &["aé…", "../../config", "ex/am"] {
+            let e = caesium.publish(&manifest_json(name, "0.1.0"), &crate_tar("example", "0.1.0"), &user("alice")).unwrap_err();
+            match *e.kind() {
+                ErrorKind::ValidationError(_) => {}
+                _ => panic!("unexpected error: {}", e),
+            }
+        }
+
+        assert!(uploads.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn dependencies_on_this_registry_must_be_in_the_index() {
+        let (caesium, uploads) = test_caesium("dependencies-same-registry", "");
+        let index = caesium.config.registry.index.clone();
+        let trailing_slash = format!("{}/", index);
+
+        for registry in vec![None, Some(index.as_str()), Some(trailing_slash.as_str())] {
+            let manifest = manifest_with_dep("app", "0.1.0", "example", "^0.1", registry);
+            let e = caesium.validate(&manifest, &crate_tar("app", "0.1.0"), &anonymous()).unwrap_err();
+            match *e.kind() {
+                ErrorKind::ValidationError(_) => {}
+                _ => panic!("unexpected error: {}", e),
+            }
+        }
+
+        caesium.publish(&manifest_json("example", "0.1.0"), &crate_tar("example", "0.1.0"), &anonymous()).unwrap();
+        let manifest = manifest_with_dep("app", "0.1.0", "example", "^0.2", None);
+        assert!(caesium.validate(&manifest, &crate_tar("app", "0.1.0"), &anonymous()).is_err());
+
+        let manifest = manifest_with_dep("app", "0.1.0", "example", "^0.1", Some(index.as_str()));
+        caesium.publish(&manifest, &crate_tar("app", "0.1.0"), &anonymous()).unwrap();
+        assert_eq!(*uploads.lock().unwrap(), vec!["example 0.1.0".to_string(), "app 0.1.0".to_string()]);
+
+        // Names are checked before they are used to read the index
+        let manifest = manifest_with_dep("other", "0.1.0", "../../config", "*", None);
+        let e = caesium.validate(&manifest, &crate_tar("other", "0.1.0"), &anonymous()).unwrap_err();
+        assert!(e.to_string().contains("must start with a letter"));
+    }
+
+    #[test]
+    fn dependencies_must_be_from_allowed_registries() {
+        let crates_io = "https://github.com/rust-lang/crates.io-index";
+        let other = "ssh://git@git.server/other-index.git";
+
+        let (caesium, _) = test_caesium("dependencies-default-registries", "");
+        let manifest = manifest_with_dep("app", "0.1.0", "serde", "^1.0", Some(crates_io));
+        caesium.validate(&manifest, &crate_tar("app", "0.1.0"), &anonymous()).unwrap();
+        let manifest = manifest_with_dep("app", "0.1.0", "serde", "^1.0", Some(other));
+        assert!(caesium.validate(&manifest, &crate_tar("app", "0.1.0"), &anonymous()).is_err());
+
+        let (caesium, _) = test_caesium("dependencies-allowed-registries", &format!("allowed_registries = [{:?}]", other));
+        let manifest = manifest_with_dep("app", "0.1.0", "serde", "^1.0", Some("ssh://git@git.server/other-index"));
+        caesium.validate(&manifest, &crate_tar("app", "0.1.0"), &anonymous()).unwrap();
+        let manifest = manifest_with_dep("app", "0.1.0", "serde", "^1.0", Some(crates_io));
+        let e = caesium.validate(&manifest, &crate_tar("app", "0.1.0"), &anonymous()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::ValidationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn typosquatting_names_are_rejected() {
+        let (caesium, uploads) = test_caesium("typosquatting-reject", "[policy.typosquatting]\npopular_names = [\"serde-json\"]");
+
+        let e = caesium.publish(&manifest_json("serde-jsn", "0.1.0"), &crate_tar("serde-jsn", "0.1.0"), &anonymous()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::ValidationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+
+        caesium.publish(&manifest_json("our-core-utils", "0.1.0"), &crate_tar("our-core-utils", "0.1.0"), &anonymous()).unwrap();
+        assert!(caesium.publish(&manifest_json("our_core-utils", "0.1.0"), &crate_tar("our_core-utils", "0.1.0"), &anonymous()).is_err());
+
+        assert_eq!(*uploads.lock().unwrap(), vec!["our-core-utils 0.1.0".to_string()]);
+    }
+
+    #[test]
+    fn typosquatting_names_are_held() {
+        let dir = test_dir("typosquatting-held");
+        let policy = format!("[policy.typosquatting]\npopular_names = [\"serde-json\"]\naction = \"hold\"\nhold_location = {:?}\n\
+                              [admin]\nusers = [\"root\"]",
+                             dir.join("held").to_string_lossy());
+        let (mut caesium, uploads) = test_caesium("typosquatting-hold", &policy);
+        caesium.authentication = Some(Box::new(TokenIsUser));
+
+        let e = caesium.publish(&manifest_json("serde-jsn", "0.1.0"), &crate_tar("serde-jsn", "0.1.0"), &user("alice")).unwrap_err();
+        match *e.kind() {
+            ErrorKind::PublishHeld(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+
+        assert!(uploads.lock().unwrap().is_empty());
+        assert!(dir.join("held").join("serde-jsn").join("0.1.0").join("download").exists());
+
+        // Once approved the crate is published as the user who uploaded it
+        assert!(caesium.admin_approve("serde-jsn", "0.1.0", &user("alice")).is_err());
+        assert!(caesium.admin_approve("serde-jsn", "0.2.0", &user("root")).is_err());
+        caesium.admin_approve("serde-jsn", "0.1.0", &user("root")).unwrap();
+
+        assert_eq!(*uploads.lock().unwrap(), vec!["serde-jsn 0.1.0".to_string()]);
+        assert_eq!(caesium.list_owners("serde-jsn", &anonymous()).unwrap(), Some(vec!["alice".to_string()]));
+        assert!(!dir.join("held").join("serde-jsn").exists());
+        assert!(caesium.admin_approve("serde-jsn", "0.1.0", &user("root")).is_err());
+    }
+
+    #[test]
+    fn only_admins_can_yank_crates_without_owners() {
+        // Published before authentication was configured, so it has no owners
+        let (mut caesium, _) = test_caesium("yank-no-owners", "[admin]\nusers = [\"root\"]");
+        caesium.publish(&manifest_json("example", "0.1.0"), &crate_tar("example", "0.1.0"), &anonymous()).unwrap();
+        caesium.authentication = Some(Box::new(TokenIsUser));
+
+        let e = caesium.yank("example", "0.1.0", &user("alice"), true).unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthorizationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert!(!caesium.registry.crate_entries("example").unwrap()[0].yanked);
+
+        assert!(caesium.admin_yank("example", "0.1.0", &user("alice"), true).is_err());
+        caesium.admin_yank("example", "0.1.0", &user("root"), true).unwrap();
+        assert!(caesium.registry.crate_entries("example").unwrap()[0].yanked);
+    }
+
+    #[test]
+    fn failed_index_commits_are_rolled_back() {
+        let (caesium, uploads) = test_caesium("index-rollback", "");
+        caesium.publish(&manifest_json("example", "0.1.0"), &crate_tar("example", "0.1.0"), &anonymous()).unwrap();
+
+        // Commits fail once the index has been changed and staged when git
+        // has no identity to commit with
+        let repo = git2::Repository::open(test_location("index-rollback").join("repo")).unwrap();
+        repo.config().unwrap().set_str("user.name", "").unwrap();
+
+        assert!(caesium.publish(&manifest_json("example", "0.2.0"), &crate_tar("example", "0.2.0"), &anonymous()).is_err());
+        assert!(caesium.publish(&manifest_json("other", "0.1.0"), &crate_tar("other", "0.1.0"), &anonymous()).is_err());
+
+        assert_eq!(*uploads.lock().unwrap(), vec!["example 0.1.0".to_string()]);
+        assert_eq!(caesium.registry.crate_entries("example").unwrap().len(), 1);
+        assert!(caesium.registry.crate_entries("other").unwrap().is_empty());
+        assert!(repo.statuses(None).unwrap().is_empty());
+
+        // Nothing is left behind to be picked up by the next commit
+        repo.config().unwrap().set_str("user.name", "Caesium").unwrap();
+        caesium.publish(&manifest_json("example", "0.2.0"), &crate_tar("example", "0.2.0"), &anonymous()).unwrap();
+        assert_eq!(caesium.registry.crate_entries("example").unwrap().len(), 2);
+        assert!(caesium.registry.crate_entries("other").unwrap().is_empty());
+        assert!(repo.statuses(None).unwrap().is_empty());
+    }
+
+    /// Authorization which only lets alice read crates.
+    struct OnlyAliceReads;
+
+    impl modules::authorization::Authorization for OnlyAliceReads {
+        fn authorize(&self, request: &modules::authorization::AuthorizationRequest) -> Result<()> {
+            match request.user {
+                Some(user) if request.action == "read" && user.sub != "alice" => {
+                    bail!(ErrorKind::AuthorizationError(format!("{} can't read {}", user.sub, request.name)))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn owners_of_hidden_crates_are_not_listed() {
+        let (mut caesium, _) = test_caesium("hidden-owners", "");
+        caesium.authentication = Some(Box::new(TokenIsUser));
+        caesium.publish(&manifest_json("example", "0.1.0"), &crate_tar("example", "0.1.0"), &user("alice")).unwrap();
+
+        caesium.config.registry.auth_required = Some(true);
+        caesium.config.registry.hide_unreadable_crates = Some(true);
+        caesium.authorization = Some(Box::new(OnlyAliceReads));
+
+        assert_eq!(caesium.list_owners("example", &user("alice")).unwrap(), Some(vec!["alice".to_string()]));
+        assert_eq!(caesium.list_owners("example", &user("bob")).unwrap(), None);
+        assert_eq!(caesium.list_owners("missing", &user("alice")).unwrap(), None);
+        assert_eq!(caesium.list_owners("../config", &user("alice")).unwrap(), None);
+
+        let e = caesium.list_owners("example", &anonymous()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthenticationRequired(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn reads_need_a_token_when_auth_is_required() {
+        let tokens = test_dir("auth-required-tokens").join("tokens.json");
+        let config = format!("auth_required = true\n[authentication.tokens]\nlocation = {:?}", tokens.to_string_lossy());
+        let (caesium, _) = test_caesium("auth-required", &config);
+
+        fn error<T>(result: Result<T>) -> Error {
+            match result {
+                Ok(_) => panic!("expected an error"),
+                Err(e) => e,
+            }
+        }
+
+        // Cargo only sends its token after a 401 asking for it
+        for e in &[error(caesium.index_config(&anonymous())),
+                   error(caesium.index_file("example", &anonymous())),
+                   error(caesium.download("example", "0.1.0", &anonymous())),
+                   error(caesium.download("example", "0.1.0", &user("not-a-token")))] {
+            let response = error_response(e);
+            assert_eq!(response.status(), StatusCode::Unauthorized);
+            assert_eq!(response.headers().get::<WwwAuthenticate>(), Some(&WwwAuthenticate("Cargo".to_string())));
+        }
+
+        let token = caesium.tokens.as_ref().unwrap().create(&userinfo("openid:alice"), ApiTokenOptions::default()).unwrap();
+        let config: serde_json::Value = serde_json::from_str(&caesium.index_config(&user(&token)).unwrap()).unwrap();
+        assert_eq!(config["auth-required"], true);
+    }
+
+    #[test]
+    fn api_tokens_can_not_create_tokens() {
+        let tokens = test_dir("token-minting-tokens").join("tokens.json");
+        let config = format!("[authentication.tokens]\nlocation = {:?}", tokens.to_string_lossy());
+        let (mut caesium, _) = test_caesium("token-minting", &config);
+
+        let store = caesium.tokens.clone().unwrap();
+        caesium.authentication = Some(Box::new(modules::authentication::chain::ChainedAuthentication::new(vec![
+            ("tokens".to_string(), Box::new(modules::authentication::tokens::ApiTokenAuthentication::new(store)) as Box<modules::authentication::Authentication>),
+            ("openid".to_string(), Box::new(TokenIsUser)),
+        ])));
+
+        let (_, token) = caesium.create_token(&user("alice"), ApiTokenOptions::default()).unwrap();
+        assert_eq!(caesium.list_tokens(&user(&token)).unwrap().len(), 1);
+
+        let e = caesium.create_token(&user(&token), ApiTokenOptions::default()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthorizationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert_eq!(caesium.list_tokens(&user("alice")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn downloads_are_sent_when_url_fails() {
+        let (mut caesium, _) = test_caesium("download-url-fails", "");
+        let tar = crate_tar("example", "0.1.0");
+        caesium.publish(&manifest_json("example", "0.1.0"), &tar, &anonymous()).unwrap();
+        caesium.storage = Box::new(NoUrlStorage { tar: tar.clone() });
+
+        match caesium.download("example", "0.1.0", &anonymous()) {
+            Ok(Some(Download::Contents(contents))) => assert_eq!(contents, tar),
+            Ok(Some(Download::Redirect(url))) => panic!("unexpected redirect to {}", url),
+            Ok(None) => panic!("crate not found"),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn stored_crates_are_removed_when_not_indexed() {
+        let (caesium, uploads) = test_caesium("publish-not-indexed", "");
+        let dir = test_location("publish-not-indexed");
+
+        // The index file's directory can't be created where a file is
+        std::fs::create_dir_all(dir.join("repo").join("ex")).unwrap();
+        std::fs::File::create(dir.join("repo").join("ex").join("am")).unwrap();
+
+        assert!(caesium.publish(&manifest_json("example", "0.1.0"), &crate_tar("example", "0.1.0"), &anonymous()).is_err());
+        assert!(uploads.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stored_crates_are_kept_when_the_index_is_not_pushed() {
+        let (caesium, uploads) = test_caesium("publish-not-pushed", "");
+        let dir = test_location("publish-not-pushed");
+        std::fs::remove_dir_all(dir.join("index.git")).unwrap();
+
+        let e = caesium.publish(&manifest_json("example", "0.1.0"), &crate_tar("example", "0.1.0"), &anonymous()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::IndexPushError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert_eq!(*uploads.lock().unwrap(), vec!["example 0.1.0".to_string()]);
+        assert_eq!(caesium.registry.crate_entries("example").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn crates_are_published_one_at_a_time() {
+        let (caesium, uploads) = test_caesium("publish-concurrent", "");
+        caesium.publishing.lock().unwrap().insert("example".to_string());
+
+        let e = caesium.publish(&manifest_json("Example", "0.1.0"), &crate_tar("Example", "0.1.0"), &anonymous()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::ValidationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert!(uploads.lock().unwrap().is_empty());
+
+        caesium.publishing.lock().unwrap().clear();
+        caesium.publish(&manifest_json("example", "0.1.0"), &crate_tar("example", "0.1.0"), &anonymous()).unwrap();
+        assert!(caesium.publishing.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,159 @@
+use errors::*;
+use modules::*;
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+/// Which part of the client certificate identifies the user.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CertificateIdentity {
+    CommonName,
+    DnsName,
+}
+
+/// Authentication using the certificate that the client connected with, so
+/// that machines can publish without a token. This needs the server to be
+/// configured to verify client certificates.
+pub struct CertificateAuthentication {
+    pub identity: CertificateIdentity,
+
+    // The organizational units which are used as groups, anyone who can get a
+    // certificate issued could choose the others
+    pub groups: Vec<String>,
+}
+
+impl CertificateAuthentication {
+    pub fn new(config: &::config::CaesiumCertificateConfig) -> CertificateAuthentication {
+        CertificateAuthentication {
+            identity: config.identity.unwrap_or(CertificateIdentity::CommonName),
+            groups: config.groups.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl authentication::Authentication for CertificateAuthentication {
+    fn authenticate(&self, token: &str) -> Result<authentication::AuthenticationUserInfo> {
+        self.authenticate_request(token, &authentication::AuthenticationRequest::default())
+    }
+
+    fn authenticate_request(&self, _token: &str, request: &authentication::AuthenticationRequest) -> Result<authentication::AuthenticationUserInfo> {
+        let certificate = match request.certificate {
+            Some(certificate) => certificate,
+            None => bail!(ErrorKind::AuthenticationError("No client certificate".to_string())),
+        };
+
+        let sub = match self.identity {
+            CertificateIdentity::CommonName => certificate.common_name.clone(),
+            CertificateIdentity::DnsName => certificate.dns_names.first().cloned(),
+        };
+        let sub = match sub {
+            Some(sub) => sub,
+            None => bail!(ErrorKind::AuthenticationError(format!("Client certificate has no {:?}", self.identity))),
+        };
+
+        let groups = certificate.organizational_units.iter()
+                                                     .filter(|unit| self.groups.contains(*unit))
+                                                     .cloned()
+                                                     .collect();
+
+        let mut claims = HashMap::new();
+        claims.insert("dns_names".to_string(),
+                      Value::Array(certificate.dns_names.iter().cloned().map(Value::String).collect()));
+
+        Ok(authentication::AuthenticationUserInfo {
+            sub: sub,
+            name: certificate.common_name.clone(),
+            groups: groups,
+            claims: claims,
+            ..Default::default()
+        })
+    }
+
+    fn recognises(&self, _token: &str, request: &authentication::AuthenticationRequest) -> bool {
+        request.certificate.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::authentication::{Authentication, AuthenticationRequest};
+    use tls::ClientCertificate;
+    use tls::tests::generate_certificate;
+
+    fn client_certificate(common_name: Option<&str>, units: &[&str], dns_names: &[&str]) -> ClientCertificate {
+        ClientCertificate::new(&generate_certificate(common_name, units, dns_names).0)
+    }
+
+    fn authentication(identity: CertificateIdentity, groups: &[&str]) -> CertificateAuthentication {
+        CertificateAuthentication {
+            identity: identity,
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        }
+    }
+
+    fn authenticate(authentication: &CertificateAuthentication, certificate: &ClientCertificate) -> Result<authentication::AuthenticationUserInfo> {
+        authentication.authenticate_request("any", &AuthenticationRequest {
+            certificate: Some(certificate),
+            ..AuthenticationRequest::default()
+        })
+    }
+
+    #[test]
+    fn common_names_identify_users() {
+        let certificate = client_certificate(Some("build-bot"), &[], &["build.example.com"]);
+
+        let userinfo = authenticate(&authentication(CertificateIdentity::CommonName, &[]), &certificate).unwrap();
+        assert_eq!(userinfo.sub, "build-bot");
+        assert_eq!(userinfo.name, Some("build-bot".to_string()));
+        assert_eq!(userinfo.claims["dns_names"], Value::Array(vec![Value::String("build.example.com".to_string())]));
+    }
+
+    #[test]
+    fn dns_names_identify_users() {
+        let certificate = client_certificate(Some("Build Bot"), &[], &["build.example.com", "ci.example.com"]);
+
+        let userinfo = authenticate(&authentication(CertificateIdentity::DnsName, &[]), &certificate).unwrap();
+        assert_eq!(userinfo.sub, "build.example.com");
+        assert_eq!(userinfo.name, Some("Build Bot".to_string()));
+    }
+
+    #[test]
+    fn certificates_need_the_identity() {
+        let no_dns_names = client_certificate(Some("build-bot"), &[], &[]);
+        let e = authenticate(&authentication(CertificateIdentity::DnsName, &[]), &no_dns_names).unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthenticationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+
+        let no_common_name = client_certificate(None, &[], &["build.example.com"]);
+        assert!(authenticate(&authentication(CertificateIdentity::CommonName, &[]), &no_common_name).is_err());
+    }
+
+    #[test]
+    fn only_configured_units_are_groups() {
+        let certificate = client_certificate(Some("build-bot"), &["deployers", "admins"], &[]);
+
+        let userinfo = authenticate(&authentication(CertificateIdentity::CommonName, &["deployers"]), &certificate).unwrap();
+        assert_eq!(userinfo.groups, vec!["deployers".to_string()]);
+
+        let userinfo = authenticate(&authentication(CertificateIdentity::CommonName, &[]), &certificate).unwrap();
+        assert!(userinfo.groups.is_empty());
+    }
+
+    #[test]
+    fn requests_without_certificates_are_not_recognised() {
+        let authentication = authentication(CertificateIdentity::CommonName, &[]);
+        let certificate = client_certificate(Some("build-bot"), &[], &[]);
+
+        assert!(!authentication.recognises("token", &AuthenticationRequest::default()));
+        assert!(authentication.recognises("token", &AuthenticationRequest {
+            certificate: Some(&certificate),
+            ..AuthenticationRequest::default()
+        }));
+        assert!(authentication.authenticate("token").is_err());
+    }
+}
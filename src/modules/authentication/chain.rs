@@ -0,0 +1,153 @@
+use errors::*;
+use modules::*;
+
+/// Puts the user's sub in the backend's namespace, such as `ldap:alice`, as
+/// different backends could give the same sub to different people.
+pub fn namespaced(backend: &str, mut userinfo: authentication::AuthenticationUserInfo) -> authentication::AuthenticationUserInfo {
+    userinfo.sub = format!("{}:{}", backend, userinfo.sub);
+    userinfo.backend = Some(backend.to_string());
+    userinfo
+}
+
+/// Tries each of the configured authentication backends in turn, the first
+/// backend to accept the token is used.
+pub struct ChainedAuthentication {
+    pub backends: Vec<(String, Box<authentication::Authentication>)>,
+}
+
+impl ChainedAuthentication {
+    pub fn new(backends: Vec<(String, Box<authentication::Authentication>)>) -> ChainedAuthentication {
+        ChainedAuthentication {
+            backends: backends,
+        }
+    }
+}
+
+impl authentication::Authentication for ChainedAuthentication {
+    fn authenticate(&self, token: &str) -> Result<authentication::AuthenticationUserInfo> {
+        self.authenticate_request(token, &authentication::AuthenticationRequest::default())
+    }
+
+    fn authenticate_request(&self, token: &str, request: &authentication::AuthenticationRequest) -> Result<authentication::AuthenticationUserInfo> {
+        let mut rejections = Vec::new();
+        let mut failures = Vec::new();
+
+        for &(ref name, ref backend) in &self.backends {
+            if !backend.recognises(token, request) {
+                continue;
+            }
+
+            match backend.authenticate_request(token, request) {
+                // Tokens issued by Caesium hold the namespaced sub of the user
+                // they were issued to.
+                Ok(mut userinfo) if name == "tokens" => {
+                    userinfo.backend = Some(name.clone());
+                    return Ok(userinfo);
+                }
+                Ok(userinfo) => return Ok(namespaced(name, userinfo)),
+                Err(e) => {
+                    match *e.kind() {
+                        ErrorKind::AuthenticationError(ref reason) => rejections.push(format!("{} backend: {}", name, reason)),
+                        ErrorKind::AuthenticationBackendError(ref reason) => failures.push(format!("{} backend: {}", name, reason)),
+                        _ => failures.push(format!("{} backend: {}", name, e)),
+                    }
+                }
+            }
+        }
+
+        // The token could belong to a backend which is down, so it is not
+        // rejected outright.
+        if !failures.is_empty() {
+            bail!(ErrorKind::AuthenticationBackendError(failures.into_iter().chain(rejections).collect::<Vec<_>>().join(", ")));
+        }
+
+        if rejections.is_empty() {
+            bail!(ErrorKind::UnrecognisedToken("no authentication backend recognised the token".to_string()));
+        }
+
+        bail!(ErrorKind::AuthenticationError(format!("token rejected by {}", rejections.join(", "))))
+    }
+
+    fn recognises(&self, token: &str, request: &authentication::AuthenticationRequest) -> bool {
+        self.backends.iter().any(|&(_, ref backend)| backend.recognises(token, request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::authentication::Authentication;
+
+    /// A backend which recognises tokens with its prefix, and either accepts
+    /// them, rejects them or is unavailable.
+    struct StubBackend {
+        prefix: &'static str,
+        result: fn() -> Result<()>,
+    }
+
+    impl authentication::Authentication for StubBackend {
+        fn authenticate(&self, token: &str) -> Result<authentication::AuthenticationUserInfo> {
+            (self.result)()?;
+            Ok(authentication::AuthenticationUserInfo {
+                sub: token[self.prefix.len()..].to_string(),
+                ..Default::default()
+            })
+        }
+
+        fn recognises(&self, token: &str, _request: &authentication::AuthenticationRequest) -> bool {
+            token.starts_with(self.prefix)
+        }
+    }
+
+    fn chain() -> ChainedAuthentication {
+        fn accept() -> Result<()> { Ok(()) }
+        fn reject() -> Result<()> { bail!(ErrorKind::AuthenticationError("bad token".to_string())) }
+        fn unavailable() -> Result<()> { bail!(::std::io::Error::new(::std::io::ErrorKind::Other, "connection refused")) }
+
+        ChainedAuthentication::new(vec![
+            ("tokens".to_string(), Box::new(StubBackend { prefix: "t-", result: accept }) as Box<authentication::Authentication>),
+            ("ldap".to_string(), Box::new(StubBackend { prefix: "l-", result: accept })),
+            ("openid".to_string(), Box::new(StubBackend { prefix: "o-", result: accept })),
+            ("paseto".to_string(), Box::new(StubBackend { prefix: "x-", result: reject })),
+            ("certificate".to_string(), Box::new(StubBackend { prefix: "x-", result: unavailable })),
+        ])
+    }
+
+    #[test]
+    fn subs_are_namespaced_by_backend() {
+        let chain = chain();
+
+        let userinfo = chain.authenticate("l-alice").unwrap();
+        assert_eq!(userinfo.sub, "ldap:alice");
+        assert_eq!(userinfo.backend, Some("ldap".to_string()));
+
+        assert_eq!(chain.authenticate("o-alice").unwrap().sub, "openid:alice");
+
+        // API tokens already hold the sub they were issued to
+        let userinfo = chain.authenticate("t-openid:alice").unwrap();
+        assert_eq!(userinfo.sub, "openid:alice");
+        assert_eq!(userinfo.backend, Some("tokens".to_string()));
+    }
+
+    #[test]
+    fn errors_distinguish_unrecognised_rejected_and_unavailable() {
+        let e = chain().authenticate("unknown").unwrap_err();
+        match *e.kind() {
+            ErrorKind::UnrecognisedToken(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+
+        let chain = ChainedAuthentication::new(chain().backends.into_iter().filter(|&(ref name, _)| name != "certificate").collect());
+        let e = chain.authenticate("x-alice").unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthenticationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+
+        let e = self::chain().authenticate("x-alice").unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthenticationBackendError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+}
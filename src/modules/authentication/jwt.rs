@@ -0,0 +1,179 @@
+use errors::*;
+
+use std::io;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+use futures::{Future, Stream};
+use hyper;
+use hyper::Client;
+use hyper_tls::HttpsConnector;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use serde_json;
+use serde_json::Value;
+use tokio_core;
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+pub fn fetch_json(uri: hyper::Uri) -> Result<Value> {
+    let mut core = tokio_core::reactor::Core::new().unwrap();
+    let handle = core.handle();
+
+    let client = Client::configure()
+        .connector(HttpsConnector::new(4, &handle).expect("Failed to setup HTTPS"))
+        .build(&handle);
+    let work = client.get(uri)
+        .and_then(|res| {
+            if res.status() != hyper::StatusCode::Ok {
+                bail!(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Invalid status code: {}", res.status()),
+                ));
+            }
+            Ok(res)
+        }).and_then(|res| res.body().concat2());
+
+    let body = core.run(work)?;
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+fn decode(part: &str) -> Result<Vec<u8>> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| ErrorKind::AuthenticationError("Invalid base64 in JWT".to_string()).into())
+}
+
+/// Whether a token looks like a JWT, rather than an opaque token.
+pub fn is_jwt(token: &str) -> bool {
+    let parts = token.split('.').collect::<Vec<_>>();
+    parts.len() == 3 &&
+        parts.iter().all(|part| !part.is_empty() && decode(part).is_ok()) &&
+        decode(parts[0]).ok().and_then(|header| serde_json::from_slice::<JwtHeader>(&header).ok()).is_some()
+}
+
+/// The keys that an issuer signs its JWTs with, fetched from its JWKS URL.
+#[derive(Debug)]
+pub struct JsonWebKeys {
+    uri: hyper::Uri,
+
+    // Keys are cached, and refreshed when a token uses a key we do not have
+    keys: Mutex<Vec<Jwk>>,
+}
+
+impl JsonWebKeys {
+    pub fn new(uri: hyper::Uri) -> JsonWebKeys {
+        JsonWebKeys {
+            uri: uri,
+            keys: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn find_key(&self, kid: Option<&String>) -> Result<Jwk> {
+        let find = |keys: &[Jwk]| keys.iter().find(|key| key.kty == "RSA" && (kid.is_none() || key.kid.as_ref() == kid)).cloned();
+
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(key) = find(&keys[..]) {
+            return Ok(key);
+        }
+
+        let jwks: Jwks = serde_json::from_value(fetch_json(self.uri.clone())?)?;
+        *keys = jwks.keys;
+
+        match find(&keys[..]) {
+            Some(key) => Ok(key),
+            None => bail!(ErrorKind::AuthenticationError("No key found to verify JWT".to_string())),
+        }
+    }
+
+    fn verify_signature(&self, header: &JwtHeader, signing_input: &str, signature: &[u8]) -> Result<()> {
+        if header.alg != "RS256" {
+            bail!(ErrorKind::AuthenticationError(format!("Unsupported JWT algorithm {}", header.alg)));
+        }
+
+        let key = self.find_key(header.kid.as_ref())?;
+        let (n, e) = match (key.n, key.e) {
+            (Some(n), Some(e)) => (n, e),
+            _ => bail!(ErrorKind::AuthenticationError("Invalid RSA key in JWKS".to_string())),
+        };
+
+        let rsa = Rsa::from_public_components(BigNum::from_slice(&decode(&n)?)?, BigNum::from_slice(&decode(&e)?)?)?;
+        let pkey = PKey::from_rsa(rsa)?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &pkey)?;
+        verifier.update(signing_input.as_bytes())?;
+
+        if !verifier.verify(signature)? {
+            bail!(ErrorKind::AuthenticationError("Invalid JWT signature".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Verifies the signature of a JWT and returns its claims, which must
+    /// then be checked with `check_claims`.
+    pub fn verify(&self, jwt: &str) -> Result<serde_json::Map<String, Value>> {
+        let parts = jwt.split('.').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            bail!(ErrorKind::AuthenticationError("Invalid JWT".to_string()));
+        }
+
+        let header: JwtHeader = serde_json::from_slice(&decode(parts[0])?)?;
+        let signing_input = format!("{}.{}", parts[0], parts[1]);
+        self.verify_signature(&header, &signing_input, &decode(parts[2])?)?;
+
+        match serde_json::from_slice::<Value>(&decode(parts[1])?)? {
+            Value::Object(claims) => Ok(claims),
+            _ => bail!(ErrorKind::AuthenticationError("Invalid JWT claims".to_string())),
+        }
+    }
+}
+
+/// Checks that a JWT was issued by the expected issuer and is valid now, and
+/// that it was issued for us if an audience is given.
+pub fn check_claims(claims: &serde_json::Map<String, Value>, issuer: &str, audience: Option<&str>) -> Result<()> {
+    if claims.get("iss").and_then(|iss| iss.as_str()) != Some(issuer) {
+        bail!(ErrorKind::AuthenticationError("JWT was not issued by the trusted issuer".to_string()));
+    }
+
+    if let Some(audience) = audience {
+        let audience_matches = match claims.get("aud") {
+            Some(&Value::String(ref aud)) => aud == audience,
+            Some(&Value::Array(ref auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+            _ => false,
+        };
+        if !audience_matches {
+            bail!(ErrorKind::AuthenticationError("JWT is not for this registry".to_string()));
+        }
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0);
+    if claims.get("exp").and_then(|exp| exp.as_u64()).map_or(true, |exp| exp <= now) {
+        bail!(ErrorKind::AuthenticationError("JWT has expired".to_string()));
+    }
+    if claims.get("nbf").and_then(|nbf| nbf.as_u64()).map_or(false, |nbf| nbf > now) {
+        bail!(ErrorKind::AuthenticationError("JWT is not valid yet".to_string()));
+    }
+
+    Ok(())
+}
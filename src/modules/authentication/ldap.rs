@@ -0,0 +1,313 @@
+use errors::*;
+use modules::*;
+
+use std::collections::HashMap;
+
+use ldap3::{ldap_escape, LdapConn, Scope, SearchEntry};
+use serde_json::Value;
+
+// LDAP result code for a bind with the wrong DN or password.
+const INVALID_CREDENTIALS: u32 = 49;
+
+const DEFAULT_NAME_ATTRIBUTE: &'static str = "cn";
+const DEFAULT_EMAIL_ATTRIBUTE: &'static str = "mail";
+const DEFAULT_GROUP_FILTER: &'static str = "(member={dn})";
+const DEFAULT_GROUP_ATTRIBUTE: &'static str = "cn";
+
+// Group membership recorded on the user's own entry, used when groups are not
+// searched for.
+const MEMBER_OF_ATTRIBUTE: &'static str = "memberOf";
+
+// Users who bind as a service account get a different sub, so that they can't
+// be mistaken for a person with the same name.
+const SERVICE_ACCOUNT_PREFIX: &'static str = "service-account:";
+
+type Attributes = HashMap<String, Vec<String>>;
+
+/// The operations used on the directory, so that it can be replaced in tests.
+trait Directory {
+    /// Returns false if the DN or password is wrong, and an error for any
+    /// other failure.
+    fn bind(&self, dn: &str, password: &str) -> Result<bool>;
+
+    /// Reads the attributes of a single entry.
+    fn read(&self, dn: &str, attrs: Vec<&str>) -> Result<Option<Attributes>>;
+
+    /// Searches the subtree under the base DN.
+    fn search(&self, base: &str, filter: &str, attrs: Vec<&str>) -> Result<Vec<Attributes>>;
+}
+
+impl Directory for LdapConn {
+    fn bind(&self, dn: &str, password: &str) -> Result<bool> {
+        let result = self.simple_bind(dn, password)?;
+        match result.rc {
+            0 => Ok(true),
+            INVALID_CREDENTIALS => Ok(false),
+            _ => bail!(ErrorKind::AuthenticationBackendError(format!("LDAP bind failed: {}", result))),
+        }
+    }
+
+    fn read(&self, dn: &str, attrs: Vec<&str>) -> Result<Option<Attributes>> {
+        let (entries, _) = LdapConn::search(self, dn, Scope::Base, "(objectClass=*)", attrs)?.success()?;
+        Ok(entries.into_iter().next().map(|entry| SearchEntry::construct(entry).attrs))
+    }
+
+    fn search(&self, base: &str, filter: &str, attrs: Vec<&str>) -> Result<Vec<Attributes>> {
+        let (entries, _) = LdapConn::search(self, base, Scope::Subtree, filter, attrs)?.success()?;
+        Ok(entries.into_iter().map(|entry| SearchEntry::construct(entry).attrs).collect())
+    }
+}
+
+/// Authentication against an LDAP directory, where the token is given as
+/// `user:password` and checked with a simple bind as the user.
+pub struct LdapAuthentication {
+    pub url: String,
+
+    // Templates for the DN to bind as, with `{username}` replaced by the user,
+    // along with the prefix for the sub of users who bind with it
+    pub user_dns: Vec<(String, &'static str)>,
+
+    pub name_attribute: String,
+    pub email_attribute: String,
+
+    // Where to search for groups that the user is a member of, otherwise the
+    // user's `memberOf` attribute is used.
+    pub group_base_dn: Option<String>,
+    pub group_filter: String,
+    pub group_attribute: String,
+}
+
+/// Usernames are put into DNs, so only allow characters which never need to
+/// be escaped.
+fn valid_username(username: &str) -> bool {
+    !username.is_empty() &&
+        username.chars().all(|c| c.is_alphanumeric() || c == '.' || c == '-' || c == '_' || c == '@')
+}
+
+/// The value of the first part of a DN, such as `billing` for
+/// `cn=billing,ou=groups,dc=example,dc=com`.
+fn first_rdn_value(dn: &str) -> Option<String> {
+    dn.split(',').next()
+      .and_then(|rdn| rdn.splitn(2, '=').nth(1))
+      .map(|value| value.trim().to_string())
+}
+
+impl LdapAuthentication {
+    pub fn new(config: &::config::CaesiumLdapConfig) -> LdapAuthentication {
+        let mut user_dns = vec![(config.user_dn.clone(), "")];
+        if let Some(ref service_account_dn) = config.service_account_dn {
+            user_dns.push((service_account_dn.clone(), SERVICE_ACCOUNT_PREFIX));
+        }
+
+        LdapAuthentication {
+            url: config.url.clone(),
+            user_dns: user_dns,
+            name_attribute: config.name_attribute.clone().unwrap_or(DEFAULT_NAME_ATTRIBUTE.to_string()),
+            email_attribute: config.email_attribute.clone().unwrap_or(DEFAULT_EMAIL_ATTRIBUTE.to_string()),
+            group_base_dn: config.group_base_dn.clone(),
+            group_filter: config.group_filter.clone().unwrap_or(DEFAULT_GROUP_FILTER.to_string()),
+            group_attribute: config.group_attribute.clone().unwrap_or(DEFAULT_GROUP_ATTRIBUTE.to_string()),
+        }
+    }
+
+    /// Binds as the user, trying each of the DN templates in turn, and returns
+    /// the DN that the bind succeeded with and the user's sub.
+    fn bind(&self, directory: &Directory, username: &str, password: &str) -> Result<(String, String)> {
+        for &(ref user_dn, prefix) in &self.user_dns {
+            let dn = user_dn.replace("{username}", username);
+
+            if directory.bind(&dn, password)? {
+                return Ok((dn, format!("{}{}", prefix, username)));
+            }
+        }
+
+        bail!(ErrorKind::AuthenticationError("Invalid LDAP username or password".to_string()))
+    }
+
+    fn groups(&self, directory: &Directory, dn: &str, entry: &Attributes) -> Result<Vec<String>> {
+        let group_base_dn = match self.group_base_dn {
+            Some(ref group_base_dn) => group_base_dn,
+            None => {
+                return Ok(entry.get(MEMBER_OF_ATTRIBUTE)
+                               .map(|groups| groups.iter().filter_map(|group| first_rdn_value(group)).collect())
+                               .unwrap_or_default());
+            }
+        };
+
+        let filter = self.group_filter.replace("{dn}", &ldap_escape(dn));
+        let groups = directory.search(group_base_dn, &filter, vec![self.group_attribute.as_str()])?;
+
+        Ok(groups.into_iter()
+                 .filter_map(|group| group.get(&self.group_attribute).and_then(|values| values.first().cloned()))
+                 .collect())
+    }
+
+    fn authenticate_with(&self, directory: &Directory, username: &str, password: &str) -> Result<authentication::AuthenticationUserInfo> {
+        let (dn, sub) = self.bind(directory, username, password)?;
+
+        let entry = directory.read(&dn, vec![self.name_attribute.as_str(), self.email_attribute.as_str(), MEMBER_OF_ATTRIBUTE])?;
+        let entry = match entry {
+            Some(entry) => entry,
+            None => bail!(ErrorKind::AuthenticationError(format!("LDAP entry {} not found", dn))),
+        };
+
+        let groups = self.groups(directory, &dn, &entry)?;
+        let attr = |name: &str| entry.get(name).and_then(|values| values.first().cloned());
+
+        let mut claims = HashMap::new();
+        claims.insert("dn".to_string(), Value::String(dn.clone()));
+
+        Ok(authentication::AuthenticationUserInfo {
+            sub: sub,
+            name: attr(&self.name_attribute),
+            email: attr(&self.email_attribute),
+            groups: groups,
+            claims: claims,
+            ..Default::default()
+        })
+    }
+}
+
+impl authentication::Authentication for LdapAuthentication {
+    fn authenticate(&self, token: &str) -> Result<authentication::AuthenticationUserInfo> {
+        let (username, password) = match token.find(':') {
+            Some(index) => (&token[..index], &token[index + 1..]),
+            None => bail!(ErrorKind::AuthenticationError("LDAP tokens must be given as user:password".to_string())),
+        };
+
+        if !valid_username(username) {
+            bail!(ErrorKind::AuthenticationError(format!("Invalid LDAP username '{}'", username)));
+        }
+
+        // An empty password would be an unauthenticated bind, which most
+        // servers allow for anyone.
+        if password.is_empty() {
+            bail!(ErrorKind::AuthenticationError("LDAP password must not be empty".to_string()));
+        }
+
+        let ldap = LdapConn::new(&self.url).map_err(|e| {
+            ErrorKind::AuthenticationBackendError(format!("failed to connect to LDAP server: {}", e))
+        })?;
+        let userinfo = self.authenticate_with(&ldap, username, password);
+        let _ = ldap.unbind();
+
+        userinfo
+    }
+
+    fn recognises(&self, token: &str, _request: &authentication::AuthenticationRequest) -> bool {
+        token.contains(':')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::authentication::Authentication;
+
+    use toml;
+
+    /// A directory held in memory, with users and the DNs of group members.
+    struct StubDirectory {
+        users: HashMap<String, (String, Attributes)>,
+        groups: Vec<(String, Vec<String>)>,
+    }
+
+    impl StubDirectory {
+        fn new() -> StubDirectory {
+            let mut users = HashMap::new();
+            for &(dn, password, cn) in &[("uid=alice,ou=people,dc=example,dc=com", "secret", "Alice"),
+                                         ("uid=deploy,ou=services,dc=example,dc=com", "s3rvice", "Deploy"),
+                                         ("uid=ci,ou=services,dc=example,dc=com", "ci-secret", "CI")] {
+                let mut attrs = HashMap::new();
+                attrs.insert("cn".to_string(), vec![cn.to_string()]);
+                attrs.insert("mail".to_string(), vec![format!("{}@example.com", cn.to_lowercase())]);
+                users.insert(dn.to_string(), (password.to_string(), attrs));
+            }
+
+            StubDirectory {
+                users: users,
+                groups: vec![("billing".to_string(), vec!["uid=alice,ou=people,dc=example,dc=com".to_string()])],
+            }
+        }
+    }
+
+    impl Directory for StubDirectory {
+        fn bind(&self, dn: &str, password: &str) -> Result<bool> {
+            Ok(self.users.get(dn).map_or(false, |&(ref expected, _)| expected == password))
+        }
+
+        fn read(&self, dn: &str, _attrs: Vec<&str>) -> Result<Option<Attributes>> {
+            Ok(self.users.get(dn).map(|&(_, ref attrs)| attrs.clone()))
+        }
+
+        fn search(&self, _base: &str, filter: &str, _attrs: Vec<&str>) -> Result<Vec<Attributes>> {
+            Ok(self.groups.iter()
+                          .filter(|&&(_, ref members)| members.iter().any(|member| filter == format!("(member={})", member)))
+                          .map(|&(ref name, _)| {
+                              let mut attrs = HashMap::new();
+                              attrs.insert("cn".to_string(), vec![name.clone()]);
+                              attrs
+                          })
+                          .collect())
+        }
+    }
+
+    fn ldap() -> LdapAuthentication {
+        LdapAuthentication::new(&toml::from_str(r#"
+            url = "ldap://localhost:389"
+            user_dn = "uid={username},ou=people,dc=example,dc=com"
+            service_account_dn = "uid={username},ou=services,dc=example,dc=com"
+            group_base_dn = "ou=groups,dc=example,dc=com"
+        "#).unwrap())
+    }
+
+    #[test]
+    fn user_bind() {
+        let userinfo = ldap().authenticate_with(&StubDirectory::new(), "alice", "secret").unwrap();
+        assert_eq!(userinfo.sub, "alice");
+        assert_eq!(userinfo.name, Some("Alice".to_string()));
+        assert_eq!(userinfo.email, Some("alice@example.com".to_string()));
+        assert_eq!(userinfo.groups, vec!["billing".to_string()]);
+        assert_eq!(userinfo.claims["dn"], "uid=alice,ou=people,dc=example,dc=com");
+    }
+
+    #[test]
+    fn service_account_bind_has_different_sub() {
+        let userinfo = ldap().authenticate_with(&StubDirectory::new(), "deploy", "s3rvice").unwrap();
+        assert_eq!(userinfo.sub, "service-account:deploy");
+        assert!(userinfo.groups.is_empty());
+        assert_eq!(userinfo.claims["dn"], "uid=deploy,ou=services,dc=example,dc=com");
+    }
+
+    #[test]
+    fn wrong_password_is_rejected() {
+        let e = ldap().authenticate_with(&StubDirectory::new(), "alice", "s3rvice").unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthenticationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn invalid_tokens_are_rejected_before_binding() {
+        let ldap = ldap();
+        assert!(ldap.authenticate("alice").is_err());
+        assert!(ldap.authenticate("alice:").is_err());
+        assert!(ldap.authenticate("*)(uid=*:secret").is_err());
+    }
+
+    /// Runs against a real server, such as `docker run -p 389:389 osixia/openldap`,
+    /// with the admin user `cn=admin,dc=example,dc=org` and password `admin`.
+    #[test]
+    #[ignore]
+    fn openldap_bind() {
+        let ldap = LdapAuthentication::new(&toml::from_str(r#"
+            url = "ldap://localhost:389"
+            user_dn = "cn={username},dc=example,dc=org"
+        "#).unwrap());
+
+        let userinfo = ldap.authenticate("admin:admin").unwrap();
+        assert_eq!(userinfo.sub, "admin");
+        assert!(ldap.authenticate("admin:wrong").is_err());
+    }
+}
@@ -1,15 +1,115 @@
 
 use errors::*;
 
-#[derive(Debug, Deserialize)]
+use std::collections::HashMap;
+
+use serde_json::Value;
+use glob::Pattern;
+
+/// The actions which a restricted token can be allowed to perform.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenScope {
+    PublishNew,
+    PublishUpdate,
+    Yank,
+    ChangeOwners,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct AuthenticationUserInfo {
     pub sub: String,
     pub name: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+
+    // Any additional claims that the module has been configured to keep
+    #[serde(default)]
+    pub claims: HashMap<String, Value>,
+
+    // Restrictions from the token used, `None` means that the token allows
+    // every action or every crate.
+    #[serde(default)]
+    pub scopes: Option<Vec<TokenScope>>,
+    #[serde(default)]
+    pub crate_scopes: Option<Vec<String>>,
+
+    // The name of the authentication backend which accepted the token
+    #[serde(default)]
+    pub backend: Option<String>,
+}
+
+impl AuthenticationUserInfo {
+    /// Checks that the token used allows the action on the named crate.
+    pub fn check_scope(&self, scope: TokenScope, name: &str) -> Result<()> {
+        if let Some(ref scopes) = self.scopes {
+            if !scopes.contains(&scope) {
+                bail!(ErrorKind::AuthorizationError(format!("token does not have the {:?} scope", scope)));
+            }
+        }
+
+        if let Some(ref crate_scopes) = self.crate_scopes {
+            let allowed = crate_scopes.iter().any(|crate_scope| {
+                Pattern::new(crate_scope).map(|pattern| pattern.matches(name)).unwrap_or(false)
+            });
+            if !allowed {
+                bail!(ErrorKind::AuthorizationError(format!("token is not allowed to be used for crate '{}'", name)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the token used has no restrictions, which is needed for
+    /// actions that are not covered by a scope, such as managing tokens.
+    pub fn check_unrestricted(&self) -> Result<()> {
+        if self.scopes.is_some() || self.crate_scopes.is_some() {
+            bail!(ErrorKind::AuthorizationError("this action requires a token without scopes".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Details of the request that a token is being used for. Asymmetric tokens
+/// are signed for a single request, so they are checked against these.
+#[derive(Debug, Default)]
+pub struct AuthenticationRequest<'a> {
+    // One of "publish", "yank", "unyank" or "owners", or `None` for requests
+    // which do not change anything.
+    pub mutation: Option<&'a str>,
+    pub name: Option<&'a str>,
+    pub vers: Option<&'a str>,
+    pub cksum: Option<&'a str>,
+
+    // The verified certificate that the client connected with, if any
+    pub certificate: Option<&'a ::tls::ClientCertificate>,
 }
 
 pub trait Authentication {
     // Authenticate using the token provided by cargo publish.
     fn authenticate(&self, token: &str) -> Result<AuthenticationUserInfo>;
+
+    // Authenticate a token for a particular request, modules which only look
+    // at the token itself do not need to implement this.
+    fn authenticate_request(&self, token: &str, _request: &AuthenticationRequest) -> Result<AuthenticationUserInfo> {
+        self.authenticate(token)
+    }
+
+    // Whether the request looks like one which this module handles, modules
+    // which cannot tell from the token alone try every token.
+    fn recognises(&self, _token: &str, _request: &AuthenticationRequest) -> bool {
+        true
+    }
 }
 
+pub mod certificate;
+pub mod chain;
+pub mod jwt;
+pub mod ldap;
 pub mod openid;
+pub mod paseto;
+pub mod tokens;
+pub mod trusted_publishing;
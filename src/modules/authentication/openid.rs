@@ -1,6 +1,7 @@
 
 use errors::*;
 use modules::*;
+use modules::authentication::jwt::{self, JsonWebKeys};
 
 use tokio_core;
 use hyper;
@@ -14,6 +15,7 @@ use serde_json::Value;
 use futures::Stream;
 use std::str::FromStr;
 use std::io;
+use std::collections::HashMap;
 
 #[derive(Debug)]
 pub struct OpenIdAuthentication {
@@ -23,10 +25,32 @@ pub struct OpenIdAuthentication {
     pub authorization_endpoint: hyper::Uri,
     pub token_endpoint: hyper::Uri,
     pub userinfo_endpoint: hyper::Uri,
+
+    // Claims whose values are treated as the groups that the user is in
+    pub groups_claims: Vec<String>,
+
+    // Additional claims to keep in the user info
+    pub claims: Vec<String>,
+
+    // Used to verify tokens which are JWTs, so that their claims can be used
+    pub issuer: Option<String>,
+    pub jwks: Option<JsonWebKeys>,
+}
+
+/// Whether a token could be an opaque access token, which are made of the
+/// characters allowed in a bearer token. Tokens in the formats of Caesium's
+/// other backends are not.
+fn is_opaque_token(token: &str) -> bool {
+    !token.is_empty() &&
+        !token.starts_with(authentication::tokens::TOKEN_PREFIX) &&
+        !token.starts_with(authentication::paseto::TOKEN_HEADER) &&
+        token.trim_right_matches('=')
+             .chars()
+             .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_' || c == '~' || c == '+' || c == '/')
 }
 
 impl OpenIdAuthentication {
-    pub fn new(openid_configuration: &str) -> OpenIdAuthentication {
+    pub fn new(openid_configuration: &str, groups_claims: Vec<String>, claims: Vec<String>) -> OpenIdAuthentication {
 
         // Get the endpoints that should be used with OAuth2
         let mut core = tokio_core::reactor::Core::new().unwrap();
@@ -54,12 +78,74 @@ impl OpenIdAuthentication {
         let auth_endpoint = hyper::Uri::from_str(openid_config["authorization_endpoint"].as_str().unwrap()).expect("Invalid authorization_endpoint received");
         let token_endpoint = hyper::Uri::from_str(openid_config["token_endpoint"].as_str().unwrap()).expect("Invalid token_endpoint received");
         let userinfo_endpoint = hyper::Uri::from_str(openid_config["userinfo_endpoint"].as_str().unwrap()).expect("Invalid userinfo_endpoint received");
+        let jwks = openid_config["jwks_uri"].as_str().map(|jwks_uri| {
+            JsonWebKeys::new(hyper::Uri::from_str(jwks_uri).expect("Invalid jwks_uri received"))
+        });
 
         OpenIdAuthentication {
             openid_configuration: openid_config_url,
             authorization_endpoint: auth_endpoint,
             token_endpoint: token_endpoint,
             userinfo_endpoint: userinfo_endpoint,
+            groups_claims: groups_claims,
+            claims: claims,
+            issuer: openid_config["issuer"].as_str().map(|issuer| issuer.to_string()),
+            jwks: jwks,
+        }
+    }
+
+    /// Builds the user info from the claims returned by the userinfo
+    /// endpoint, keeping the configured groups and additional claims.
+    fn user_info(&self, claims: &serde_json::Map<String, Value>) -> Result<authentication::AuthenticationUserInfo> {
+        let sub = match claims.get("sub").and_then(|sub| sub.as_str()) {
+            Some(sub) => sub.to_string(),
+            None => bail!(ErrorKind::AuthenticationError("No sub claim in user info".to_string())),
+        };
+
+        let mut groups = Vec::new();
+        for groups_claim in &self.groups_claims {
+            match claims.get(groups_claim) {
+                Some(&Value::Array(ref values)) => {
+                    groups.extend(values.iter().filter_map(|value| value.as_str()).map(|value| value.to_string()));
+                }
+                Some(&Value::String(ref value)) => groups.push(value.clone()),
+                _ => {}
+            }
+        }
+
+        let kept_claims = self.claims.iter()
+                                     .filter_map(|claim| claims.get(claim).map(|value| (claim.clone(), value.clone())))
+                                     .collect::<HashMap<_, _>>();
+
+        Ok(authentication::AuthenticationUserInfo {
+            sub: sub,
+            name: claims.get("name").and_then(|name| name.as_str()).map(|name| name.to_string()),
+            email: claims.get("email").and_then(|email| email.as_str()).map(|email| email.to_string()),
+            groups: groups,
+            claims: kept_claims,
+            ..Default::default()
+        })
+    }
+
+    /// Returns the claims from the token if it is a JWT signed by the
+    /// provider, or `None` for opaque tokens and JWTs which can't be verified.
+    fn verified_jwt_claims(&self, token: &str) -> Option<serde_json::Map<String, Value>> {
+        let (issuer, jwks) = match (&self.issuer, &self.jwks) {
+            (&Some(ref issuer), &Some(ref jwks)) if jwt::is_jwt(token) => (issuer, jwks),
+            _ => return None,
+        };
+
+        let verified = jwks.verify(token).and_then(|claims| {
+            jwt::check_claims(&claims, issuer, None)?;
+            Ok(claims)
+        });
+
+        match verified {
+            Ok(claims) => Some(claims),
+            Err(e) => {
+                println!("Not using claims from JWT: {}", e);
+                None
+            }
         }
     }
 }
@@ -86,26 +172,44 @@ impl authentication::Authentication for OpenIdAuthentication {
 
         let work = client.request(request)
             .and_then(|res| {
-                if res.status() != hyper::StatusCode::Ok {
-                    bail!(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Invalid status code: {}", res.status()),
-                    ));
-                }
-                Ok(res)
-            }).and_then(|res| {
-                res.body().concat2().and_then(move |body| {
-                    let json: authentication::AuthenticationUserInfo = serde_json::from_slice(&body).map_err(|e| {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            e
-                        )
-                    })?;
-                    Ok(json)
-                })
+                let status = res.status();
+                res.body().concat2().map(move |body| (status, body))
             });
-        let user_info = core.run(work).map_err(|_| ErrorKind::AuthenticationError("Failed to authenticate".to_string()))?;
+        let (status, body) = core.run(work).map_err(|e| {
+            ErrorKind::AuthenticationBackendError(format!("Failed to reach OpenID provider: {}", e))
+        })?;
+
+        // The provider rejects tokens it does not accept, anything else means
+        // it could not decide.
+        match status {
+            hyper::StatusCode::Ok => {}
+            hyper::StatusCode::Unauthorized | hyper::StatusCode::Forbidden => {
+                bail!(ErrorKind::AuthenticationError("Token rejected by OpenID provider".to_string()));
+            }
+            status => bail!(ErrorKind::AuthenticationBackendError(format!("OpenID provider returned {}", status))),
+        }
+
+        let mut claims: serde_json::Map<String, Value> = serde_json::from_slice(&body).map_err(|e| {
+            ErrorKind::AuthenticationBackendError(format!("Invalid user info from OpenID provider: {}", e))
+        })?;
+
+        // Claims which the userinfo endpoint does not return (such as groups)
+        // are taken from the token, but only if it is a JWT which the provider
+        // signed for the same user.
+        if let Some(jwt_claims) = self.verified_jwt_claims(token) {
+            if jwt_claims.get("sub").is_some() && jwt_claims.get("sub") == claims.get("sub") {
+                for (claim, value) in jwt_claims {
+                    claims.entry(claim).or_insert(value);
+                }
+            }
+        }
+
+        self.user_info(&claims)
+    }
 
-        Ok(user_info)
+    // Tokens from the provider are either JWTs or opaque access tokens, other
+    // backends' tokens are never sent to the provider.
+    fn recognises(&self, token: &str, _request: &authentication::AuthenticationRequest) -> bool {
+        jwt::is_jwt(token) || is_opaque_token(token)
     }
 }
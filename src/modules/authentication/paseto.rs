@@ -0,0 +1,565 @@
+use errors::*;
+use modules::*;
+use modules::authentication::tokens;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+
+use base64;
+use chrono::DateTime;
+use openssl::bn::BigNumContext;
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid;
+use openssl::pkey::PKey;
+use openssl::sign::Verifier;
+use serde_json;
+use serde_json::Value;
+
+pub const TOKEN_HEADER: &'static str = "v3.public.";
+const PUBLIC_KEY_PREFIX: &'static str = "k3.public.";
+const KEY_ID_PREFIX: &'static str = "k3.pid.";
+
+// Public keys are compressed P-384 points, and signatures are the r and s
+// values of the ECDSA signature.
+const PUBLIC_KEY_LENGTH: usize = 49;
+const SIGNATURE_LENGTH: usize = 96;
+
+// Key IDs are made from the start of the SHA384 of the key.
+const KEY_ID_LENGTH: usize = 33;
+
+const DEFAULT_MAX_AGE_SECS: u64 = 300;
+
+// Allow for the client's clock being a little ahead of ours.
+const CLOCK_SKEW_SECS: i64 = 60;
+
+/// A public key which has been registered for a user, along with the details
+/// of the user to authenticate requests signed with it as.
+#[derive(Debug, Deserialize)]
+struct RegisteredKey {
+    key: String,
+    sub: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+struct PublicKey {
+    // The compressed point, which is part of what gets signed
+    bytes: Vec<u8>,
+    userinfo: authentication::AuthenticationUserInfo,
+}
+
+#[derive(Deserialize)]
+struct Footer {
+    url: String,
+    kip: String,
+}
+
+fn decode(part: &str) -> Result<Vec<u8>> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| ErrorKind::AuthenticationError("Invalid base64 in asymmetric token".to_string()).into())
+}
+
+/// The PASERK ID of a `k3.public` key, which cargo sends as the `kip` in the
+/// token footer.
+fn key_id(paserk: &str) -> Result<String> {
+    let digest = hash(MessageDigest::sha384(), format!("{}{}", KEY_ID_PREFIX, paserk).as_bytes())?;
+    Ok(format!("{}{}", KEY_ID_PREFIX, base64::encode_config(&digest[..KEY_ID_LENGTH], base64::URL_SAFE_NO_PAD)))
+}
+
+fn public_key(bytes: &[u8]) -> Result<PKey> {
+    let group = EcGroup::from_curve_name(nid::SECP384R1)?;
+    let mut ctx = BigNumContext::new()?;
+    let point = EcPoint::from_bytes(&group, bytes, &mut ctx)?;
+    Ok(PKey::from_ec_key(EcKey::from_public_key(&group, &point)?)?)
+}
+
+// Pre-authentication encoding, which makes the boundaries between each of the
+// signed pieces unambiguous.
+fn pae(pieces: &[&[u8]]) -> Vec<u8> {
+    fn le64(n: usize) -> Vec<u8> {
+        let n = n as u64 & 0x7fff_ffff_ffff_ffff;
+        (0..8).map(|i| (n >> (8 * i)) as u8).collect()
+    }
+
+    let mut encoded = le64(pieces.len());
+    for piece in pieces {
+        encoded.extend(le64(piece.len()));
+        encoded.extend_from_slice(piece);
+    }
+    encoded
+}
+
+// OpenSSL expects ECDSA signatures to be DER encoded, but PASETO just has the
+// r and s values one after the other.
+fn der_signature(signature: &[u8]) -> Vec<u8> {
+    fn der_integer(value: &[u8]) -> Vec<u8> {
+        let start = value.iter().position(|&b| b != 0).unwrap_or(value.len() - 1);
+        let value = &value[start..];
+
+        let mut encoded = vec![0x02];
+        if value[0] & 0x80 != 0 {
+            encoded.push(value.len() as u8 + 1);
+            encoded.push(0);
+        } else {
+            encoded.push(value.len() as u8);
+        }
+        encoded.extend_from_slice(value);
+        encoded
+    }
+
+    let (r, s) = signature.split_at(signature.len() / 2);
+    let mut body = der_integer(r);
+    body.extend(der_integer(s));
+
+    let mut encoded = vec![0x30, body.len() as u8];
+    encoded.extend(body);
+    encoded
+}
+
+/// Authenticates PASETO v3.public tokens, which cargo signs for each request
+/// with the user's private key when using the `cargo:paseto` credential
+/// provider.
+pub struct PasetoAuthentication {
+    keys: HashMap<String, PublicKey>,
+    registry_url: String,
+    max_age: u64,
+
+    // The hashes of tokens which have been used to change something, along
+    // with when they were issued, so that they cannot be replayed. The signed
+    // content is hashed rather than the signature, as an ECDSA signature can
+    // be altered and still be valid.
+    used: Mutex<HashMap<Vec<u8>, i64>>,
+}
+
+impl PasetoAuthentication {
+    pub fn new(config: &::config::CaesiumPasetoConfig, registry_url: &str) -> PasetoAuthentication {
+        let mut contents = String::new();
+        File::open(&config.keys_file).and_then(|mut f| f.read_to_string(&mut contents))
+                                     .expect("Failed to read asymmetric token keys");
+        let registered: Vec<RegisteredKey> = serde_json::from_str(&contents).expect("Invalid asymmetric token keys");
+
+        let mut keys = HashMap::new();
+        for key in registered {
+            let sub = key.sub.clone();
+            let (id, key) = PasetoAuthentication::load_key(key)
+                .unwrap_or_else(|e| panic!("Invalid asymmetric token key for {}: {}", sub, e));
+            keys.insert(id, key);
+        }
+
+        PasetoAuthentication {
+            keys: keys,
+            registry_url: config.registry_url.clone().unwrap_or(registry_url.to_string()),
+            max_age: config.max_age.unwrap_or(DEFAULT_MAX_AGE_SECS),
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn load_key(registered: RegisteredKey) -> Result<(String, PublicKey)> {
+        if !registered.key.starts_with(PUBLIC_KEY_PREFIX) {
+            bail!("not a {} key", PUBLIC_KEY_PREFIX);
+        }
+
+        let bytes = decode(&registered.key[PUBLIC_KEY_PREFIX.len()..])?;
+        if bytes.len() != PUBLIC_KEY_LENGTH {
+            bail!("not a compressed P-384 public key");
+        }
+
+        // Check that the key is valid now, rather than on the first request
+        public_key(&bytes)?;
+
+        let key = PublicKey {
+            bytes: bytes,
+            userinfo: authentication::AuthenticationUserInfo {
+                sub: registered.sub,
+                name: registered.name,
+                email: registered.email,
+                groups: registered.groups,
+                ..Default::default()
+            },
+        };
+
+        Ok((key_id(&registered.key)?, key))
+    }
+
+    fn verify_signature(&self, key: &PublicKey, signed: &[u8], signature: &[u8]) -> Result<()> {
+        let pkey = public_key(&key.bytes)?;
+        let mut verifier = Verifier::new(MessageDigest::sha384(), &pkey)?;
+        verifier.update(signed)?;
+
+        if !verifier.verify(&der_signature(signature))? {
+            bail!(ErrorKind::AuthenticationError("Invalid asymmetric token signature".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the token was signed for this request, cargo includes the
+    /// details of any change being made in the claims.
+    fn check_claims(&self, claims: &HashMap<String, Value>, request: &authentication::AuthenticationRequest) -> Result<()> {
+        let claim = |name: &str| claims.get(name).and_then(|value| value.as_str());
+
+        // Challenges are only valid for the server which issued them, and
+        // Caesium doesn't issue any.
+        if claims.contains_key("challenge") {
+            bail!(ErrorKind::AuthenticationError("Asymmetric token was signed for a challenge which was not issued".to_string()));
+        }
+
+        if claim("mutation") != request.mutation {
+            bail!(ErrorKind::AuthenticationError("Asymmetric token was not signed for this request".to_string()));
+        }
+
+        for &(name, expected) in &[("name", request.name), ("vers", request.vers), ("cksum", request.cksum)] {
+            if let Some(expected) = expected {
+                if claim(name) != Some(expected) {
+                    bail!(ErrorKind::AuthenticationError(format!("Asymmetric token {} does not match the request", name)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the token was issued recently, and that a token used to
+    /// change something has not been used before.
+    fn check_fresh(&self, claims: &HashMap<String, Value>, signed: &[u8], request: &authentication::AuthenticationRequest) -> Result<()> {
+        let iat = match claims.get("iat").and_then(|iat| iat.as_str()).and_then(|iat| DateTime::parse_from_rfc3339(iat).ok()) {
+            Some(iat) => iat.timestamp(),
+            None => bail!(ErrorKind::AuthenticationError("Asymmetric token has no valid iat claim".to_string())),
+        };
+
+        let now = tokens::now() as i64;
+        let max_age = self.max_age as i64;
+        if iat > now + CLOCK_SKEW_SECS || iat + max_age < now {
+            bail!(ErrorKind::AuthenticationError("Asymmetric token has expired".to_string()));
+        }
+
+        if request.mutation.is_some() {
+            let digest = hash(MessageDigest::sha384(), signed)?.to_vec();
+
+            let mut used = self.used.lock().unwrap();
+            used.retain(|_, used_iat| *used_iat + max_age >= now);
+
+            if used.insert(digest, iat).is_some() {
+                bail!(ErrorKind::AuthenticationError("Asymmetric token has already been used".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn verify(&self, token: &str, request: &authentication::AuthenticationRequest) -> Result<authentication::AuthenticationUserInfo> {
+        let parts = token[TOKEN_HEADER.len()..].split('.').collect::<Vec<_>>();
+        if parts.len() != 2 {
+            bail!(ErrorKind::AuthenticationError("Asymmetric token has no footer".to_string()));
+        }
+
+        let payload = decode(parts[0])?;
+        let footer_bytes = decode(parts[1])?;
+        if payload.len() <= SIGNATURE_LENGTH {
+            bail!(ErrorKind::AuthenticationError("Invalid asymmetric token".to_string()));
+        }
+        let (message, signature) = payload.split_at(payload.len() - SIGNATURE_LENGTH);
+
+        let footer: Footer = serde_json::from_slice(&footer_bytes).map_err(|_| {
+            ErrorKind::AuthenticationError("Invalid asymmetric token footer".to_string())
+        })?;
+
+        if footer.url != self.registry_url {
+            bail!(ErrorKind::AuthenticationError("Asymmetric token is for a different registry".to_string()));
+        }
+
+        let key = match self.keys.get(&footer.kip) {
+            Some(key) => key,
+            None => bail!(ErrorKind::AuthenticationError(format!("Unknown key {}", footer.kip))),
+        };
+
+        let signed = pae(&[&key.bytes[..], TOKEN_HEADER.as_bytes(), message, &footer_bytes, b""]);
+        self.verify_signature(key, &signed, signature)?;
+
+        let claims: HashMap<String, Value> = serde_json::from_slice(message).map_err(|_| {
+            ErrorKind::AuthenticationError("Invalid asymmetric token claims".to_string())
+        })?;
+
+        self.check_claims(&claims, request)?;
+        self.check_fresh(&claims, &signed, request)?;
+
+        let mut userinfo = key.userinfo.clone();
+        if request.mutation.is_none() {
+            // The token was not signed for any change, so it cannot be used
+            // for any action which needs a scope.
+            userinfo.scopes = Some(Vec::new());
+        }
+
+        Ok(userinfo)
+    }
+}
+
+impl authentication::Authentication for PasetoAuthentication {
+    fn authenticate(&self, token: &str) -> Result<authentication::AuthenticationUserInfo> {
+        self.authenticate_request(token, &authentication::AuthenticationRequest::default())
+    }
+
+    fn authenticate_request(&self, token: &str, request: &authentication::AuthenticationRequest) -> Result<authentication::AuthenticationUserInfo> {
+        self.verify(token, request)
+    }
+
+    fn recognises(&self, token: &str, _request: &authentication::AuthenticationRequest) -> bool {
+        token.starts_with(TOKEN_HEADER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::authentication::{Authentication, AuthenticationRequest};
+
+    use chrono::{TimeZone, Utc};
+    use openssl::bn::BigNum;
+    use openssl::ec::POINT_CONVERSION_COMPRESSED;
+    use openssl::sign::Signer;
+
+    const REGISTRY_URL: &'static str = "sparse+https://crates.example.com/index/";
+
+    // The order of the P-384 group
+    const ORDER: &'static str = "ffffffffffffffffffffffffffffffffffffffffffffffffc7634d81f4372ddf581a0db248b0a77aecec196accc52973";
+
+    fn encode(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    // Pads a big endian integer to the length of each half of a signature
+    fn padded(value: &[u8]) -> Vec<u8> {
+        let value = &value[value.len().saturating_sub(SIGNATURE_LENGTH / 2)..];
+        let mut padded = vec![0; SIGNATURE_LENGTH / 2 - value.len()];
+        padded.extend_from_slice(value);
+        padded
+    }
+
+    /// A user's key pair, which signs tokens as cargo would.
+    struct TestKey {
+        key: PKey,
+        bytes: Vec<u8>,
+        paserk: String,
+    }
+
+    impl TestKey {
+        fn new() -> TestKey {
+            let group = EcGroup::from_curve_name(nid::SECP384R1).unwrap();
+            let ec_key = EcKey::generate(&group).unwrap();
+            let mut ctx = BigNumContext::new().unwrap();
+            let bytes = ec_key.public_key().unwrap().to_bytes(&group, POINT_CONVERSION_COMPRESSED, &mut ctx).unwrap();
+
+            TestKey {
+                key: PKey::from_ec_key(ec_key).unwrap(),
+                paserk: format!("{}{}", PUBLIC_KEY_PREFIX, encode(&bytes)),
+                bytes: bytes,
+            }
+        }
+
+        fn token(&self, claims: &str) -> String {
+            let footer = format!(r#"{{"url": "{}", "kip": "{}"}}"#, REGISTRY_URL, key_id(&self.paserk).unwrap());
+
+            let mut signer = Signer::new(MessageDigest::sha384(), &self.key).unwrap();
+            signer.update(&pae(&[&self.bytes[..], TOKEN_HEADER.as_bytes(), claims.as_bytes(), footer.as_bytes(), b""])).unwrap();
+            let der = signer.finish().unwrap();
+
+            // Convert the DER sequence of r and s to the two values one after
+            // the other, the lengths are all short for P-384.
+            let mut payload = claims.as_bytes().to_vec();
+            let mut rest = &der[2..];
+            for _ in 0..2 {
+                let length = rest[1] as usize;
+                payload.extend(padded(&rest[2..2 + length]));
+                rest = &rest[2 + length..];
+            }
+
+            format!("{}{}.{}", TOKEN_HEADER, encode(&payload), encode(footer.as_bytes()))
+        }
+
+        fn authentication(&self) -> PasetoAuthentication {
+            let (id, key) = PasetoAuthentication::load_key(RegisteredKey {
+                key: self.paserk.clone(),
+                sub: "alice".to_string(),
+                name: None,
+                email: None,
+                groups: vec!["developers".to_string()],
+            }).unwrap();
+
+            let mut keys = HashMap::new();
+            keys.insert(id, key);
+
+            PasetoAuthentication {
+                keys: keys,
+                registry_url: REGISTRY_URL.to_string(),
+                max_age: DEFAULT_MAX_AGE_SECS,
+                used: Mutex::new(HashMap::new()),
+            }
+        }
+    }
+
+    fn iat(offset: i64) -> String {
+        Utc.timestamp(tokens::now() as i64 + offset, 0).to_rfc3339()
+    }
+
+    fn publish_claims(iat: &str) -> String {
+        format!(r#"{{"iat": "{}", "mutation": "publish", "name": "example", "vers": "0.1.0", "cksum": "abc123"}}"#, iat)
+    }
+
+    fn publish_request() -> AuthenticationRequest<'static> {
+        AuthenticationRequest {
+            mutation: Some("publish"),
+            name: Some("example"),
+            vers: Some("0.1.0"),
+            cksum: Some("abc123"),
+            ..AuthenticationRequest::default()
+        }
+    }
+
+    fn assert_rejected(result: Result<authentication::AuthenticationUserInfo>) {
+        match result {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::AuthenticationError(_) => {}
+                _ => panic!("unexpected error: {}", e),
+            },
+            Ok(_) => panic!("expected the token to be rejected"),
+        }
+    }
+
+    #[test]
+    fn pae_matches_the_spec() {
+        assert_eq!(pae(&[]), b"\x00\x00\x00\x00\x00\x00\x00\x00".to_vec());
+        assert_eq!(pae(&[&b""[..]]), b"\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec());
+        assert_eq!(pae(&[&b"test"[..]]), b"\x01\x00\x00\x00\x00\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00test".to_vec());
+    }
+
+    #[test]
+    fn signatures_are_der_encoded() {
+        // Leading zeros are dropped, and a zero is added to keep values with
+        // the top bit set positive.
+        assert_eq!(der_signature(&[0x00, 0x00, 0x01, 0x80, 0x01, 0x02]),
+                   vec![0x30, 0x09, 0x02, 0x01, 0x01, 0x02, 0x04, 0x00, 0x80, 0x01, 0x02]);
+        assert_eq!(der_signature(&[0x00, 0x00, 0x7f, 0x7f]),
+                   vec![0x30, 0x07, 0x02, 0x01, 0x00, 0x02, 0x02, 0x7f, 0x7f]);
+    }
+
+    #[test]
+    fn key_ids_are_paserk_ids() {
+        // The compressed generator point of P-384
+        assert_eq!(key_id("k3.public.A6qHyiK-iwU3jrHHHvMgrXRuHTtii6ebmFn3QeCCVCo4VQLyXb9VKWw6VF44cnYKtw").unwrap(),
+                   "k3.pid.6mfu-tuOAlvgfyirHYmFVDwVwkSxUB9vWJc2_cG_oCGG");
+    }
+
+    #[test]
+    fn signed_tokens_are_accepted() {
+        let key = TestKey::new();
+        let authentication = key.authentication();
+
+        let userinfo = authentication.authenticate_request(&key.token(&publish_claims(&iat(0))), &publish_request()).unwrap();
+        assert_eq!(userinfo.sub, "alice");
+        assert_eq!(userinfo.groups, vec!["developers".to_string()]);
+        assert_eq!(userinfo.scopes, None);
+
+        // Tokens which weren't signed for a change can only be used to read
+        let token = key.token(&format!(r#"{{"iat": "{}"}}"#, iat(0)));
+        assert_eq!(authentication.authenticate(&token).unwrap().scopes, Some(Vec::new()));
+    }
+
+    #[test]
+    fn tokens_from_other_keys_are_rejected() {
+        let key = TestKey::new();
+        let other = TestKey::new();
+
+        assert_rejected(key.authentication().authenticate_request(&other.token(&publish_claims(&iat(0))), &publish_request()));
+    }
+
+    #[test]
+    fn claims_must_match_the_request() {
+        let key = TestKey::new();
+        let authentication = key.authentication();
+        let token = key.token(&publish_claims(&iat(0)));
+
+        let other_version = AuthenticationRequest { vers: Some("0.2.0"), ..publish_request() };
+        assert_rejected(authentication.authenticate_request(&token, &other_version));
+
+        let other_crate = AuthenticationRequest { name: Some("other"), ..publish_request() };
+        assert_rejected(authentication.authenticate_request(&token, &other_crate));
+
+        let yank = AuthenticationRequest { mutation: Some("yank"), ..publish_request() };
+        assert_rejected(authentication.authenticate_request(&token, &yank));
+
+        assert_rejected(authentication.authenticate(&token));
+    }
+
+    #[test]
+    fn challenges_are_rejected() {
+        let key = TestKey::new();
+        let token = key.token(&format!(r#"{{"iat": "{}", "challenge": "made-up"}}"#, iat(0)));
+
+        assert_rejected(key.authentication().authenticate(&token));
+    }
+
+    #[test]
+    fn old_tokens_are_rejected() {
+        let key = TestKey::new();
+        let authentication = key.authentication();
+
+        let old = key.token(&publish_claims(&iat(-(DEFAULT_MAX_AGE_SECS as i64) - 1)));
+        assert_rejected(authentication.authenticate_request(&old, &publish_request()));
+
+        let future = key.token(&publish_claims(&iat(CLOCK_SKEW_SECS + 60)));
+        assert_rejected(authentication.authenticate_request(&future, &publish_request()));
+
+        let no_iat = key.token(r#"{"mutation": "publish", "name": "example", "vers": "0.1.0", "cksum": "abc123"}"#);
+        assert_rejected(authentication.authenticate_request(&no_iat, &publish_request()));
+    }
+
+    #[test]
+    fn tokens_can_not_be_replayed() {
+        let key = TestKey::new();
+        let authentication = key.authentication();
+        let token = key.token(&publish_claims(&iat(0)));
+
+        authentication.authenticate_request(&token, &publish_request()).unwrap();
+        assert_rejected(authentication.authenticate_request(&token, &publish_request()));
+    }
+
+    #[test]
+    fn altered_signatures_can_not_be_replayed() {
+        let key = TestKey::new();
+        let authentication = key.authentication();
+        let token = key.token(&publish_claims(&iat(0)));
+
+        // Changing s to n - s gives another valid signature for the same
+        // token, which must not get around the check.
+        let parts = token[TOKEN_HEADER.len()..].split('.').collect::<Vec<_>>();
+        let mut payload = decode(parts[0]).unwrap();
+        let s_start = payload.len() - SIGNATURE_LENGTH / 2;
+        let s = BigNum::from_slice(&payload[s_start..]).unwrap();
+        let mut flipped = BigNum::new().unwrap();
+        flipped.checked_sub(&BigNum::from_hex_str(ORDER).unwrap(), &s).unwrap();
+        payload.truncate(s_start);
+        payload.extend(padded(&flipped.to_vec()));
+        let malleated = format!("{}{}.{}", TOKEN_HEADER, encode(&payload), parts[1]);
+
+        assert!(malleated != token);
+        authentication.authenticate_request(&malleated, &publish_request()).unwrap();
+        assert_rejected(authentication.authenticate_request(&token, &publish_request()));
+    }
+
+    #[test]
+    fn read_tokens_can_be_reused() {
+        let key = TestKey::new();
+        let authentication = key.authentication();
+        let token = key.token(&format!(r#"{{"iat": "{}"}}"#, iat(0)));
+
+        authentication.authenticate(&token).unwrap();
+        authentication.authenticate(&token).unwrap();
+    }
+}
@@ -0,0 +1,375 @@
+use errors::*;
+use modules::*;
+
+use std;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rand::{OsRng, Rng};
+use serde_json;
+
+/// All tokens issued by Caesium start with this prefix, so that they can be
+/// told apart from tokens issued by other authentication modules.
+pub const TOKEN_PREFIX: &'static str = "caesium_";
+
+const TOKEN_BYTES: usize = 32;
+
+// The ID of a token is the start of its hash, which is enough to identify it
+// without revealing anything useful about the token.
+const TOKEN_ID_LENGTH: usize = 16;
+
+// Tokens record the user info, including groups, when they are issued, so
+// they are not allowed to live forever.
+pub const DEFAULT_MAX_LIFETIME_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Restrictions on what a token can be used for, and when it expires.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApiTokenOptions {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub endpoint_scopes: Option<Vec<authentication::TokenScope>>,
+    #[serde(default)]
+    pub crate_scopes: Option<Vec<String>>,
+    // Seconds since the Unix epoch
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    // Only the SHA256 of the token is stored
+    pub hash: String,
+    pub userinfo: authentication::AuthenticationUserInfo,
+    pub created: u64,
+    #[serde(default)]
+    pub options: ApiTokenOptions,
+}
+
+impl ApiToken {
+    pub fn id(&self) -> &str {
+        &self.hash[..TOKEN_ID_LENGTH]
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.options.expires_at.map_or(false, |expires_at| expires_at <= now())
+    }
+
+    /// Makes sure the token expires no later than `max_lifetime` seconds
+    /// after it was created.
+    fn limit_lifetime(&mut self, max_lifetime: u64) {
+        let latest = self.created + max_lifetime;
+        self.options.expires_at = Some(self.options.expires_at.map_or(latest, |expires_at| expires_at.min(latest)));
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    let mut sha = Sha256::new();
+    sha.input_str(token);
+    sha.result_str()
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/// API tokens issued by Caesium, stored in a JSON file.
+pub struct ApiTokenStore {
+    location: PathBuf,
+    tokens: Mutex<Vec<ApiToken>>,
+    max_lifetime: u64,
+}
+
+impl ApiTokenStore {
+    pub fn new(location: &str, max_lifetime: Option<u64>) -> ApiTokenStore {
+        let location = PathBuf::from(location);
+        let max_lifetime = max_lifetime.unwrap_or(DEFAULT_MAX_LIFETIME_SECS);
+
+        let mut tokens: Vec<ApiToken> = if std::fs::metadata(&location).is_ok() {
+            let mut contents = String::new();
+            File::open(&location).and_then(|mut f| f.read_to_string(&mut contents))
+                                 .expect("Failed to read token store");
+            serde_json::from_str(&contents).expect("Invalid token store")
+        } else {
+            Vec::new()
+        };
+
+        // Tokens issued before the limit was set, or lowered, are held to it
+        for api_token in &mut tokens {
+            api_token.limit_lifetime(max_lifetime);
+        }
+
+        ApiTokenStore {
+            location: location,
+            tokens: Mutex::new(tokens),
+            max_lifetime: max_lifetime,
+        }
+    }
+
+    fn save(&self, tokens: &[ApiToken]) -> Result<()> {
+        if let Some(parent) = self.location.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        File::create(&self.location)?.write_all(serde_json::to_string(tokens)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Issues a new token for the user, the token itself is only returned
+    /// here and cannot be recovered later.
+    pub fn create(&self, userinfo: &authentication::AuthenticationUserInfo, options: ApiTokenOptions) -> Result<String> {
+        let mut bytes = [0u8; TOKEN_BYTES];
+        OsRng::new().map_err(|e| format!("Failed to create random number generator: {}", e))?
+                    .fill_bytes(&mut bytes);
+
+        let token = format!("{}{}", TOKEN_PREFIX, bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+
+        let mut tokens = self.tokens.lock().unwrap();
+
+        // Short lived tokens would otherwise build up, so remove any tokens
+        // which have expired.
+        tokens.retain(|api_token| !api_token.is_expired());
+
+        let mut api_token = ApiToken {
+            hash: hash_token(&token),
+            userinfo: userinfo.clone(),
+            created: now(),
+            options: options,
+        };
+        api_token.limit_lifetime(self.max_lifetime);
+        tokens.push(api_token);
+        self.save(&tokens)?;
+
+        Ok(token)
+    }
+
+    pub fn find(&self, token: &str) -> Option<ApiToken> {
+        let hash = hash_token(token);
+
+        self.tokens.lock().unwrap().iter().find(|api_token| api_token.hash == hash).cloned()
+    }
+
+    /// Returns the tokens which were issued to a user.
+    pub fn list(&self, sub: &str) -> Vec<ApiToken> {
+        self.tokens.lock().unwrap().iter().filter(|api_token| api_token.userinfo.sub == sub).cloned().collect()
+    }
+
+    /// Revokes one of a user's tokens, users cannot revoke tokens which were
+    /// issued to someone else.
+    pub fn revoke(&self, sub: &str, id: &str) -> Result<()> {
+        let mut tokens = self.tokens.lock().unwrap();
+
+        let before = tokens.len();
+        tokens.retain(|api_token| !(api_token.userinfo.sub == sub && api_token.id() == id));
+        if tokens.len() == before {
+            bail!(ErrorKind::ValidationError(format!("no token with id '{}'", id)));
+        }
+
+        self.save(&tokens)
+    }
+}
+
+/// Authenticates tokens issued by Caesium.
+pub struct ApiTokenAuthentication {
+    pub store: Arc<ApiTokenStore>,
+}
+
+impl ApiTokenAuthentication {
+    pub fn new(store: Arc<ApiTokenStore>) -> ApiTokenAuthentication {
+        ApiTokenAuthentication {
+            store: store,
+        }
+    }
+}
+
+impl authentication::Authentication for ApiTokenAuthentication {
+    fn authenticate(&self, token: &str) -> Result<authentication::AuthenticationUserInfo> {
+        let api_token = match self.store.find(token) {
+            Some(api_token) => api_token,
+            None => bail!(ErrorKind::AuthenticationError("Unknown API token".to_string())),
+        };
+
+        if api_token.is_expired() {
+            bail!(ErrorKind::AuthenticationError("API token has expired".to_string()));
+        }
+
+        let mut userinfo = api_token.userinfo;
+        userinfo.scopes = api_token.options.endpoint_scopes;
+        userinfo.crate_scopes = api_token.options.crate_scopes;
+        Ok(userinfo)
+    }
+
+    fn recognises(&self, token: &str, _request: &authentication::AuthenticationRequest) -> bool {
+        token.starts_with(TOKEN_PREFIX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::authentication::{Authentication, AuthenticationUserInfo, TokenScope};
+    use test_helpers::test_dir;
+
+    fn store(name: &str, max_lifetime: Option<u64>) -> Arc<ApiTokenStore> {
+        let dir = test_dir(name);
+        Arc::new(ApiTokenStore::new(&dir.join("tokens.json").to_string_lossy(), max_lifetime))
+    }
+
+    fn userinfo(sub: &str) -> AuthenticationUserInfo {
+        AuthenticationUserInfo {
+            sub: sub.to_string(),
+            groups: vec!["developers".to_string()],
+            backend: Some("openid".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn options(endpoint_scopes: Option<Vec<TokenScope>>, crate_scopes: Option<Vec<&str>>) -> ApiTokenOptions {
+        ApiTokenOptions {
+            endpoint_scopes: endpoint_scopes,
+            crate_scopes: crate_scopes.map(|crate_scopes| crate_scopes.iter().map(|s| s.to_string()).collect()),
+            ..ApiTokenOptions::default()
+        }
+    }
+
+    fn assert_not_authorized(result: Result<()>) {
+        match result {
+            Err(ref e) => match *e.kind() {
+                ErrorKind::AuthorizationError(_) => {}
+                _ => panic!("unexpected error: {}", e),
+            },
+            Ok(()) => panic!("expected the token to be refused"),
+        }
+    }
+
+    #[test]
+    fn tokens_authenticate_as_their_user() {
+        let store = store("tokens-authenticate", None);
+        let token = store.create(&userinfo("openid:alice"), ApiTokenOptions::default()).unwrap();
+        let authentication = ApiTokenAuthentication::new(store.clone());
+
+        assert!(token.starts_with(TOKEN_PREFIX));
+        let authenticated = authentication.authenticate(&token).unwrap();
+        assert_eq!(authenticated.sub, "openid:alice");
+        assert_eq!(authenticated.groups, vec!["developers".to_string()]);
+
+        assert!(authentication.authenticate("caesium_unknown").is_err());
+
+        // Tokens are kept when the store is loaded again
+        let reloaded = ApiTokenStore::new(&store.location.to_string_lossy(), None);
+        assert!(reloaded.find(&token).is_some());
+    }
+
+    #[test]
+    fn endpoint_scopes_are_checked() {
+        let store = store("tokens-scopes", None);
+        let token = store.create(&userinfo("openid:alice"), options(Some(vec![TokenScope::PublishUpdate]), None)).unwrap();
+        let authenticated = ApiTokenAuthentication::new(store).authenticate(&token).unwrap();
+
+        assert!(authenticated.check_scope(TokenScope::PublishUpdate, "example").is_ok());
+        assert_not_authorized(authenticated.check_scope(TokenScope::PublishNew, "example"));
+        assert_not_authorized(authenticated.check_scope(TokenScope::Yank, "example"));
+        assert_not_authorized(authenticated.check_unrestricted());
+    }
+
+    #[test]
+    fn crate_scopes_match_globs() {
+        let store = store("tokens-crate-scopes", None);
+        let token = store.create(&userinfo("openid:alice"), options(None, Some(vec!["acme-*", "exact"]))).unwrap();
+        let authenticated = ApiTokenAuthentication::new(store).authenticate(&token).unwrap();
+
+        assert!(authenticated.check_scope(TokenScope::Yank, "acme-utils").is_ok());
+        assert!(authenticated.check_scope(TokenScope::Yank, "acme-").is_ok());
+        assert!(authenticated.check_scope(TokenScope::Yank, "exact").is_ok());
+        assert_not_authorized(authenticated.check_scope(TokenScope::Yank, "acme"));
+        assert_not_authorized(authenticated.check_scope(TokenScope::Yank, "exactly"));
+        assert_not_authorized(authenticated.check_scope(TokenScope::Yank, "other-acme-utils"));
+        assert_not_authorized(authenticated.check_unrestricted());
+    }
+
+    #[test]
+    fn unrestricted_tokens_allow_everything() {
+        let store = store("tokens-unrestricted", None);
+        let token = store.create(&userinfo("openid:alice"), ApiTokenOptions::default()).unwrap();
+        let authenticated = ApiTokenAuthentication::new(store).authenticate(&token).unwrap();
+
+        assert!(authenticated.check_scope(TokenScope::PublishNew, "anything").is_ok());
+        assert!(authenticated.check_scope(TokenScope::ChangeOwners, "anything").is_ok());
+        assert!(authenticated.check_unrestricted().is_ok());
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected_and_removed() {
+        let store = store("tokens-expiry", None);
+        let expired = store.create(&userinfo("openid:alice"), ApiTokenOptions {
+            expires_at: Some(now() - 1),
+            ..ApiTokenOptions::default()
+        }).unwrap();
+
+        let e = ApiTokenAuthentication::new(store.clone()).authenticate(&expired).unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthenticationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+
+        // Issuing another token clears out the expired one
+        store.create(&userinfo("openid:alice"), ApiTokenOptions::default()).unwrap();
+        assert!(store.find(&expired).is_none());
+        assert_eq!(store.list("openid:alice").len(), 1);
+    }
+
+    #[test]
+    fn lifetimes_are_limited() {
+        let store = store("tokens-lifetime", Some(60));
+
+        let default = store.create(&userinfo("openid:alice"), ApiTokenOptions::default()).unwrap();
+        let default = store.find(&default).unwrap();
+        assert_eq!(default.options.expires_at, Some(default.created + 60));
+
+        let long = store.create(&userinfo("openid:alice"), ApiTokenOptions {
+            expires_at: Some(now() + 3600),
+            ..ApiTokenOptions::default()
+        }).unwrap();
+        let long = store.find(&long).unwrap();
+        assert_eq!(long.options.expires_at, Some(long.created + 60));
+
+        let short_expiry = now() + 10;
+        let short = store.create(&userinfo("openid:alice"), ApiTokenOptions {
+            expires_at: Some(short_expiry),
+            ..ApiTokenOptions::default()
+        }).unwrap();
+        assert_eq!(store.find(&short).unwrap().options.expires_at, Some(short_expiry));
+
+        // Lowering the limit applies to tokens which were already issued
+        let reloaded = ApiTokenStore::new(&store.location.to_string_lossy(), Some(5));
+        for api_token in reloaded.list("openid:alice") {
+            assert!(api_token.options.expires_at.unwrap() <= api_token.created + 5);
+        }
+    }
+
+    #[test]
+    fn users_can_only_revoke_their_own_tokens() {
+        let store = store("tokens-revoke", None);
+        let alice = store.create(&userinfo("openid:alice"), ApiTokenOptions::default()).unwrap();
+        let bob = store.create(&userinfo("openid:bob"), ApiTokenOptions::default()).unwrap();
+        let alice_id = store.find(&alice).unwrap().id().to_string();
+
+        let e = store.revoke("openid:bob", &alice_id).unwrap_err();
+        match *e.kind() {
+            ErrorKind::ValidationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert!(store.find(&alice).is_some());
+
+        store.revoke("openid:alice", &alice_id).unwrap();
+        assert!(store.find(&alice).is_none());
+        assert!(store.find(&bob).is_some());
+        assert!(store.revoke("openid:alice", &alice_id).is_err());
+    }
+}
@@ -0,0 +1,407 @@
+use errors::*;
+use modules::*;
+use modules::authentication::jwt::{self, JsonWebKeys};
+use modules::authentication::tokens::{self, ApiTokenOptions, ApiTokenStore};
+
+use std;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use hyper;
+use serde_json;
+use serde_json::Value;
+
+const DEFAULT_TOKEN_LIFETIME_SECS: u64 = 1800;
+
+// Every CI pipeline's sub starts with this, so they can't be mistaken for users
+const SUB_PREFIX: &'static str = "trusted-publishing:";
+
+// The claims from the CI provider's token are kept in the user info under
+// these names, whatever the provider calls them.
+const REPOSITORY_CLAIM: &'static str = "repository";
+const REF_CLAIM: &'static str = "ref";
+const WORKFLOW_CLAIM: &'static str = "workflow";
+
+/// A CI pipeline which is trusted to publish a crate. The ref and workflow are
+/// optional, when they are not set any ref or workflow in the repository is
+/// trusted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedPublisher {
+    #[serde(default)]
+    pub id: u64,
+    #[serde(default)]
+    pub crate_name: String,
+    pub repository: String,
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    pub workflow: Option<String>,
+}
+
+impl TrustedPublisher {
+    fn matches(&self, claims: &HashMap<String, Value>) -> bool {
+        fn claim_matches(claims: &HashMap<String, Value>, claim: &str, expected: Option<&String>) -> bool {
+            match expected {
+                Some(expected) => claims.get(claim).and_then(|value| value.as_str()) == Some(expected.as_str()),
+                None => true,
+            }
+        }
+
+        claim_matches(claims, REPOSITORY_CLAIM, Some(&self.repository)) &&
+            claim_matches(claims, REF_CLAIM, self.git_ref.as_ref()) &&
+            claim_matches(claims, WORKFLOW_CLAIM, self.workflow.as_ref())
+    }
+}
+
+/// The trusted publishers for every crate, stored in a JSON file.
+pub struct TrustedPublisherStore {
+    location: PathBuf,
+    publishers: Mutex<Vec<TrustedPublisher>>,
+}
+
+impl TrustedPublisherStore {
+    pub fn new(location: &str) -> TrustedPublisherStore {
+        let location = PathBuf::from(location);
+
+        let publishers = if std::fs::metadata(&location).is_ok() {
+            let mut contents = String::new();
+            File::open(&location).and_then(|mut f| f.read_to_string(&mut contents))
+                                 .expect("Failed to read trusted publishers");
+            serde_json::from_str(&contents).expect("Invalid trusted publishers")
+        } else {
+            Vec::new()
+        };
+
+        TrustedPublisherStore {
+            location: location,
+            publishers: Mutex::new(publishers),
+        }
+    }
+
+    fn save(&self, publishers: &[TrustedPublisher]) -> Result<()> {
+        if let Some(parent) = self.location.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        File::create(&self.location)?.write_all(serde_json::to_string(publishers)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn list(&self, crate_name: &str) -> Vec<TrustedPublisher> {
+        self.publishers.lock().unwrap().iter().filter(|publisher| publisher.crate_name == crate_name).cloned().collect()
+    }
+
+    pub fn add(&self, crate_name: &str, mut publisher: TrustedPublisher) -> Result<TrustedPublisher> {
+        let mut publishers = self.publishers.lock().unwrap();
+
+        publisher.id = publishers.iter().map(|publisher| publisher.id).max().unwrap_or(0) + 1;
+        publisher.crate_name = crate_name.to_string();
+        publishers.push(publisher.clone());
+        self.save(&publishers)?;
+
+        Ok(publisher)
+    }
+
+    pub fn remove(&self, crate_name: &str, id: u64) -> Result<()> {
+        let mut publishers = self.publishers.lock().unwrap();
+
+        let before = publishers.len();
+        publishers.retain(|publisher| !(publisher.crate_name == crate_name && publisher.id == id));
+        if publishers.len() == before {
+            bail!(ErrorKind::ValidationError(format!("crate '{}' has no trusted publisher {}", crate_name, id)));
+        }
+
+        self.save(&publishers)
+    }
+
+    /// Returns the names of the crates which trust a CI pipeline with the
+    /// given claims.
+    fn trusted_crates(&self, claims: &HashMap<String, Value>) -> Vec<String> {
+        let mut crates = self.publishers.lock().unwrap()
+                                        .iter()
+                                        .filter(|publisher| publisher.matches(claims))
+                                        .map(|publisher| publisher.crate_name.clone())
+                                        .collect::<Vec<_>>();
+        crates.sort();
+        crates.dedup();
+        crates
+    }
+}
+
+/// Exchanges identity tokens issued to CI pipelines for short lived publish
+/// tokens, for crates which trust the pipeline.
+pub struct TrustedPublishing {
+    issuer: String,
+    audience: String,
+    token_lifetime: u64,
+
+    // The names of the claims in the CI provider's tokens
+    repository_claim: String,
+    ref_claim: String,
+    workflow_claim: String,
+
+    keys: JsonWebKeys,
+
+    publishers: TrustedPublisherStore,
+    tokens: Arc<ApiTokenStore>,
+}
+
+impl TrustedPublishing {
+    pub fn new(config: &::config::CaesiumTrustedPublishingConfig,
+               tokens: Arc<ApiTokenStore>) -> TrustedPublishing {
+
+        let jwks_url = match config.jwks_url {
+            Some(ref jwks_url) => jwks_url.clone(),
+            None => {
+                let discovery = format!("{}/.well-known/openid-configuration", config.issuer.trim_right_matches('/'));
+                let discovery = jwt::fetch_json(hyper::Uri::from_str(&discovery).expect("Invalid issuer URL"))
+                    .expect("Failed to get openid configuration for issuer");
+                discovery["jwks_uri"].as_str().expect("No jwks_uri in openid configuration").to_string()
+            }
+        };
+
+        TrustedPublishing {
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            token_lifetime: config.token_lifetime.unwrap_or(DEFAULT_TOKEN_LIFETIME_SECS),
+            repository_claim: config.repository_claim.clone().unwrap_or(REPOSITORY_CLAIM.to_string()),
+            ref_claim: config.ref_claim.clone().unwrap_or(REF_CLAIM.to_string()),
+            workflow_claim: config.workflow_claim.clone().unwrap_or(WORKFLOW_CLAIM.to_string()),
+            keys: JsonWebKeys::new(hyper::Uri::from_str(&jwks_url).expect("Invalid jwks_uri")),
+            publishers: TrustedPublisherStore::new(&config.location),
+            tokens: tokens,
+        }
+    }
+
+    pub fn publishers(&self) -> &TrustedPublisherStore {
+        &self.publishers
+    }
+
+    /// Verifies the JWT and returns its claims.
+    fn verify(&self, jwt: &str) -> Result<serde_json::Map<String, Value>> {
+        let claims = self.keys.verify(jwt)?;
+        jwt::check_claims(&claims, &self.issuer, Some(self.audience.as_str()))?;
+
+        Ok(claims)
+    }
+
+    /// Exchanges a CI identity token for a short lived token which can only
+    /// publish new versions of the crates that trust the pipeline.
+    pub fn exchange(&self, jwt: &str) -> Result<String> {
+        let userinfo = authentication::Authentication::authenticate(self, jwt)?;
+
+        let crates = self.publishers.trusted_crates(&userinfo.claims);
+        if crates.is_empty() {
+            bail!(ErrorKind::AuthorizationError("No crates trust this CI pipeline".to_string()));
+        }
+
+        let options = ApiTokenOptions {
+            name: Some(format!("Trusted publishing for {}", crates.join(", "))),
+            endpoint_scopes: Some(vec![authentication::TokenScope::PublishUpdate]),
+            crate_scopes: Some(crates),
+            expires_at: Some(tokens::now() + self.token_lifetime),
+        };
+
+        self.tokens.create(&userinfo, options)
+    }
+
+    /// Checks whether the user is a CI pipeline which the crate trusts, this is
+    /// checked on each publish so removing the trust takes effect straight
+    /// away. Only tokens which were exchanged here are trusted, as other
+    /// backends can keep claims with the same names.
+    pub fn is_trusted(&self, crate_name: &str, userinfo: &authentication::AuthenticationUserInfo) -> bool {
+        if userinfo.backend.as_ref().map(|backend| backend.as_str()) != Some("tokens") ||
+            !userinfo.sub.starts_with(SUB_PREFIX) {
+            return false;
+        }
+
+        self.publishers.list(crate_name).iter().any(|publisher| publisher.matches(&userinfo.claims))
+    }
+}
+
+impl authentication::Authentication for TrustedPublishing {
+    // Authenticate using an identity token issued to a CI pipeline.
+    fn authenticate(&self, token: &str) -> Result<authentication::AuthenticationUserInfo> {
+        let claims = self.verify(token)?;
+
+        let mut kept_claims = HashMap::new();
+        for &(claim, name) in &[(&self.repository_claim, REPOSITORY_CLAIM),
+                                (&self.ref_claim, REF_CLAIM),
+                                (&self.workflow_claim, WORKFLOW_CLAIM)] {
+            if let Some(value) = claims.get(claim.as_str()) {
+                kept_claims.insert(name.to_string(), value.clone());
+            }
+        }
+
+        let repository = match kept_claims.get(REPOSITORY_CLAIM).and_then(|repository| repository.as_str()) {
+            Some(repository) => repository.to_string(),
+            None => bail!(ErrorKind::AuthenticationError("JWT has no repository claim".to_string())),
+        };
+
+        Ok(authentication::AuthenticationUserInfo {
+            sub: format!("{}{}", SUB_PREFIX, repository),
+            name: Some(format!("{} (trusted publishing)", repository)),
+            claims: kept_claims,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::authentication::Authentication;
+    use modules::authentication::tokens::ApiTokenAuthentication;
+    use test_helpers::test_dir;
+    use test_server::{TestResponse, TestServer};
+
+    use base64;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+    use toml;
+
+    const ISSUER: &'static str = "https://ci.example.com";
+    const AUDIENCE: &'static str = "https://caesium.example.com";
+
+    fn encode(bytes: &[u8]) -> String {
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// A CI provider with its own key pair, which serves its public key as a
+    /// JWKS and signs identity tokens with the private key.
+    struct TestIssuer {
+        key: PKey,
+        _jwks: TestServer,
+        trusted_publishing: TrustedPublishing,
+        tokens: ApiTokenAuthentication,
+    }
+
+    impl TestIssuer {
+        fn new(name: &str) -> TestIssuer {
+            let rsa = Rsa::generate(2048).unwrap();
+            let jwks = format!(r#"{{"keys": [{{"kty": "RSA", "kid": "test", "n": "{}", "e": "{}"}}]}}"#,
+                               encode(&rsa.n().unwrap().to_vec()),
+                               encode(&rsa.e().unwrap().to_vec()));
+            let server = TestServer::start(move |_| TestResponse::json(&jwks));
+
+            let dir = test_dir(name);
+
+            let config: ::config::CaesiumTrustedPublishingConfig = toml::from_str(&format!(
+                "issuer = {:?}\naudience = {:?}\njwks_url = {:?}\nlocation = {:?}\n",
+                ISSUER, AUDIENCE, server.url, dir.join("publishers.json").to_string_lossy())).unwrap();
+            let tokens = Arc::new(ApiTokenStore::new(&dir.join("tokens.json").to_string_lossy(), None));
+
+            TestIssuer {
+                key: PKey::from_rsa(rsa).unwrap(),
+                _jwks: server,
+                trusted_publishing: TrustedPublishing::new(&config, tokens.clone()),
+                tokens: ApiTokenAuthentication::new(tokens),
+            }
+        }
+
+        fn sign(&self, claims: &str) -> String {
+            let header = encode(br#"{"alg": "RS256", "typ": "JWT", "kid": "test"}"#);
+            let payload = encode(claims.as_bytes());
+            let signing_input = format!("{}.{}", header, payload);
+
+            let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+            signer.update(signing_input.as_bytes()).unwrap();
+            format!("{}.{}", signing_input, encode(&signer.finish().unwrap()))
+        }
+
+        fn identity_token(&self, repository: &str, git_ref: &str, expires_in: i64) -> String {
+            self.sign(&format!(r#"{{"iss": "{}", "aud": "{}", "sub": "repo:{}:ref:{}", "repository": "{}",
+                                   "ref": "{}", "workflow": "release", "exp": {}}}"#,
+                               ISSUER, AUDIENCE, repository, git_ref, repository, git_ref,
+                               tokens::now() as i64 + expires_in))
+        }
+
+        /// Exchanges the identity token, then authenticates with the
+        /// resulting token as a publish would.
+        fn exchange(&self, jwt: &str) -> Result<authentication::AuthenticationUserInfo> {
+            let token = self.trusted_publishing.exchange(jwt)?;
+            let mut userinfo = self.tokens.authenticate(&token)?;
+            userinfo.backend = Some("tokens".to_string());
+            Ok(userinfo)
+        }
+    }
+
+    fn trust(issuer: &TestIssuer, crate_name: &str) {
+        issuer.trusted_publishing.publishers().add(crate_name, TrustedPublisher {
+            id: 0,
+            crate_name: String::new(),
+            repository: "acme/widgets".to_string(),
+            git_ref: Some("refs/heads/main".to_string()),
+            workflow: None,
+        }).unwrap();
+    }
+
+    #[test]
+    fn accepts_trusted_pipeline() {
+        let issuer = TestIssuer::new("trusted-publishing-accept");
+        trust(&issuer, "widgets");
+
+        let userinfo = issuer.exchange(&issuer.identity_token("acme/widgets", "refs/heads/main", 300)).unwrap();
+        assert_eq!(userinfo.sub, "trusted-publishing:acme/widgets");
+        assert_eq!(userinfo.crate_scopes, Some(vec!["widgets".to_string()]));
+        assert!(issuer.trusted_publishing.is_trusted("widgets", &userinfo));
+        assert!(!issuer.trusted_publishing.is_trusted("gadgets", &userinfo));
+    }
+
+    #[test]
+    fn rejects_other_repository() {
+        let issuer = TestIssuer::new("trusted-publishing-repo");
+        trust(&issuer, "widgets");
+
+        let e = issuer.exchange(&issuer.identity_token("mallory/widgets", "refs/heads/main", 300)).unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthorizationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn rejects_other_ref() {
+        let issuer = TestIssuer::new("trusted-publishing-ref");
+        trust(&issuer, "widgets");
+
+        let e = issuer.exchange(&issuer.identity_token("acme/widgets", "refs/heads/feature", 300)).unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthorizationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let issuer = TestIssuer::new("trusted-publishing-expired");
+        trust(&issuer, "widgets");
+
+        let e = issuer.exchange(&issuer.identity_token("acme/widgets", "refs/heads/main", -60)).unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthenticationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn only_trusts_exchanged_tokens() {
+        let issuer = TestIssuer::new("trusted-publishing-backend");
+        trust(&issuer, "widgets");
+
+        // The same claims from a different backend are not enough
+        let mut userinfo = issuer.exchange(&issuer.identity_token("acme/widgets", "refs/heads/main", 300)).unwrap();
+        userinfo.backend = Some("openid".to_string());
+        assert!(!issuer.trusted_publishing.is_trusted("widgets", &userinfo));
+
+        userinfo.backend = Some("tokens".to_string());
+        userinfo.sub = "acme/widgets".to_string();
+        assert!(!issuer.trusted_publishing.is_trusted("widgets", &userinfo));
+    }
+}
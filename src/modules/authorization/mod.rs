@@ -0,0 +1,59 @@
+use errors::*;
+use modules::authentication::AuthenticationUserInfo;
+
+#[derive(Debug, Serialize)]
+pub struct AuthorizationDependency {
+    pub name: String,
+    pub version_req: String,
+    pub registry: Option<String>,
+    pub kind: String,
+}
+
+/// Everything known about a request that needs to be authorized.
+#[derive(Debug, Serialize)]
+pub struct AuthorizationRequest<'a> {
+    pub action: &'a str,
+    pub user: Option<&'a AuthenticationUserInfo>,
+    pub name: &'a str,
+    pub version: Option<&'a str>,
+    pub license: Option<&'a str>,
+    pub dependencies: Vec<AuthorizationDependency>,
+}
+
+impl<'a> AuthorizationRequest<'a> {
+    pub fn publish(manifest: &'a ::registry::CargoManifest, user: Option<&'a AuthenticationUserInfo>) -> AuthorizationRequest<'a> {
+        AuthorizationRequest {
+            action: "publish",
+            user: user,
+            name: &manifest.name,
+            version: Some(&manifest.vers),
+            license: manifest.license.as_ref().map(|license| license.as_str()),
+            dependencies: manifest.deps.iter().map(|dep| {
+                AuthorizationDependency {
+                    name: dep.name.clone(),
+                    version_req: dep.version_req.clone(),
+                    registry: dep.registry.clone(),
+                    kind: dep.kind.clone(),
+                }
+            }).collect(),
+        }
+    }
+
+    pub fn crate_action(action: &'a str, name: &'a str, user: Option<&'a AuthenticationUserInfo>) -> AuthorizationRequest<'a> {
+        AuthorizationRequest {
+            action: action,
+            user: user,
+            name: name,
+            version: None,
+            license: None,
+            dependencies: Vec::new(),
+        }
+    }
+}
+
+pub trait Authorization {
+    // Returns an error if the request is not allowed.
+    fn authorize(&self, request: &AuthorizationRequest) -> Result<()>;
+}
+
+pub mod opa;
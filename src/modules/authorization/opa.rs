@@ -0,0 +1,170 @@
+use errors::*;
+use modules::*;
+
+use tokio_core;
+use tokio_core::reactor::Timeout;
+use hyper;
+use hyper::Client;
+use hyper::header::ContentType;
+use hyper_tls::HttpsConnector;
+use futures::Future;
+use futures::future::Either;
+use futures::Stream;
+use serde_json;
+use serde_json::Value;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Serialize)]
+struct PolicyInput<'a, 'b: 'a> {
+    input: &'a authorization::AuthorizationRequest<'b>,
+}
+
+/// Authorization using an external policy service, such as Open Policy Agent.
+/// The request is POSTed as `{"input": ...}` and the service must respond with
+/// either `{"result": true}` or `{"result": {"allow": true, "reason": "..."}}`.
+pub struct OpaAuthorization {
+    pub url: hyper::Uri,
+    pub timeout: Duration,
+}
+
+impl OpaAuthorization {
+    pub fn new(url: &str, timeout: Option<u64>) -> OpaAuthorization {
+        OpaAuthorization {
+            url: hyper::Uri::from_str(url).expect("Invalid policy service URL"),
+            timeout: Duration::from_secs(timeout.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+        }
+    }
+
+    fn query(&self, request: &authorization::AuthorizationRequest) -> ::std::result::Result<Value, String> {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+
+        let client = Client::configure()
+            .connector(HttpsConnector::new(4, &handle).map_err(|e| e.to_string())?)
+            .build(&handle);
+
+        let body = serde_json::to_string(&PolicyInput { input: request }).map_err(|e| e.to_string())?;
+
+        let mut http_request = hyper::Request::new(hyper::Method::Post, self.url.clone());
+        http_request.headers_mut().set(ContentType::json());
+        http_request.set_body(body);
+
+        let work = client.request(http_request)
+            .map_err(|e| e.to_string())
+            .and_then(|res| {
+                let status = res.status();
+                res.body().concat2().map_err(|e| e.to_string()).and_then(move |body| {
+                    if status != hyper::StatusCode::Ok {
+                        return Err(format!("invalid status code: {}", status));
+                    }
+                    serde_json::from_slice::<Value>(&body).map_err(|e| e.to_string())
+                })
+            });
+
+        let timeout = Timeout::new(self.timeout, &handle).map_err(|e| e.to_string())?;
+
+        let work = work.select2(timeout).then(|res| {
+            match res {
+                Ok(Either::A((response, _))) => Ok(response),
+                Ok(Either::B(_)) => Err("timed out".to_string()),
+                Err(Either::A((e, _))) => Err(e),
+                Err(Either::B((e, _))) => Err(e.to_string()),
+            }
+        });
+
+        core.run(work)
+    }
+}
+
+impl authorization::Authorization for OpaAuthorization {
+    fn authorize(&self, request: &authorization::AuthorizationRequest) -> Result<()> {
+
+        // Fail closed, if we cannot get a decision then nothing is allowed.
+        let response = self.query(request).map_err(|e| {
+            ErrorKind::PolicyServiceError(format!("failed to query policy service: {}", e))
+        })?;
+
+        let (allow, reason) = match response["result"] {
+            Value::Bool(allow) => (allow, None),
+            ref result @ Value::Object(_) => {
+                (result["allow"].as_bool().unwrap_or(false),
+                 result["reason"].as_str().map(|reason| reason.to_string()))
+            }
+            _ => bail!(ErrorKind::PolicyServiceError("invalid response from policy service".to_string())),
+        };
+
+        if !allow {
+            bail!(ErrorKind::AuthorizationError(
+                reason.unwrap_or(format!("{} of crate '{}' denied by policy", request.action, request.name))));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::authorization::Authorization;
+    use test_server::{self, TestResponse, TestServer};
+
+    fn yank_request() -> authorization::AuthorizationRequest<'static> {
+        authorization::AuthorizationRequest::crate_action("yank", "my-crate", None)
+    }
+
+    #[test]
+    fn allows_when_policy_allows() {
+        let server = TestServer::start(|_| TestResponse::json(r#"{"result": true}"#));
+        let opa = OpaAuthorization::new(&server.url, None);
+
+        opa.authorize(&yank_request()).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        let body: Value = serde_json::from_slice(&requests[0].body).unwrap();
+        assert_eq!(body["input"]["action"], "yank");
+        assert_eq!(body["input"]["name"], "my-crate");
+    }
+
+    #[test]
+    fn denies_with_reason_from_policy() {
+        let server = TestServer::start(|_| {
+            TestResponse::json(r#"{"result": {"allow": false, "reason": "crates are frozen"}}"#)
+        });
+        let opa = OpaAuthorization::new(&server.url, None);
+
+        let e = opa.authorize(&yank_request()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::AuthorizationError(ref reason) => assert_eq!(reason, "crates are frozen"),
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn fails_closed_when_unreachable() {
+        let opa = OpaAuthorization::new(&test_server::unreachable_url(), Some(1));
+
+        let e = opa.authorize(&yank_request()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::PolicyServiceError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn fails_closed_on_invalid_response() {
+        let server = TestServer::start(|_| TestResponse::new(500, "oops"));
+        let opa = OpaAuthorization::new(&server.url, None);
+
+        let e = opa.authorize(&yank_request()).unwrap_err();
+        match *e.kind() {
+            ErrorKind::PolicyServiceError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+}
@@ -1,3 +1,4 @@
 
 pub mod authentication;
+pub mod authorization;
 pub mod storage;
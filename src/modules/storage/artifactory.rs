@@ -1,4 +1,3 @@
-
 use errors::*;
 use modules::*;
 
@@ -7,60 +6,372 @@ use std::str::FromStr;
 use tokio_core;
 use hyper;
 use hyper::Client;
-use futures::Future;
+use hyper::header::{Authorization, Basic, Bearer, ContentLength, ContentType};
+use hyper_tls::HttpsConnector;
+use futures::{Future, Stream};
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
 use url;
+use serde_json;
 
 header! { (XJFrogArtApi, "X-JFrog-Art-Api") => [String] }
+header! { (XChecksumSha1, "X-Checksum-Sha1") => [String] }
+header! { (XChecksumSha256, "X-Checksum-Sha256") => [String] }
+
+/// The parts of the file info returned by the storage API which are used.
+#[derive(Deserialize)]
+struct FileInfo {
+    checksums: FileChecksums,
+}
+
+#[derive(Deserialize)]
+struct FileChecksums {
+    sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SignedUrlRequest {
+    repo_path: String,
+    valid_for_secs: u64,
+}
+
+/// How requests to Artifactory are authenticated.
+enum ArtifactoryCredentials {
+    ApiKey(String),
+    AccessToken(String),
+    Basic(String, String),
+}
+
+/// Where the base URL is within Artifactory, which is needed to use the APIs
+/// that aren't under the repository.
+struct ArtifactoryRepository {
+    // The URL of Artifactory itself
+    api_url: url::Url,
+
+    // The path of the base URL within Artifactory, starting with the
+    // repository
+    repo_path: String,
+}
+
+/// Encodes a matrix parameter value, Artifactory decodes these the same as
+/// paths so spaces can't be sent as `+`.
+fn matrix_value(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>().replace('+', "%20")
+}
 
 pub struct ArtifactoryCrateStorage {
     base_url: url::Url,
-    api_key: String,
+    credentials: ArtifactoryCredentials,
+    repository: Option<ArtifactoryRepository>,
+
+    // How long signed download URLs are valid for, if downloads are
+    // redirected
+    presign_expiry: Option<u64>,
 }
 
 impl ArtifactoryCrateStorage {
-    pub fn new(url: &String, api_key: &String) -> ArtifactoryCrateStorage {
+    pub fn new(config: &::config::CaesiumArtifactoryStorageConfig) -> ArtifactoryCrateStorage {
 
         // May be worth ensuring that we can authenticate using the provided
         // credentials.
 
+        let base_url = url::Url::parse(&config.base_url).expect("Invalid Artifactory URL in config");
+
+        let credentials = match (&config.api_key, &config.access_token, &config.username) {
+            (&Some(ref api_key), &None, &None) => ArtifactoryCredentials::ApiKey(api_key.clone()),
+            (&None, &Some(ref access_token), &None) => ArtifactoryCredentials::AccessToken(access_token.clone()),
+            (&None, &None, &Some(ref username)) => {
+                let password = config.password.clone().expect("Artifactory username needs a password");
+                ArtifactoryCredentials::Basic(username.clone(), password)
+            }
+            _ => panic!("Artifactory needs one of api_key, access_token or username"),
+        };
+
+        let repository = config.repository.as_ref().map(|repository| {
+            // The base URL is the Artifactory URL followed by the repository,
+            // which is where the API lives.
+            let segments = base_url.path_segments().map(|segments| segments.collect::<Vec<_>>()).unwrap_or_default();
+            let position = segments.iter().position(|segment| *segment == repository.as_str())
+                                   .expect("Artifactory repository is not part of the base URL");
+
+            let mut api_url = base_url.clone();
+            api_url.path_segments_mut().unwrap()
+                                       .clear()
+                                       .extend(&segments[..position]);
+
+            ArtifactoryRepository {
+                api_url: api_url,
+                repo_path: segments[position..].join("/"),
+            }
+        });
+
+        if config.presign_expiry.is_some() && repository.is_none() {
+            panic!("Artifactory presign_expiry needs the repository to be configured");
+        }
+
         ArtifactoryCrateStorage {
-            base_url: url::Url::parse(url).expect("Invalid Artifactory URL in config"),
-            api_key: api_key.clone(),
+            base_url: base_url,
+            credentials: credentials,
+            repository: repository,
+            presign_expiry: config.presign_expiry,
         }
     }
+
+    fn crate_url(&self, name: &str, vers: &str) -> url::Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut().unwrap()
+                               .pop_if_empty()
+                               .push(name)
+                               .push(vers)
+                               .push("download");
+        url
+    }
+
+    /// The URL of one of the Artifactory APIs for a crate file, if the
+    /// repository is known.
+    fn api_url(&self, api: &[&str], name: &str, vers: &str) -> Option<url::Url> {
+        self.repository.as_ref().map(|repository| {
+            let mut url = repository.api_url.clone();
+            url.path_segments_mut().unwrap()
+                                   .pop_if_empty()
+                                   .push("api")
+                                   .extend(api)
+                                   .extend(repository.repo_path.split('/'))
+                                   .push(name)
+                                   .push(vers)
+                                   .push("download");
+            url
+        })
+    }
+
+    /// Fetches the SHA-256 checksum that Artifactory has for a crate file,
+    /// if the repository is known.
+    fn checksum(&self, name: &str, vers: &str) -> Result<Option<String>> {
+        let url = match self.api_url(&["storage"], name, vers) {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+
+        let (status, body) = self.send(self.request(hyper::Method::Get, url.as_str())?)?;
+        if status != hyper::StatusCode::Ok {
+            bail!("Artifactory returned {} for the file info of {} {}", status, name, vers);
+        }
+
+        let info: FileInfo = serde_json::from_slice(&body)?;
+        Ok(info.checksums.sha256)
+    }
+
+    fn request(&self, method: hyper::Method, url: &str) -> Result<hyper::Request> {
+        let mut request = hyper::Request::new(method, hyper::Uri::from_str(url)?);
+
+        match self.credentials {
+            ArtifactoryCredentials::ApiKey(ref api_key) => {
+                request.headers_mut().set(XJFrogArtApi(api_key.clone()));
+            }
+            ArtifactoryCredentials::AccessToken(ref access_token) => {
+                request.headers_mut().set(Authorization(Bearer { token: access_token.clone() }));
+            }
+            ArtifactoryCredentials::Basic(ref username, ref password) => {
+                request.headers_mut().set(Authorization(Basic {
+                    username: username.clone(),
+                    password: Some(password.clone()),
+                }));
+            }
+        }
+
+        Ok(request)
+    }
+
+    fn send(&self, request: hyper::Request) -> Result<(hyper::StatusCode, Vec<u8>)> {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+
+        let client = Client::configure()
+            .connector(HttpsConnector::new(4, &handle).expect("Failed to setup HTTPS"))
+            .build(&handle);
+
+        let work = client.request(request).and_then(|res| {
+            let status = res.status();
+            res.body().concat2().map(move |body| (status, body.to_vec()))
+        });
+
+        Ok(core.run(work)?)
+    }
 }
 
 /// Artifactory based storage
 impl storage::CrateStorage for ArtifactoryCrateStorage {
-    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8]) -> Result<()> {
+    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8], publisher: Option<&str>) -> Result<()> {
 
-        let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
+        // The properties are deployed along with the crate, as matrix
+        // parameters
+        let mut properties = vec![("crate.name", manifest.name.as_str()), ("crate.version", manifest.vers.as_str())];
+        if let Some(publisher) = publisher {
+            properties.push(("publisher", publisher));
+        }
+        if let Some(ref license) = manifest.license {
+            properties.push(("license", license.as_str()));
+        }
+        let matrix = properties.iter()
+                               .map(|&(name, value)| format!(";{}={}", name, matrix_value(value)))
+                               .collect::<String>();
 
-        let client = Client::new(&handle);
-        let body = tar.to_vec();
+        let url = format!("{}{}", self.crate_url(&manifest.name, &manifest.vers), matrix);
+        let mut request = self.request(hyper::Method::Put, &url)?;
 
-        let mut url = self.base_url.clone();
-        url.path_segments_mut().unwrap()
-                               .push(&manifest.name)
-                               .push(&manifest.vers)
-                               .push("download");
-        let hyper_uri = hyper::Uri::from_str(url.as_str())?;
+        // Artifactory checks these against what it receives
+        let mut sha1 = Sha1::new();
+        sha1.input(tar);
+        let mut sha256 = Sha256::new();
+        sha256.input(tar);
+        request.headers_mut().set(XChecksumSha1(sha1.result_str()));
+        request.headers_mut().set(XChecksumSha256(sha256.result_str()));
 
-        let mut request = hyper::Request::new(hyper::Method::Put, hyper_uri);
-        request.set_body(body);
-        request.headers_mut().set(XJFrogArtApi(self.api_key.clone()));
+        request.headers_mut().set(ContentLength(tar.len() as u64));
+        request.set_body(tar.to_vec());
 
-        let work = client.request(request).and_then(|res| {
-            if res.status() != hyper::StatusCode::Created {
-                panic!("Received invalid error code: {}", res.status());
+        let (status, body) = self.send(request)?;
+        if status != hyper::StatusCode::Created && status != hyper::StatusCode::Ok {
+            bail!("Artifactory returned {} uploading {} {}: {}", status, manifest.name, manifest.vers, String::from_utf8_lossy(&body));
+        }
+
+        Ok(())
+    }
+
+    fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+        // The checksum Artifactory verified on upload is checked against
+        // what is downloaded
+        let checksum = self.checksum(name, vers)?;
+
+        let request = self.request(hyper::Method::Get, self.crate_url(name, vers).as_str())?;
+
+        let (status, body) = self.send(request)?;
+        if status != hyper::StatusCode::Ok {
+            bail!("Artifactory returned {} for {} {}", status, name, vers);
+        }
+
+        if let Some(checksum) = checksum {
+            let mut sha256 = Sha256::new();
+            sha256.input(&body);
+            if !sha256.result_str().eq_ignore_ascii_case(&checksum) {
+                bail!("Artifactory returned a crate for {} {} which doesn't match its checksum", name, vers);
             }
+        }
 
-            Ok(())
-        });
+        Ok(body)
+    }
+
+    fn delete(&self, name: &str, vers: &str) -> Result<()> {
+        let request = self.request(hyper::Method::Delete, self.crate_url(name, vers).as_str())?;
 
-        core.run(work)?;
+        // A crate which is already gone is as good as deleted
+        let (status, _) = self.send(request)?;
+        if status != hyper::StatusCode::NoContent && status != hyper::StatusCode::Ok && status != hyper::StatusCode::NotFound {
+            bail!("Artifactory returned {} deleting {} {}", status, name, vers);
+        }
 
         Ok(())
     }
+
+    fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+        // The storage API returns the file info rather than the file, when
+        // the repository isn't known the file's headers are used instead
+        let request = match self.api_url(&["storage"], name, vers) {
+            Some(url) => self.request(hyper::Method::Get, url.as_str())?,
+            None => self.request(hyper::Method::Head, self.crate_url(name, vers).as_str())?,
+        };
+
+        match self.send(request)?.0 {
+            hyper::StatusCode::Ok => Ok(true),
+            hyper::StatusCode::NotFound => Ok(false),
+            status => bail!("Artifactory returned {} checking {} {}", status, name, vers),
+        }
+    }
+
+    fn download_url(&self, name: &str, vers: &str) -> Result<Option<String>> {
+        let (repository, expiry) = match (&self.repository, self.presign_expiry) {
+            (&Some(ref repository), Some(expiry)) => (repository, expiry),
+            _ => return Ok(None),
+        };
+
+        let mut url = repository.api_url.clone();
+        url.path_segments_mut().unwrap()
+                               .pop_if_empty()
+                               .extend(&["api", "signed", "url"]);
+
+        let body = SignedUrlRequest {
+            repo_path: format!("{}/{}/{}/download", repository.repo_path, name, vers),
+            valid_for_secs: expiry,
+        };
+
+        let mut request = self.request(hyper::Method::Post, url.as_str())?;
+        request.headers_mut().set(ContentType::json());
+        request.set_body(serde_json::to_vec(&body)?);
+
+        let (status, body) = self.send(request)?;
+        if status != hyper::StatusCode::Ok {
+            bail!("Artifactory returned {} signing URL for {} {}", status, name, vers);
+        }
+
+        Ok(Some(String::from_utf8_lossy(&body).trim().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::storage::CrateStorage;
+    use test_server::{TestResponse, TestServer};
+
+    fn storage(url: &str) -> ArtifactoryCrateStorage {
+        let config = format!("base_url = \"{}/artifactory/caesium\"\napi_key = \"key\"\nrepository = \"caesium\"", url);
+        ArtifactoryCrateStorage::new(&::toml::from_str(&config).unwrap())
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        let mut sha256 = Sha256::new();
+        sha256.input(data);
+        sha256.result_str()
+    }
+
+    #[test]
+    fn downloads_are_checked_against_the_storage_api() {
+        let server = TestServer::start(|request| {
+            match request.path.as_str() {
+                "/artifactory/api/storage/caesium/good/1.0.0/download" |
+                "/artifactory/api/storage/caesium/bad/1.0.0/download" => {
+                    TestResponse::json(&format!(r#"{{"checksums": {{"sha256": "{}"}}}}"#, sha256(b"crate file")))
+                }
+                "/artifactory/caesium/good/1.0.0/download" => TestResponse::new(200, "crate file"),
+                "/artifactory/caesium/bad/1.0.0/download" => TestResponse::new(200, "corrupt file"),
+                _ => TestResponse::new(404, ""),
+            }
+        });
+        let storage = storage(&server.url);
+
+        assert_eq!(storage.download("good", "1.0.0").unwrap(), b"crate file".to_vec());
+        assert!(storage.download("bad", "1.0.0").is_err());
+        assert!(storage.download("missing", "1.0.0").is_err());
+        assert!(server.requests().iter().all(|request| request.header("X-JFrog-Art-Api") == Some("key")));
+    }
+
+    #[test]
+    fn checks_and_deletes_crates() {
+        let server = TestServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/artifactory/api/storage/caesium/present/1.0.0/download") => TestResponse::json(r#"{"checksums": {}}"#),
+                ("DELETE", "/artifactory/caesium/present/1.0.0/download") => TestResponse::new(204, ""),
+                ("DELETE", "/artifactory/caesium/locked/1.0.0/download") => TestResponse::new(403, ""),
+                _ => TestResponse::new(404, ""),
+            }
+        });
+        let storage = storage(&server.url);
+
+        assert!(storage.exists("present", "1.0.0").unwrap());
+        assert!(!storage.exists("missing", "1.0.0").unwrap());
+        storage.delete("present", "1.0.0").unwrap();
+        storage.delete("missing", "1.0.0").unwrap();
+        assert!(storage.delete("locked", "1.0.0").is_err());
+    }
 }
@@ -0,0 +1,348 @@
+use errors::*;
+use modules::*;
+
+use std::str::FromStr;
+
+use base64;
+use chrono::{DateTime, Duration, Utc};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use futures::{Future, Stream};
+use hyper;
+use hyper::Client;
+use hyper_tls::HttpsConnector;
+use tokio_core;
+use url;
+
+const API_VERSION: &'static str = "2019-12-12";
+
+fn sign(key: &[u8], string_to_sign: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(string_to_sign.as_bytes());
+    base64::encode(hmac.result().code())
+}
+
+/// How requests to the storage account are authorized.
+enum AzureCredentials {
+    SharedKey(Vec<u8>),
+    Sas(String),
+}
+
+/// Storage in an Azure Blob Storage container, this also works with the
+/// Azurite emulator.
+pub struct AzureCrateStorage {
+    account: String,
+    container: String,
+    prefix: String,
+
+    // The URL of the storage account, for Azurite this includes the account
+    endpoint: url::Url,
+
+    credentials: AzureCredentials,
+
+    // How long download URLs are valid for, if downloads are redirected
+    presign_expiry: Option<u64>,
+}
+
+impl AzureCrateStorage {
+    pub fn new(config: &::config::CaesiumAzureStorageConfig) -> AzureCrateStorage {
+        let endpoint = config.endpoint.clone()
+                                      .unwrap_or(format!("https://{}.blob.core.windows.net", config.account));
+
+        let credentials = match (&config.access_key, &config.sas_token) {
+            (&Some(ref access_key), &None) => {
+                AzureCredentials::SharedKey(base64::decode(access_key).expect("Invalid Azure access key in config"))
+            }
+            (&None, &Some(ref sas_token)) => AzureCredentials::Sas(sas_token.trim_left_matches('?').to_string()),
+            _ => panic!("Azure storage needs one of access_key or sas_token"),
+        };
+
+        if config.presign_expiry.is_some() {
+            if let AzureCredentials::Sas(_) = credentials {
+                panic!("Azure presign_expiry needs an access_key to sign with");
+            }
+        }
+
+        AzureCrateStorage {
+            account: config.account.clone(),
+            container: config.container.clone(),
+            prefix: config.prefix.clone().map(|prefix| prefix.trim_matches('/').to_string()).unwrap_or_default(),
+            endpoint: url::Url::parse(&endpoint).expect("Invalid Azure endpoint in config"),
+            credentials: credentials,
+            presign_expiry: config.presign_expiry,
+        }
+    }
+
+    /// The name of a crate's blob, using the same layout as the other storage
+    /// modules.
+    fn blob_name(&self, name: &str, vers: &str) -> String {
+        if self.prefix.is_empty() {
+            format!("{}/{}/download", name, vers)
+        } else {
+            format!("{}/{}/{}/download", self.prefix, name, vers)
+        }
+    }
+
+    fn blob_url(&self, name: &str, vers: &str) -> url::Url {
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut().unwrap()
+                               .pop_if_empty()
+                               .push(&self.container)
+                               .extend(self.blob_name(name, vers).split('/'));
+        url
+    }
+
+    /// Builds a request for a crate's blob, authorized with either the shared
+    /// key or the SAS token.
+    fn request(&self, method: hyper::Method, name: &str, vers: &str, body: Vec<u8>) -> Result<hyper::Request> {
+        self.request_at(method, name, vers, body, Utc::now())
+    }
+
+    fn request_at(&self, method: hyper::Method, name: &str, vers: &str, body: Vec<u8>, now: DateTime<Utc>) -> Result<hyper::Request> {
+        let mut url = self.blob_url(name, vers);
+        if let AzureCredentials::Sas(ref sas_token) = self.credentials {
+            url.set_query(Some(sas_token.as_str()));
+        }
+
+        let mut headers = vec![("x-ms-date".to_string(), now.format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+                               ("x-ms-version".to_string(), API_VERSION.to_string())];
+        if method == hyper::Method::Put {
+            headers.insert(0, ("x-ms-blob-type".to_string(), "BlockBlob".to_string()));
+        }
+
+        if let AzureCredentials::SharedKey(ref key) = self.credentials {
+            let content_length = if body.is_empty() { String::new() } else { body.len().to_string() };
+            let canonical_headers = headers.iter().map(|&(ref name, ref value)| format!("{}:{}\n", name, value)).collect::<String>();
+
+            // The fields are the standard headers that are signed, only the
+            // length is ever sent.
+            let string_to_sign = format!("{}\n\n\n{}\n\n\n\n\n\n\n\n\n{}/{}{}",
+                                         method,
+                                         content_length,
+                                         canonical_headers,
+                                         self.account,
+                                         url.path());
+
+            headers.push(("Authorization".to_string(),
+                          format!("SharedKey {}:{}", self.account, sign(key, &string_to_sign))));
+        }
+
+        let mut request = hyper::Request::new(method, hyper::Uri::from_str(url.as_str())?);
+        for (name, value) in headers {
+            request.headers_mut().set_raw(name, value);
+        }
+
+        // Azure needs the length up front, rather than a chunked upload
+        if !body.is_empty() {
+            request.headers_mut().set(hyper::header::ContentLength(body.len() as u64));
+            request.set_body(body);
+        }
+
+        Ok(request)
+    }
+
+    fn send(&self, request: hyper::Request) -> Result<(hyper::StatusCode, Vec<u8>)> {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+
+        let client = Client::configure()
+            .connector(HttpsConnector::new(4, &handle).expect("Failed to setup HTTPS"))
+            .build(&handle);
+
+        let work = client.request(request).and_then(|res| {
+            let status = res.status();
+            res.body().concat2().map(move |body| (status, body.to_vec()))
+        });
+
+        Ok(core.run(work)?)
+    }
+
+    /// Builds a read only service SAS URL for downloading a crate.
+    fn presign(&self, key: &[u8], name: &str, vers: &str, expiry: u64) -> String {
+        self.presign_until(key, name, vers, Utc::now() + Duration::seconds(expiry as i64))
+    }
+
+    fn presign_until(&self, key: &[u8], name: &str, vers: &str, expires: DateTime<Utc>) -> String {
+        let expires = expires.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        let resource = format!("/blob/{}/{}/{}", self.account, self.container, self.blob_name(name, vers));
+
+        // The fields which aren't used are left empty, but still need to be
+        // signed.
+        let string_to_sign = ["r",
+                              "",
+                              expires.as_str(),
+                              resource.as_str(),
+                              "",
+                              "",
+                              "",
+                              API_VERSION,
+                              "b",
+                              "",
+                              "",
+                              "",
+                              "",
+                              "",
+                              ""].join("\n");
+
+        let mut url = self.blob_url(name, vers);
+        url.query_pairs_mut()
+           .append_pair("sp", "r")
+           .append_pair("se", &expires)
+           .append_pair("sv", API_VERSION)
+           .append_pair("sr", "b")
+           .append_pair("sig", &sign(key, &string_to_sign));
+
+        url.into_string()
+    }
+}
+
+/// Azure Blob Storage based storage
+impl storage::CrateStorage for AzureCrateStorage {
+    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8], _publisher: Option<&str>) -> Result<()> {
+        let request = self.request(hyper::Method::Put, &manifest.name, &manifest.vers, tar.to_vec())?;
+
+        let (status, body) = self.send(request)?;
+        if status != hyper::StatusCode::Created {
+            bail!("Azure returned {} uploading {} {}: {}", status, manifest.name, manifest.vers, String::from_utf8_lossy(&body));
+        }
+
+        Ok(())
+    }
+
+    fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+        let request = self.request(hyper::Method::Get, name, vers, Vec::new())?;
+
+        let (status, body) = self.send(request)?;
+        if status != hyper::StatusCode::Ok {
+            bail!("Azure returned {} downloading {} {}", status, name, vers);
+        }
+
+        Ok(body)
+    }
+
+    fn delete(&self, name: &str, vers: &str) -> Result<()> {
+        let request = self.request(hyper::Method::Delete, name, vers, Vec::new())?;
+
+        let (status, body) = self.send(request)?;
+        if status != hyper::StatusCode::Accepted && status != hyper::StatusCode::NotFound {
+            bail!("Azure returned {} deleting {} {}: {}", status, name, vers, String::from_utf8_lossy(&body));
+        }
+
+        Ok(())
+    }
+
+    fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+        let request = self.request(hyper::Method::Head, name, vers, Vec::new())?;
+
+        match self.send(request)?.0 {
+            hyper::StatusCode::Ok => Ok(true),
+            hyper::StatusCode::NotFound => Ok(false),
+            status => bail!("Azure returned {} checking {} {}", status, name, vers),
+        }
+    }
+
+    fn download_url(&self, name: &str, vers: &str) -> Result<Option<String>> {
+        match (&self.credentials, self.presign_expiry) {
+            (&AzureCredentials::SharedKey(ref key), Some(expiry)) => Ok(Some(self.presign(key, name, vers, expiry))),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::storage::CrateStorage;
+    use test_server::{TestResponse, TestServer};
+
+    use chrono::TimeZone;
+
+    // The well known account and key of the Azurite emulator
+    const ACCOUNT: &'static str = "devstoreaccount1";
+    const KEY: &'static str = "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+    fn storage(endpoint: &str, credentials: AzureCredentials) -> AzureCrateStorage {
+        AzureCrateStorage {
+            account: ACCOUNT.to_string(),
+            container: "crates".to_string(),
+            prefix: String::new(),
+            endpoint: url::Url::parse(&format!("{}/{}", endpoint, ACCOUNT)).unwrap(),
+            credentials: credentials,
+            presign_expiry: None,
+        }
+    }
+
+    fn shared_key() -> AzureCredentials {
+        AzureCredentials::SharedKey(base64::decode(KEY).unwrap())
+    }
+
+    fn header(request: &hyper::Request, name: &str) -> Option<String> {
+        request.headers().get_raw(name)
+                         .and_then(|value| value.one())
+                         .map(|value| String::from_utf8_lossy(value).into_owned())
+    }
+
+    #[test]
+    fn signs_requests_with_shared_key() {
+        let storage = storage("http://127.0.0.1:10000", shared_key());
+        let now = Utc.ymd(2020, 1, 2).and_hms(3, 4, 5);
+
+        let put = storage.request_at(hyper::Method::Put, "my-crate", "1.0.0", b"crate".to_vec(), now).unwrap();
+        assert_eq!(put.uri().path(), "/devstoreaccount1/crates/my-crate/1.0.0/download");
+        assert_eq!(header(&put, "x-ms-date"), Some("Thu, 02 Jan 2020 03:04:05 GMT".to_string()));
+        assert_eq!(header(&put, "Authorization"),
+                   Some("SharedKey devstoreaccount1:olGP7Hjm+pt5Ma40wyE49U5RttAKN2w/peJA/MtImQ8=".to_string()));
+
+        let head = storage.request_at(hyper::Method::Head, "my-crate", "1.0.0", Vec::new(), now).unwrap();
+        assert_eq!(header(&head, "Authorization"),
+                   Some("SharedKey devstoreaccount1:DNmHzlVTUvTQUF/uJ/4oRMWoiBBMJ1MsLxJVJIxqgOM=".to_string()));
+    }
+
+    #[test]
+    fn sends_sas_token_instead_of_signing() {
+        let storage = storage("http://127.0.0.1:10000", AzureCredentials::Sas("sv=2019-12-12&sig=abc".to_string()));
+
+        let request = storage.request(hyper::Method::Get, "my-crate", "1.0.0", Vec::new()).unwrap();
+        assert_eq!(request.uri().query(), Some("sv=2019-12-12&sig=abc"));
+        assert_eq!(header(&request, "Authorization"), None);
+    }
+
+    #[test]
+    fn presigns_download_urls() {
+        let storage = storage("http://127.0.0.1:10000", shared_key());
+        let key = base64::decode(KEY).unwrap();
+
+        let url = storage.presign_until(&key, "my-crate", "1.0.0", Utc.ymd(2020, 1, 2).and_hms(4, 4, 5));
+        let url = url::Url::parse(&url).unwrap();
+        assert_eq!(url.path(), "/devstoreaccount1/crates/my-crate/1.0.0/download");
+        assert_eq!(url.query_pairs().into_owned().collect::<Vec<_>>(),
+                   vec![("sp".to_string(), "r".to_string()),
+                        ("se".to_string(), "2020-01-02T04:04:05Z".to_string()),
+                        ("sv".to_string(), API_VERSION.to_string()),
+                        ("sr".to_string(), "b".to_string()),
+                        ("sig".to_string(), "r0GJiLTPz+wx2uIF2jWcnQNJZnuzNFbEut2IN0NCg4A=".to_string())]);
+    }
+
+    #[test]
+    fn checks_and_deletes_blobs() {
+        let server = TestServer::start(|request| {
+            match (request.method.as_str(), request.path.contains("/present/")) {
+                ("HEAD", true) => TestResponse::new(200, ""),
+                ("HEAD", false) => TestResponse::new(404, ""),
+                ("DELETE", _) => TestResponse::new(202, ""),
+                _ => TestResponse::new(400, ""),
+            }
+        });
+        let storage = storage(&server.url, shared_key());
+
+        assert!(storage.exists("present", "1.0.0").unwrap());
+        assert!(!storage.exists("missing", "1.0.0").unwrap());
+        storage.delete("present", "1.0.0").unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[2].method, "DELETE");
+        assert_eq!(requests[2].path, "/devstoreaccount1/crates/present/1.0.0/download");
+        assert!(requests.iter().all(|request| request.header("Authorization").map_or(false, |auth| auth.starts_with("SharedKey "))));
+    }
+}
@@ -0,0 +1,344 @@
+use errors::*;
+use modules::*;
+
+use std;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::UNIX_EPOCH;
+
+use glob::glob;
+
+/// A crate file in the cache.
+struct CacheEntry {
+    size: u64,
+
+    // When the file was last used, relative to the other files
+    last_used: u64,
+}
+
+struct CacheEntries {
+    entries: HashMap<PathBuf, CacheEntry>,
+    size: u64,
+    clock: u64,
+}
+
+impl CacheEntries {
+    fn touch(&mut self, path: &Path) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(path) {
+            entry.last_used = self.clock;
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, size: u64) {
+        self.remove(&path);
+        self.clock += 1;
+        self.size += size;
+        self.entries.insert(path, CacheEntry { size: size, last_used: self.clock });
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.size -= entry.size;
+        }
+    }
+
+    /// Returns the least recently used files, which need removing to get the
+    /// cache under its maximum size.
+    fn evict(&mut self, max_size: u64) -> Vec<PathBuf> {
+        let mut evicted = Vec::new();
+
+        while self.size > max_size {
+            let path = match self.entries.iter().min_by_key(|&(_, entry)| entry.last_used) {
+                Some((path, _)) => path.clone(),
+                None => break,
+            };
+            self.remove(&path);
+            evicted.push(path);
+        }
+
+        evicted
+    }
+}
+
+/// Keeps recently downloaded crates on local disk, in front of any other
+/// storage. Cached crates are checked against the checksum in the index
+/// whenever they are used. Downloads are never redirected to the storage, as
+/// that would bypass the cache.
+pub struct CachedCrateStorage {
+    storage: Box<storage::CrateStorage>,
+    location: PathBuf,
+    max_size: u64,
+    entries: Mutex<CacheEntries>,
+
+    // Numbers the temporary files, so that two writes of the same crate
+    // don't write to the same file
+    writes: AtomicUsize,
+}
+
+impl CachedCrateStorage {
+    pub fn new(config: &::config::CaesiumCacheStorageConfig, storage: Box<storage::CrateStorage>) -> CachedCrateStorage {
+        let location = PathBuf::from(&config.location);
+        std::fs::create_dir_all(&location).expect("Failed to create cache dir");
+
+        // Temporary files left by writes which didn't finish, such as when
+        // Caesium was stopped, are never used
+        let pattern = location.join("*").join("*").join("*.partial");
+        for path in glob(&pattern.to_string_lossy()).expect("Invalid cache location").filter_map(|path| path.ok()) {
+            if let Err(e) = std::fs::remove_file(&path) {
+                println!("Failed to remove {} from the cache: {}", path.display(), e);
+            }
+        }
+
+        // The files which are already cached are ordered by when they were
+        // written, as that is all that is known about them
+        let pattern = location.join("*").join("*").join("download");
+        let mut files = glob(&pattern.to_string_lossy()).expect("Invalid cache location")
+            .filter_map(|path| path.ok())
+            .filter_map(|path| std::fs::metadata(&path).ok().map(|metadata| (path, metadata)))
+            .map(|(path, metadata)| {
+                let modified = metadata.modified().ok()
+                                       .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                                       .map(|modified| modified.as_secs())
+                                       .unwrap_or(0);
+                (modified, path, metadata.len())
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+
+        let mut entries = CacheEntries {
+            entries: HashMap::new(),
+            size: 0,
+            clock: 0,
+        };
+        for (_, path, size) in files {
+            entries.insert(path, size);
+        }
+
+        let cache = CachedCrateStorage {
+            storage: storage,
+            location: location,
+            max_size: config.max_size_mb * 1024 * 1024,
+            entries: Mutex::new(entries),
+            writes: AtomicUsize::new(0),
+        };
+        cache.evict();
+
+        cache
+    }
+
+    fn cache_file(&self, name: &str, vers: &str) -> PathBuf {
+        self.location.join(name).join(vers).join("download")
+    }
+
+    fn evict(&self) {
+        let evicted = self.entries.lock().unwrap().evict(self.max_size);
+
+        for path in evicted {
+            if let Err(e) = std::fs::remove_file(&path) {
+                println!("Failed to remove {} from the cache: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Returns the cached crate file, if it is cached and matches the
+    /// checksum. Files which don't match are removed.
+    fn read(&self, path: &Path, cksum: &str) -> Option<Vec<u8>> {
+        if !self.entries.lock().unwrap().entries.contains_key(path) {
+            return None;
+        }
+
+        let mut tar = Vec::new();
+        let matches = match File::open(path).and_then(|mut f| f.read_to_end(&mut tar)) {
+            Ok(_) => ::registry::checksum(&tar) == cksum,
+            Err(_) => false,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if matches {
+            entries.touch(path);
+            Some(tar)
+        } else {
+            println!("Cached file {} does not match the index, removing it", path.display());
+            entries.remove(path);
+            let _ = std::fs::remove_file(path);
+            None
+        }
+    }
+
+    /// Adds a crate file to the cache, it is written to a temporary file first
+    /// so that a partial file is never read.
+    fn write(&self, path: &Path, tar: &[u8]) -> Result<()> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        let partial = path.with_file_name(format!("download.{}-{}.partial",
+                                                  std::process::id(),
+                                                  self.writes.fetch_add(1, Ordering::SeqCst)));
+        let written = File::create(&partial).and_then(|mut f| f.write_all(tar))
+                                            .and_then(|_| std::fs::rename(&partial, path));
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e.into());
+        }
+
+        self.entries.lock().unwrap().insert(path.to_path_buf(), tar.len() as u64);
+        self.evict();
+
+        Ok(())
+    }
+}
+
+/// Cached storage
+impl storage::CrateStorage for CachedCrateStorage {
+    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8], publisher: Option<&str>) -> Result<()> {
+        self.storage.upload(manifest, tar, publisher)?;
+
+        // Newly published crates are likely to be downloaded soon
+        if let Err(e) = self.write(&self.cache_file(&manifest.name, &manifest.vers), tar) {
+            println!("Failed to cache {} {}: {}", manifest.name, manifest.vers, e);
+        }
+
+        Ok(())
+    }
+
+    fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+        // Without the checksum the cached file can't be trusted
+        self.storage.download(name, vers)
+    }
+
+    fn download_verified(&self, name: &str, vers: &str, cksum: &str) -> Result<Vec<u8>> {
+        let path = self.cache_file(name, vers);
+        if let Some(tar) = self.read(&path, cksum) {
+            return Ok(tar);
+        }
+
+        let tar = self.storage.download_verified(name, vers, cksum)?;
+        if ::registry::checksum(&tar) != cksum {
+            bail!("Crate file for {} {} does not match the checksum in the index", name, vers);
+        }
+
+        if let Err(e) = self.write(&path, &tar) {
+            println!("Failed to cache {} {}: {}", name, vers, e);
+        }
+
+        Ok(tar)
+    }
+
+    fn delete(&self, name: &str, vers: &str) -> Result<()> {
+        let path = self.cache_file(name, vers);
+        self.entries.lock().unwrap().remove(&path);
+        let _ = std::fs::remove_file(&path);
+
+        self.storage.delete(name, vers)
+    }
+
+    fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+        self.storage.exists(name, vers)
+    }
+
+    fn repair(&self) -> Result<storage::RepairSummary> {
+        self.storage.repair()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::storage::CrateStorage;
+    use test_helpers::test_dir;
+
+    use std::sync::Arc;
+
+    /// Storage which holds crate files in memory, and counts the downloads.
+    struct MemoryStorage {
+        crates: HashMap<String, Vec<u8>>,
+        downloads: Arc<AtomicUsize>,
+    }
+
+    impl storage::CrateStorage for MemoryStorage {
+        fn upload(&self, _manifest: &::registry::CargoManifest, _tar: &[u8], _publisher: Option<&str>) -> Result<()> {
+            bail!("read only")
+        }
+
+        fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+            self.downloads.fetch_add(1, Ordering::SeqCst);
+            match self.crates.get(&format!("{} {}", name, vers)) {
+                Some(tar) => Ok(tar.clone()),
+                None => bail!("{} {} is not stored", name, vers),
+            }
+        }
+    }
+
+    /// A cache of at most 1MB in front of storage holding crates `a`, `b`
+    /// and `c`, each of which is 400KB.
+    fn cache(dir: &Path) -> (CachedCrateStorage, Arc<AtomicUsize>) {
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let storage = MemoryStorage {
+            crates: ["a", "b", "c"].iter().map(|name| (format!("{} 1.0.0", name), vec![name.as_bytes()[0]; 400 * 1024])).collect(),
+            downloads: downloads.clone(),
+        };
+        let config = ::config::CaesiumCacheStorageConfig {
+            location: dir.to_string_lossy().into_owned(),
+            max_size_mb: 1,
+        };
+
+        (CachedCrateStorage::new(&config, Box::new(storage)), downloads)
+    }
+
+    fn cksum(name: &str) -> String {
+        ::registry::checksum(&vec![name.as_bytes()[0]; 400 * 1024])
+    }
+
+    #[test]
+    fn least_recently_used_crates_are_evicted() {
+        let dir = test_dir("cache-lru");
+        let (cache, downloads) = cache(&dir);
+
+        cache.download_verified("a", "1.0.0", &cksum("a")).unwrap();
+        cache.download_verified("b", "1.0.0", &cksum("b")).unwrap();
+        cache.download_verified("a", "1.0.0", &cksum("a")).unwrap();
+        assert_eq!(downloads.load(Ordering::SeqCst), 2);
+
+        // Caching c goes over the limit, b was used longest ago
+        cache.download_verified("c", "1.0.0", &cksum("c")).unwrap();
+        assert!(dir.join("a").join("1.0.0").join("download").exists());
+        assert!(!dir.join("b").join("1.0.0").join("download").exists());
+        assert!(dir.join("c").join("1.0.0").join("download").exists());
+
+        cache.download_verified("a", "1.0.0", &cksum("a")).unwrap();
+        assert_eq!(downloads.load(Ordering::SeqCst), 3);
+        cache.download_verified("b", "1.0.0", &cksum("b")).unwrap();
+        assert_eq!(downloads.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn cached_files_which_do_not_match_are_purged() {
+        let dir = test_dir("cache-purge");
+        let (cache, downloads) = cache(&dir);
+        cache.download_verified("a", "1.0.0", &cksum("a")).unwrap();
+
+        let path = dir.join("a").join("1.0.0").join("download");
+        File::create(&path).unwrap().write_all(b"corrupt").unwrap();
+
+        assert_eq!(cache.download_verified("a", "1.0.0", &cksum("a")).unwrap(), vec![b'a'; 400 * 1024]);
+        assert_eq!(downloads.load(Ordering::SeqCst), 2);
+
+        // Storage which doesn't match the index isn't cached either
+        assert!(cache.download_verified("b", "1.0.0", &cksum("a")).is_err());
+        assert!(!dir.join("b").join("1.0.0").join("download").exists());
+    }
+
+    #[test]
+    fn partial_files_are_removed_at_startup() {
+        let dir = test_dir("cache-partial");
+        std::fs::create_dir_all(dir.join("a").join("1.0.0")).unwrap();
+        File::create(dir.join("a").join("1.0.0").join("download.1-0.partial")).unwrap();
+
+        cache(&dir);
+        assert!(!dir.join("a").join("1.0.0").join("download.1-0.partial").exists());
+    }
+}
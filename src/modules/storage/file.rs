@@ -4,7 +4,7 @@ use modules::*;
 
 use std;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::PathBuf;
 
 pub struct FileCrateStorage {
@@ -21,7 +21,7 @@ impl FileCrateStorage {
 
 /// File based storage
 impl storage::CrateStorage for FileCrateStorage {
-    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8]) -> Result<()> {
+    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8], _publisher: Option<&str>) -> Result<()> {
         let upload_file = self.location.join(manifest.name.clone()).join(manifest.vers.clone()).join("download");
 
         std::fs::create_dir_all(upload_file.parent().unwrap()).expect("Failed to create dir");
@@ -31,4 +31,23 @@ impl storage::CrateStorage for FileCrateStorage {
 
         Ok(())
     }
+
+    fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+        let download_file = self.location.join(name).join(vers).join("download");
+
+        let mut tar = Vec::new();
+        File::open(download_file)?.read_to_end(&mut tar)?;
+
+        Ok(tar)
+    }
+
+    fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+        Ok(self.location.join(name).join(vers).join("download").is_file())
+    }
+
+    fn delete(&self, name: &str, vers: &str) -> Result<()> {
+        std::fs::remove_file(self.location.join(name).join(vers).join("download"))?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,344 @@
+use errors::*;
+use modules::*;
+
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use base64;
+use chrono::Utc;
+use futures::{Future, Stream};
+use hyper;
+use hyper::Client;
+use hyper::header::{Authorization, Bearer, ContentLength, ContentType};
+use hyper_tls::HttpsConnector;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde_json;
+use serde_json::Value;
+use tokio_core;
+use url;
+
+const DEFAULT_ENDPOINT: &'static str = "https://storage.googleapis.com";
+const DEFAULT_TOKEN_URI: &'static str = "https://oauth2.googleapis.com/token";
+const SCOPE: &'static str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+// How long the access tokens are requested for, and how long before they
+// expire that they are renewed
+const TOKEN_LIFETIME: i64 = 3600;
+const TOKEN_RENEWAL: i64 = 300;
+
+/// The parts of a service account JSON key that are needed.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AssertionHeader {
+    alg: &'static str,
+    typ: &'static str,
+}
+
+#[derive(Serialize)]
+struct AssertionClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+fn encode<T: ::serde::Serialize>(part: &T) -> Result<String> {
+    Ok(base64::encode_config(&serde_json::to_vec(part)?, base64::URL_SAFE_NO_PAD))
+}
+
+/// Storage in a Google Cloud Storage bucket, using the JSON API. This also
+/// works with fake-gcs-server.
+pub struct GcsCrateStorage {
+    bucket: String,
+    prefix: String,
+    endpoint: url::Url,
+
+    // Requests are anonymous when there is no key, which is only useful for
+    // emulators
+    key: Option<ServiceAccountKey>,
+
+    // The current access token and when it expires
+    token: Mutex<Option<(String, i64)>>,
+}
+
+impl GcsCrateStorage {
+    pub fn new(config: &::config::CaesiumGcsStorageConfig) -> GcsCrateStorage {
+        let key = config.credentials_file.as_ref().map(|credentials_file| {
+            let mut contents = String::new();
+            File::open(credentials_file).and_then(|mut f| f.read_to_string(&mut contents))
+                                        .expect("Failed to read GCS credentials file");
+
+            let key: ServiceAccountKey = serde_json::from_str(&contents).expect("Invalid GCS credentials file");
+            PKey::private_key_from_pem(key.private_key.as_bytes()).expect("Invalid private key in GCS credentials file");
+            key
+        });
+
+        let endpoint = config.endpoint.clone().unwrap_or(DEFAULT_ENDPOINT.to_string());
+
+        GcsCrateStorage {
+            bucket: config.bucket.clone(),
+            prefix: config.prefix.clone().map(|prefix| prefix.trim_matches('/').to_string()).unwrap_or_default(),
+            endpoint: url::Url::parse(&endpoint).expect("Invalid GCS endpoint in config"),
+            key: key,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// The name of a crate's object, using the same layout as the other
+    /// storage modules.
+    fn object_name(&self, name: &str, vers: &str) -> String {
+        if self.prefix.is_empty() {
+            format!("{}/{}/download", name, vers)
+        } else {
+            format!("{}/{}/{}/download", self.prefix, name, vers)
+        }
+    }
+
+    /// The URL of a crate's object in the JSON API, where the object name is
+    /// a single segment so the slashes get encoded.
+    fn object_url(&self, name: &str, vers: &str) -> url::Url {
+        let object_name = self.object_name(name, vers);
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut().unwrap()
+                               .pop_if_empty()
+                               .extend(&["storage", "v1", "b", self.bucket.as_str(), "o", object_name.as_str()]);
+        url
+    }
+
+    /// Signs a JWT with the service account key, which is exchanged for an
+    /// access token.
+    fn assertion(&self, key: &ServiceAccountKey) -> Result<String> {
+        let now = Utc::now().timestamp();
+        let claims = AssertionClaims {
+            iss: &key.client_email,
+            scope: SCOPE,
+            aud: key.token_uri.as_ref().map(|token_uri| token_uri.as_str()).unwrap_or(DEFAULT_TOKEN_URI),
+            iat: now,
+            exp: now + TOKEN_LIFETIME,
+        };
+
+        let message = format!("{}.{}", encode(&AssertionHeader { alg: "RS256", typ: "JWT" })?, encode(&claims)?);
+
+        let pkey = PKey::private_key_from_pem(key.private_key.as_bytes())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+        signer.update(message.as_bytes())?;
+        let signature = signer.finish()?;
+
+        Ok(format!("{}.{}", message, base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)))
+    }
+
+    /// Returns an access token, requesting a new one when the current token
+    /// is about to expire.
+    fn access_token(&self, key: &ServiceAccountKey) -> Result<String> {
+        let mut token = self.token.lock().unwrap();
+        let now = Utc::now().timestamp();
+
+        if let Some((ref access_token, expires)) = *token {
+            if expires - TOKEN_RENEWAL > now {
+                return Ok(access_token.clone());
+            }
+        }
+
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer")
+            .append_pair("assertion", &self.assertion(key)?)
+            .finish();
+
+        let token_uri = key.token_uri.clone().unwrap_or(DEFAULT_TOKEN_URI.to_string());
+        let mut request = hyper::Request::new(hyper::Method::Post, hyper::Uri::from_str(&token_uri)?);
+        request.headers_mut().set(ContentType::form_url_encoded());
+        request.set_body(body);
+
+        let (status, body) = self.send(request)?;
+        if status != hyper::StatusCode::Ok {
+            bail!("GCS token endpoint returned {}: {}", status, String::from_utf8_lossy(&body));
+        }
+
+        let response: Value = serde_json::from_slice(&body)?;
+        let access_token = match response["access_token"].as_str() {
+            Some(access_token) => access_token.to_string(),
+            None => bail!("No access token returned by GCS token endpoint"),
+        };
+        let expires_in = response["expires_in"].as_i64().unwrap_or(TOKEN_LIFETIME);
+
+        *token = Some((access_token.clone(), now + expires_in));
+
+        Ok(access_token)
+    }
+
+    fn request(&self, method: hyper::Method, url: url::Url) -> Result<hyper::Request> {
+        let mut request = hyper::Request::new(method, hyper::Uri::from_str(url.as_str())?);
+
+        if let Some(ref key) = self.key {
+            request.headers_mut().set(Authorization(Bearer { token: self.access_token(key)? }));
+        }
+
+        Ok(request)
+    }
+
+    fn send(&self, request: hyper::Request) -> Result<(hyper::StatusCode, Vec<u8>)> {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+
+        let client = Client::configure()
+            .connector(HttpsConnector::new(4, &handle).expect("Failed to setup HTTPS"))
+            .build(&handle);
+
+        let work = client.request(request).and_then(|res| {
+            let status = res.status();
+            res.body().concat2().map(move |body| (status, body.to_vec()))
+        });
+
+        Ok(core.run(work)?)
+    }
+}
+
+/// Google Cloud Storage based storage
+impl storage::CrateStorage for GcsCrateStorage {
+    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8], _publisher: Option<&str>) -> Result<()> {
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut().unwrap()
+                               .pop_if_empty()
+                               .extend(&["upload", "storage", "v1", "b", self.bucket.as_str(), "o"]);
+        url.query_pairs_mut()
+           .append_pair("uploadType", "media")
+           .append_pair("name", &self.object_name(&manifest.name, &manifest.vers));
+
+        let mut request = self.request(hyper::Method::Post, url)?;
+        request.headers_mut().set(ContentType::octet_stream());
+        request.headers_mut().set(ContentLength(tar.len() as u64));
+        request.set_body(tar.to_vec());
+
+        let (status, body) = self.send(request)?;
+        if status != hyper::StatusCode::Ok {
+            bail!("GCS returned {} uploading {} {}: {}", status, manifest.name, manifest.vers, String::from_utf8_lossy(&body));
+        }
+
+        Ok(())
+    }
+
+    fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+        let mut url = self.object_url(name, vers);
+        url.query_pairs_mut().append_pair("alt", "media");
+
+        let request = self.request(hyper::Method::Get, url)?;
+
+        let (status, body) = self.send(request)?;
+        if status != hyper::StatusCode::Ok {
+            bail!("GCS returned {} downloading {} {}", status, name, vers);
+        }
+
+        Ok(body)
+    }
+
+    fn delete(&self, name: &str, vers: &str) -> Result<()> {
+        let request = self.request(hyper::Method::Delete, self.object_url(name, vers))?;
+
+        let (status, body) = self.send(request)?;
+        if status != hyper::StatusCode::NoContent && status != hyper::StatusCode::NotFound {
+            bail!("GCS returned {} deleting {} {}: {}", status, name, vers, String::from_utf8_lossy(&body));
+        }
+
+        Ok(())
+    }
+
+    fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+        // Without `alt=media` this only returns the object's metadata
+        let request = self.request(hyper::Method::Get, self.object_url(name, vers))?;
+
+        match self.send(request)?.0 {
+            hyper::StatusCode::Ok => Ok(true),
+            hyper::StatusCode::NotFound => Ok(false),
+            status => bail!("GCS returned {} checking {} {}", status, name, vers),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::storage::CrateStorage;
+    use test_helpers::manifest;
+    use test_server::{TestResponse, TestServer};
+
+    use std::env;
+
+    use openssl::rsa::Rsa;
+
+    fn storage(endpoint: &str, key: Option<ServiceAccountKey>) -> GcsCrateStorage {
+        GcsCrateStorage {
+            bucket: "crates".to_string(),
+            prefix: String::new(),
+            endpoint: url::Url::parse(endpoint).unwrap(),
+            key: key,
+            token: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn checks_and_deletes_objects_with_service_account() {
+        let server = TestServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/token") => TestResponse::json(r#"{"access_token": "test-token", "expires_in": 3600}"#),
+                ("GET", "/storage/v1/b/crates/o/present%2F1.0.0%2Fdownload") => TestResponse::json("{}"),
+                ("DELETE", "/storage/v1/b/crates/o/present%2F1.0.0%2Fdownload") => TestResponse::new(204, ""),
+                _ => TestResponse::new(404, ""),
+            }
+        });
+
+        let private_key = Rsa::generate(2048).unwrap().private_key_to_pem().unwrap();
+        let storage = storage(&server.url, Some(ServiceAccountKey {
+            client_email: "caesium@example.iam.gserviceaccount.com".to_string(),
+            private_key: String::from_utf8(private_key).unwrap(),
+            token_uri: Some(format!("{}/token", server.url)),
+        }));
+
+        assert!(storage.exists("present", "1.0.0").unwrap());
+        assert!(!storage.exists("missing", "1.0.0").unwrap());
+        storage.delete("present", "1.0.0").unwrap();
+        storage.delete("missing", "1.0.0").unwrap();
+
+        // The access token is reused until it is about to expire
+        let requests = server.requests();
+        assert_eq!(requests.iter().filter(|request| request.path == "/token").count(), 1);
+        assert!(String::from_utf8_lossy(&requests[0].body).contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer"));
+        assert!(requests[1..].iter().all(|request| request.header("Authorization") == Some("Bearer test-token")));
+    }
+
+    /// Runs against fake-gcs-server, such as `docker run -p 4443:4443
+    /// fsouza/fake-gcs-server -scheme http`, or the server in
+    /// `FAKE_GCS_SERVER_URL`.
+    #[test]
+    #[ignore]
+    fn fake_gcs_server_round_trip() {
+        let endpoint = env::var("FAKE_GCS_SERVER_URL").unwrap_or("http://localhost:4443".to_string());
+        let storage = storage(&endpoint, None);
+
+        // The bucket may already exist from an earlier run
+        let mut url = storage.endpoint.clone();
+        url.path_segments_mut().unwrap().pop_if_empty().extend(&["storage", "v1", "b"]);
+        let mut request = storage.request(hyper::Method::Post, url).unwrap();
+        request.headers_mut().set(ContentType::json());
+        request.set_body(r#"{"name": "crates"}"#);
+        storage.send(request).unwrap();
+
+        storage.upload(&manifest("example", "0.1.0"), b"crate file", None).unwrap();
+        assert!(storage.exists("example", "0.1.0").unwrap());
+        assert_eq!(storage.download("example", "0.1.0").unwrap(), b"crate file".to_vec());
+
+        storage.delete("example", "0.1.0").unwrap();
+        assert!(!storage.exists("example", "0.1.0").unwrap());
+        assert!(storage.download("example", "0.1.0").is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use errors::*;
+use modules::*;
+
+use std::str::FromStr;
+
+use futures::{Future, Stream};
+use hyper;
+use hyper::Client;
+use hyper::header::{Authorization, Basic, Bearer, ContentLength};
+use hyper_tls::HttpsConnector;
+use tokio_core;
+use url;
+
+/// How requests to the server are authenticated.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum HttpAuthentication {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+
+    // A custom header, such as `X-JFrog-Art-Api`
+    Header { name: String, value: String },
+}
+
+/// Storage on any server which supports PUT, GET and DELETE of files, such as
+/// a WebDAV share or a Nexus raw repository.
+pub struct HttpCrateStorage {
+    base_url: url::Url,
+    authentication: Option<HttpAuthentication>,
+
+    // WebDAV servers need the directories to be created before uploading
+    create_collections: bool,
+
+    // The status codes which mean that each request succeeded
+    upload_status: Vec<u16>,
+    download_status: Vec<u16>,
+    delete_status: Vec<u16>,
+}
+
+impl HttpCrateStorage {
+    pub fn new(config: &::config::CaesiumHttpStorageConfig) -> HttpCrateStorage {
+        HttpCrateStorage {
+            base_url: url::Url::parse(&config.base_url).expect("Invalid HTTP storage URL in config"),
+            authentication: config.auth.clone(),
+            create_collections: config.create_collections.unwrap_or(false),
+            upload_status: config.upload_status.clone().unwrap_or(vec![200, 201, 204]),
+            download_status: config.download_status.clone().unwrap_or(vec![200]),
+            delete_status: config.delete_status.clone().unwrap_or(vec![200, 202, 204]),
+        }
+    }
+
+    fn url(&self, segments: &[&str]) -> url::Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut().unwrap()
+                               .pop_if_empty()
+                               .extend(segments);
+        url
+    }
+
+    fn send(&self, method: hyper::Method, url: url::Url, body: Option<&[u8]>) -> Result<(hyper::StatusCode, Vec<u8>)> {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+
+        let client = Client::configure()
+            .connector(HttpsConnector::new(4, &handle).expect("Failed to setup HTTPS"))
+            .build(&handle);
+
+        let mut request = hyper::Request::new(method, hyper::Uri::from_str(url.as_str())?);
+
+        match self.authentication {
+            Some(HttpAuthentication::Basic { ref username, ref password }) => {
+                request.headers_mut().set(Authorization(Basic {
+                    username: username.clone(),
+                    password: Some(password.clone()),
+                }));
+            }
+            Some(HttpAuthentication::Bearer { ref token }) => {
+                request.headers_mut().set(Authorization(Bearer { token: token.clone() }));
+            }
+            Some(HttpAuthentication::Header { ref name, ref value }) => {
+                request.headers_mut().set_raw(name.clone(), value.clone());
+            }
+            None => {}
+        }
+
+        if let Some(body) = body {
+            request.headers_mut().set(ContentLength(body.len() as u64));
+            request.set_body(body.to_vec());
+        }
+
+        let work = client.request(request).and_then(|res| {
+            let status = res.status();
+            res.body().concat2().map(move |body| (status, body.to_vec()))
+        });
+
+        Ok(core.run(work)?)
+    }
+
+    /// Creates the directories for a crate file, the server refuses to create
+    /// those which already exist with 405 Method Not Allowed.
+    fn create_collections(&self, name: &str, vers: &str) -> Result<()> {
+        for segments in &[vec![name], vec![name, vers]] {
+            let mut url = self.url(segments);
+            url.path_segments_mut().unwrap().push("");
+
+            let (status, body) = self.send(hyper::Method::Extension("MKCOL".to_string()), url.clone(), None)?;
+            if !status.is_success() && status != hyper::StatusCode::MethodNotAllowed {
+                bail!("HTTP storage returned {} creating {}: {}", status, url, String::from_utf8_lossy(&body));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// HTTP based storage
+impl storage::CrateStorage for HttpCrateStorage {
+    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8], _publisher: Option<&str>) -> Result<()> {
+        if self.create_collections {
+            self.create_collections(&manifest.name, &manifest.vers)?;
+        }
+
+        let url = self.url(&[manifest.name.as_str(), manifest.vers.as_str(), "download"]);
+        let (status, body) = self.send(hyper::Method::Put, url, Some(tar))?;
+        if !self.upload_status.contains(&u16::from(status)) {
+            bail!("HTTP storage returned {} uploading {} {}: {}", status, manifest.name, manifest.vers, String::from_utf8_lossy(&body));
+        }
+
+        Ok(())
+    }
+
+    fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+        let url = self.url(&[name, vers, "download"]);
+        let (status, body) = self.send(hyper::Method::Get, url, None)?;
+        if !self.download_status.contains(&u16::from(status)) {
+            bail!("HTTP storage returned {} downloading {} {}", status, name, vers);
+        }
+
+        Ok(body)
+    }
+
+    fn delete(&self, name: &str, vers: &str) -> Result<()> {
+        let url = self.url(&[name, vers, "download"]);
+        let (status, _) = self.send(hyper::Method::Delete, url, None)?;
+        if !self.delete_status.contains(&u16::from(status)) {
+            bail!("HTTP storage returned {} deleting {} {}", status, name, vers);
+        }
+
+        Ok(())
+    }
+
+    fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+        let url = self.url(&[name, vers, "download"]);
+        let (status, _) = self.send(hyper::Method::Head, url, None)?;
+        if self.download_status.contains(&u16::from(status)) {
+            return Ok(true);
+        }
+
+        match status {
+            hyper::StatusCode::NotFound => Ok(false),
+            status => bail!("HTTP storage returned {} checking {} {}", status, name, vers),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::storage::CrateStorage;
+    use test_helpers::manifest;
+    use test_server::{TestResponse, TestServer};
+
+    fn storage(url: &str) -> HttpCrateStorage {
+        configured_storage(url, "")
+    }
+
+    fn configured_storage(url: &str, config: &str) -> HttpCrateStorage {
+        HttpCrateStorage::new(&::toml::from_str(&format!("base_url = {:?}\n{}", url, config)).unwrap())
+    }
+
+    /// Sends a request with the given authentication, returning the headers
+    /// that the server received.
+    fn authenticated_request(auth: &str) -> ::test_server::TestRequest {
+        let server = TestServer::start(|_| TestResponse::new(200, "crate"));
+        let storage = configured_storage(&server.url, &format!("auth = {}", auth));
+
+        storage.download("example", "0.1.0").unwrap();
+        server.requests().remove(0)
+    }
+
+    #[test]
+    fn sends_basic_authentication() {
+        let request = authenticated_request(r#"{ type = "basic", username = "caesium", password = "secret" }"#);
+        assert_eq!(request.header("Authorization"), Some("Basic Y2Flc2l1bTpzZWNyZXQ="));
+    }
+
+    #[test]
+    fn sends_bearer_tokens() {
+        let request = authenticated_request(r#"{ type = "bearer", token = "secret-token" }"#);
+        assert_eq!(request.header("Authorization"), Some("Bearer secret-token"));
+    }
+
+    #[test]
+    fn sends_custom_headers() {
+        let request = authenticated_request(r#"{ type = "header", name = "X-JFrog-Art-Api", value = "api-key" }"#);
+        assert_eq!(request.header("X-JFrog-Art-Api"), Some("api-key"));
+        assert_eq!(request.header("Authorization"), None);
+    }
+
+    #[test]
+    fn uploads_downloads_and_deletes_crates() {
+        let server = TestServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("PUT", "/crates/example/0.1.0/download") => TestResponse::new(201, ""),
+                ("GET", "/crates/example/0.1.0/download") => TestResponse::new(200, "crate"),
+                ("DELETE", "/crates/example/0.1.0/download") => TestResponse::new(204, ""),
+                _ => TestResponse::new(500, ""),
+            }
+        });
+        let storage = storage(&format!("{}/crates/", server.url));
+
+        storage.upload(&manifest("example", "0.1.0"), b"crate", None).unwrap();
+        assert_eq!(storage.download("example", "0.1.0").unwrap(), b"crate".to_vec());
+        storage.delete("example", "0.1.0").unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].body, b"crate".to_vec());
+        assert_eq!(requests.iter().map(|request| request.method.as_str()).collect::<Vec<_>>(), vec!["PUT", "GET", "DELETE"]);
+    }
+
+    #[test]
+    fn statuses_can_be_configured() {
+        let server = TestServer::start(|request| {
+            match request.method.as_str() {
+                "PUT" => TestResponse::new(201, ""),
+                "GET" => TestResponse::new(203, "crate"),
+                _ => TestResponse::new(200, ""),
+            }
+        });
+
+        let defaults = storage(&server.url);
+        defaults.upload(&manifest("example", "0.1.0"), b"crate", None).unwrap();
+        assert!(defaults.download("example", "0.1.0").is_err());
+        defaults.delete("example", "0.1.0").unwrap();
+
+        let configured = configured_storage(&server.url, "upload_status = [202]\ndownload_status = [203]\ndelete_status = [204]");
+        assert!(configured.upload(&manifest("example", "0.1.0"), b"crate", None).is_err());
+        assert_eq!(configured.download("example", "0.1.0").unwrap(), b"crate".to_vec());
+        assert!(configured.delete("example", "0.1.0").is_err());
+    }
+
+    #[test]
+    fn creates_collections_before_uploading() {
+        let server = TestServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                // The crate's collection already exists
+                ("MKCOL", "/example/") => TestResponse::new(405, ""),
+                ("MKCOL", "/example/0.1.0/") => TestResponse::new(201, ""),
+                ("PUT", "/example/0.1.0/download") => TestResponse::new(201, ""),
+                _ => TestResponse::new(500, ""),
+            }
+        });
+        let storage = configured_storage(&server.url, "create_collections = true");
+
+        storage.upload(&manifest("example", "0.1.0"), b"crate", None).unwrap();
+
+        let requests = server.requests().into_iter().map(|request| format!("{} {}", request.method, request.path)).collect::<Vec<_>>();
+        assert_eq!(requests, vec!["MKCOL /example/", "MKCOL /example/0.1.0/", "PUT /example/0.1.0/download"]);
+    }
+
+    #[test]
+    fn collection_failures_stop_uploads() {
+        let server = TestServer::start(|request| {
+            match request.method.as_str() {
+                "MKCOL" => TestResponse::new(403, "read only"),
+                _ => TestResponse::new(201, ""),
+            }
+        });
+        let storage = configured_storage(&server.url, "create_collections = true");
+
+        assert!(storage.upload(&manifest("example", "0.1.0"), b"crate", None).is_err());
+        assert!(server.requests().iter().all(|request| request.method == "MKCOL"));
+    }
+
+    #[test]
+    fn checks_crates_with_head_requests() {
+        let server = TestServer::start(|request| {
+            match request.path.as_str() {
+                "/present/1.0.0/download" => TestResponse::new(200, ""),
+                "/missing/1.0.0/download" => TestResponse::new(404, ""),
+                _ => TestResponse::new(500, ""),
+            }
+        });
+        let storage = storage(&server.url);
+
+        assert!(storage.exists("present", "1.0.0").unwrap());
+        assert!(!storage.exists("missing", "1.0.0").unwrap());
+        assert!(storage.exists("broken", "1.0.0").is_err());
+        assert!(server.requests().iter().all(|request| request.method == "HEAD"));
+    }
+}
@@ -1,11 +1,61 @@
 
 use errors::*;
 
+/// The crates which a repair copied to every backend that was missing them,
+/// and those which still need repairing, as `name version`.
+#[derive(Debug, Default, Serialize)]
+pub struct RepairSummary {
+    pub repaired: Vec<String>,
+    pub remaining: Vec<String>,
+}
+
 pub trait CrateStorage {
     // Uploads the crate of the tar file and returns the URL that it is
-    // available at.
-    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8]) -> Result<()>;
+    // available at. The publisher is the user that published the crate, for
+    // storage which can record it.
+    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8], publisher: Option<&str>) -> Result<()>;
+
+    // Downloads the crate file for a version of a crate.
+    fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>>;
+
+    // Downloads the crate file for a version of a crate, when its checksum
+    // is known from the index. Storage which caches crates uses this to check
+    // the cached file.
+    fn download_verified(&self, name: &str, vers: &str, _cksum: &str) -> Result<Vec<u8>> {
+        self.download(name, vers)
+    }
+
+    // Deletes the crate file for a version of a crate.
+    fn delete(&self, name: &str, vers: &str) -> Result<()> {
+        bail!("Deleting {} {} is not supported by this storage", name, vers)
+    }
+
+    // Checks whether the crate file for a version of a crate is stored,
+    // without downloading it.
+    fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+        bail!("Checking {} {} is not supported by this storage", name, vers)
+    }
+
+    // Returns a short lived URL that the crate file can be downloaded from
+    // directly, for storage that supports it, so that downloads don't need to
+    // go through Caesium.
+    fn download_url(&self, _name: &str, _vers: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    // Copies crates to any backends which failed to store them, for storage
+    // which keeps several copies.
+    fn repair(&self) -> Result<RepairSummary> {
+        bail!(ErrorKind::ValidationError("repairing is only supported by replicated storage".to_string()))
+    }
 }
 
 pub mod file;
 pub mod artifactory;
+pub mod s3;
+pub mod azure;
+pub mod gcs;
+pub mod oci;
+pub mod http;
+pub mod replicated;
+pub mod cache;
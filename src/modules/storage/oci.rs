@@ -0,0 +1,523 @@
+use errors::*;
+use modules::*;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use futures::{Future, Stream};
+use hyper;
+use hyper::Client;
+use hyper::header::{Authorization, Basic, Bearer, ContentLength, ContentType};
+use hyper_tls::HttpsConnector;
+use serde_json;
+use serde_json::Value;
+use tokio_core;
+use url;
+
+const MANIFEST_MEDIA_TYPE: &'static str = "application/vnd.oci.image.manifest.v1+json";
+const ARTIFACT_TYPE: &'static str = "application/vnd.rust.crate.v1";
+const CONFIG_MEDIA_TYPE: &'static str = "application/vnd.rust.crate.config.v1+json";
+const CRATE_MEDIA_TYPE: &'static str = "application/vnd.rust.crate.v1.tar+gzip";
+
+// The config blob has no content, OCI requires that there is one though
+const CONFIG: &'static [u8] = b"{}";
+
+fn digest(data: &[u8]) -> String {
+    let mut sha = Sha256::new();
+    sha.input(data);
+    format!("sha256:{}", sha.result_str())
+}
+
+fn header_value(res: &hyper::Response, name: &str) -> Option<String> {
+    res.headers().get_raw(name)
+                 .and_then(|raw| raw.one())
+                 .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+/// Parses the parameters of a `Bearer` challenge, the values may be quoted and
+/// contain commas.
+fn parse_challenge(challenge: &str) -> Option<Vec<(String, String)>> {
+    if !challenge.starts_with("Bearer ") {
+        return None;
+    }
+
+    let mut params = Vec::new();
+    let mut rest = challenge["Bearer ".len()..].trim();
+
+    while let Some(equals) = rest.find('=') {
+        let key = rest[..equals].trim().to_string();
+        rest = &rest[equals + 1..];
+
+        let value = if rest.starts_with('"') {
+            let end = rest[1..].find('"').map(|end| end + 1).unwrap_or(rest.len());
+            let value = rest[1..end].to_string();
+            rest = &rest[(end + 1).min(rest.len())..];
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            value
+        };
+
+        params.push((key, value));
+        rest = rest.trim_left_matches(|c: char| c == ',' || c.is_whitespace());
+    }
+
+    Some(params)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    size: usize,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageManifest {
+    schema_version: u32,
+    media_type: Option<String>,
+    artifact_type: Option<String>,
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+/// A response from the registry.
+struct RegistryResponse {
+    status: hyper::StatusCode,
+    location: Option<String>,
+    challenge: Option<String>,
+
+    // The digest of a manifest, which is needed to delete it
+    digest: Option<String>,
+
+    body: Vec<u8>,
+}
+
+/// Storage in an OCI registry, each crate is a repository and each version is
+/// a tag of an artifact containing the crate file.
+pub struct OciCrateStorage {
+    base_url: url::Url,
+
+    // The namespace that the crate repositories are created under
+    namespace: Option<String>,
+
+    username: Option<String>,
+    password: Option<String>,
+
+    // Tokens given by the registry's token service, which are scoped to a
+    // repository and whether it is being pulled from or pushed to
+    tokens: Mutex<HashMap<(String, &'static str), String>>,
+}
+
+impl OciCrateStorage {
+    pub fn new(config: &::config::CaesiumOciStorageConfig) -> OciCrateStorage {
+        OciCrateStorage {
+            base_url: url::Url::parse(&config.url).expect("Invalid OCI registry URL in config"),
+            namespace: config.namespace.clone().map(|namespace| namespace.trim_matches('/').to_string()),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// OCI repository names must be lower case, crate names are unique
+    /// regardless of case so this doesn't cause clashes.
+    fn repository(&self, name: &str) -> String {
+        match self.namespace {
+            Some(ref namespace) => format!("{}/{}", namespace, name.to_lowercase()),
+            None => name.to_lowercase(),
+        }
+    }
+
+    /// OCI tags can't contain `+`, so build metadata is separated with `_`
+    /// instead.
+    fn tag(vers: &str) -> String {
+        vers.replace('+', "_")
+    }
+
+    fn url(&self, path: &str) -> Result<url::Url> {
+        Ok(self.base_url.join(path)?)
+    }
+
+    /// Fetches a token for the scope in a challenge from the registry's token
+    /// service.
+    fn fetch_token(&self, challenge: &str) -> Result<String> {
+        let params = match parse_challenge(challenge) {
+            Some(params) => params,
+            None => bail!("Unsupported OCI registry authentication challenge: {}", challenge),
+        };
+
+        // The other parameters, such as the service and scope, are passed on
+        // to the token service
+        let realm = match params.iter().find(|&&(ref key, _)| key == "realm") {
+            Some(&(_, ref realm)) => realm.clone(),
+            None => bail!("OCI registry authentication challenge has no realm"),
+        };
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        for &(ref key, ref value) in params.iter().filter(|&&(ref key, _)| key != "realm") {
+            query.append_pair(key, value);
+        }
+        let query = query.finish();
+
+        let mut request = hyper::Request::new(hyper::Method::Get, hyper::Uri::from_str(&format!("{}?{}", realm, query))?);
+        if let Some(ref username) = self.username {
+            request.headers_mut().set(Authorization(Basic {
+                username: username.clone(),
+                password: self.password.clone(),
+            }));
+        }
+
+        let response = self.send(request)?;
+        if response.status != hyper::StatusCode::Ok {
+            bail!("OCI registry token service returned {}", response.status);
+        }
+
+        let body: Value = serde_json::from_slice(&response.body)?;
+        match body["token"].as_str().or(body["access_token"].as_str()) {
+            Some(token) => Ok(token.to_string()),
+            None => bail!("No token returned by OCI registry token service"),
+        }
+    }
+
+    fn send(&self, request: hyper::Request) -> Result<RegistryResponse> {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+
+        let client = Client::configure()
+            .connector(HttpsConnector::new(4, &handle).expect("Failed to setup HTTPS"))
+            .build(&handle);
+
+        let work = client.request(request).and_then(|res| {
+            let status = res.status();
+            let location = header_value(&res, "Location");
+            let challenge = header_value(&res, "WWW-Authenticate");
+            let digest = header_value(&res, "Docker-Content-Digest");
+            res.body().concat2().map(move |body| {
+                RegistryResponse {
+                    status: status,
+                    location: location,
+                    challenge: challenge,
+                    digest: digest,
+                    body: body.to_vec(),
+                }
+            })
+        });
+
+        Ok(core.run(work)?)
+    }
+
+    /// Sends a request to a repository in the registry, if the registry asks
+    /// for a token then one is fetched and the request is sent again.
+    fn send_authorized<F>(&self, repository: &str, method: hyper::Method, url: &url::Url, build: F) -> Result<RegistryResponse>
+        where F: Fn(&mut hyper::Request)
+    {
+        let action = match method {
+            hyper::Method::Get | hyper::Method::Head => "pull",
+            _ => "push",
+        };
+        let key = (repository.to_string(), action);
+
+        let authorize = |request: &mut hyper::Request| {
+            if let Some(token) = self.tokens.lock().unwrap().get(&key) {
+                request.headers_mut().set(Authorization(Bearer { token: token.clone() }));
+            } else if let Some(ref username) = self.username {
+                request.headers_mut().set(Authorization(Basic {
+                    username: username.clone(),
+                    password: self.password.clone(),
+                }));
+            }
+        };
+
+        let mut request = hyper::Request::new(method.clone(), hyper::Uri::from_str(url.as_str())?);
+        authorize(&mut request);
+        build(&mut request);
+
+        let response = self.send(request)?;
+        if response.status != hyper::StatusCode::Unauthorized {
+            return Ok(response);
+        }
+        let challenge = match response.challenge.clone() {
+            Some(ref challenge) if challenge.starts_with("Bearer ") => challenge.clone(),
+            _ => return Ok(response),
+        };
+
+        let token = self.fetch_token(&challenge)?;
+        self.tokens.lock().unwrap().insert(key.clone(), token);
+
+        let mut request = hyper::Request::new(method, hyper::Uri::from_str(url.as_str())?);
+        authorize(&mut request);
+        build(&mut request);
+
+        self.send(request)
+    }
+
+    /// Checks a version's manifest without downloading it.
+    fn head_manifest(&self, name: &str, vers: &str) -> Result<RegistryResponse> {
+        let repository = self.repository(name);
+
+        let url = self.url(&format!("/v2/{}/manifests/{}", repository, OciCrateStorage::tag(vers)))?;
+        self.send_authorized(&repository, hyper::Method::Head, &url, |request| {
+            request.headers_mut().set_raw("Accept", MANIFEST_MEDIA_TYPE);
+        })
+    }
+
+    /// Uploads a blob to a repository in a single request.
+    fn push_blob(&self, repository: &str, blob: &[u8]) -> Result<()> {
+        let digest = digest(blob);
+
+        let url = self.url(&format!("/v2/{}/blobs/{}", repository, digest))?;
+        let response = self.send_authorized(&repository, hyper::Method::Head, &url, |_| {})?;
+        if response.status == hyper::StatusCode::Ok {
+            return Ok(());
+        }
+
+        let url = self.url(&format!("/v2/{}/blobs/uploads/", repository))?;
+        let response = self.send_authorized(&repository, hyper::Method::Post, &url, |_| {})?;
+        if response.status != hyper::StatusCode::Accepted {
+            bail!("OCI registry returned {} starting upload to {}", response.status, repository);
+        }
+
+        let mut url = match response.location {
+            Some(location) => url.join(&location)?,
+            None => bail!("OCI registry did not return an upload location for {}", repository),
+        };
+        url.query_pairs_mut().append_pair("digest", &digest);
+
+        let response = self.send_authorized(&repository, hyper::Method::Put, &url, |request| {
+            request.headers_mut().set(ContentType::octet_stream());
+            request.headers_mut().set(ContentLength(blob.len() as u64));
+            request.set_body(blob.to_vec());
+        })?;
+        if response.status != hyper::StatusCode::Created {
+            bail!("OCI registry returned {} uploading to {}: {}", response.status, repository, String::from_utf8_lossy(&response.body));
+        }
+
+        Ok(())
+    }
+}
+
+/// OCI registry based storage
+impl storage::CrateStorage for OciCrateStorage {
+    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8], _publisher: Option<&str>) -> Result<()> {
+        let repository = self.repository(&manifest.name);
+
+        self.push_blob(&repository, CONFIG)?;
+        self.push_blob(&repository, tar)?;
+
+        let mut annotations = HashMap::new();
+        annotations.insert("org.opencontainers.image.title".to_string(), format!("{}-{}.crate", manifest.name, manifest.vers));
+        annotations.insert("org.opencontainers.image.version".to_string(), manifest.vers.clone());
+
+        let image_manifest = ImageManifest {
+            schema_version: 2,
+            media_type: Some(MANIFEST_MEDIA_TYPE.to_string()),
+            artifact_type: Some(ARTIFACT_TYPE.to_string()),
+            config: Descriptor {
+                media_type: CONFIG_MEDIA_TYPE.to_string(),
+                digest: digest(CONFIG),
+                size: CONFIG.len(),
+                annotations: HashMap::new(),
+            },
+            layers: vec![Descriptor {
+                media_type: CRATE_MEDIA_TYPE.to_string(),
+                digest: digest(tar),
+                size: tar.len(),
+                annotations: annotations,
+            }],
+        };
+        let body = serde_json::to_vec(&image_manifest)?;
+
+        let url = self.url(&format!("/v2/{}/manifests/{}", repository, OciCrateStorage::tag(&manifest.vers)))?;
+        let response = self.send_authorized(&repository, hyper::Method::Put, &url, |request| {
+            request.headers_mut().set_raw("Content-Type", MANIFEST_MEDIA_TYPE);
+            request.headers_mut().set(ContentLength(body.len() as u64));
+            request.set_body(body.clone());
+        })?;
+        if response.status != hyper::StatusCode::Created {
+            bail!("OCI registry returned {} pushing {} {}: {}", response.status, manifest.name, manifest.vers, String::from_utf8_lossy(&response.body));
+        }
+
+        Ok(())
+    }
+
+    fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+        let repository = self.repository(name);
+
+        let url = self.url(&format!("/v2/{}/manifests/{}", repository, OciCrateStorage::tag(vers)))?;
+        let response = self.send_authorized(&repository, hyper::Method::Get, &url, |request| {
+            request.headers_mut().set_raw("Accept", MANIFEST_MEDIA_TYPE);
+        })?;
+        if response.status != hyper::StatusCode::Ok {
+            bail!("OCI registry returned {} for the manifest of {} {}", response.status, name, vers);
+        }
+
+        let image_manifest: ImageManifest = serde_json::from_slice(&response.body)?;
+        let layer_digest = match image_manifest.layers.into_iter().find(|layer| layer.media_type == CRATE_MEDIA_TYPE) {
+            Some(layer) => layer.digest,
+            None => bail!("OCI artifact for {} {} has no crate layer", name, vers),
+        };
+
+        let url = self.url(&format!("/v2/{}/blobs/{}", repository, layer_digest))?;
+        let mut response = self.send_authorized(&repository, hyper::Method::Get, &url, |_| {})?;
+
+        // Registries may redirect blobs to their storage, which must not be
+        // sent the registry's credentials
+        if response.status == hyper::StatusCode::TemporaryRedirect || response.status == hyper::StatusCode::Found {
+            let location = match response.location {
+                Some(ref location) => url.join(location)?,
+                None => bail!("OCI registry redirected without a location for {} {}", name, vers),
+            };
+            response = self.send(hyper::Request::new(hyper::Method::Get, hyper::Uri::from_str(location.as_str())?))?;
+        }
+
+        if response.status != hyper::StatusCode::Ok {
+            bail!("OCI registry returned {} downloading {} {}", response.status, name, vers);
+        }
+
+        if digest(&response.body) != layer_digest {
+            bail!("OCI registry returned a crate for {} {} which doesn't match its digest", name, vers);
+        }
+
+        Ok(response.body)
+    }
+
+    fn delete(&self, name: &str, vers: &str) -> Result<()> {
+        // Registries only delete manifests by their digest, which deletes the
+        // tag along with it. The blobs are left for the registry's garbage
+        // collection.
+        let response = self.head_manifest(name, vers)?;
+        let digest = match response.status {
+            hyper::StatusCode::Ok => match response.digest {
+                Some(digest) => digest,
+                None => bail!("OCI registry did not return the digest of {} {}", name, vers),
+            },
+            hyper::StatusCode::NotFound => return Ok(()),
+            status => bail!("OCI registry returned {} for the manifest of {} {}", status, name, vers),
+        };
+
+        let repository = self.repository(name);
+        let url = self.url(&format!("/v2/{}/manifests/{}", repository, digest))?;
+        let response = self.send_authorized(&repository, hyper::Method::Delete, &url, |_| {})?;
+        if response.status != hyper::StatusCode::Accepted && response.status != hyper::StatusCode::NotFound {
+            bail!("OCI registry returned {} deleting {} {}", response.status, name, vers);
+        }
+
+        Ok(())
+    }
+
+    fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+        match self.head_manifest(name, vers)?.status {
+            hyper::StatusCode::Ok => Ok(true),
+            hyper::StatusCode::NotFound => Ok(false),
+            status => bail!("OCI registry returned {} checking {} {}", status, name, vers),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::storage::CrateStorage;
+    use test_helpers::manifest;
+    use test_server::{TestResponse, TestServer};
+
+    use std::env;
+    use std::sync::Arc;
+
+    fn storage(url: &str) -> OciCrateStorage {
+        OciCrateStorage::new(&::toml::from_str(&format!("url = {:?}\nnamespace = \"crates\"", url)).unwrap())
+    }
+
+    #[test]
+    fn parses_bearer_challenges() {
+        let params = parse_challenge(r#"Bearer realm="https://auth.example.com/token",service="registry",scope="repository:crates/a:pull,push""#);
+        assert_eq!(params, Some(vec![("realm".to_string(), "https://auth.example.com/token".to_string()),
+                                     ("service".to_string(), "registry".to_string()),
+                                     ("scope".to_string(), "repository:crates/a:pull,push".to_string())]));
+
+        assert_eq!(parse_challenge(r#"Basic realm="registry""#), None);
+    }
+
+    #[test]
+    fn tokens_are_cached_per_repository_and_scope() {
+        // Each token is only accepted for the scope it was issued for
+        let base_url = Arc::new(Mutex::new(String::new()));
+        let realm = base_url.clone();
+        let server = TestServer::start(move |request| {
+            if request.path.starts_with("/token?") {
+                let query = url::form_urlencoded::parse(request.path["/token?".len()..].as_bytes()).into_owned().collect::<HashMap<_, _>>();
+                return TestResponse::json(&format!(r#"{{"token": "token for {}"}}"#, query["scope"]));
+            }
+
+            let repository = request.path.trim_left_matches("/v2/").split("/blobs/").next().unwrap().to_string();
+            let action = if request.method == "HEAD" { "pull" } else { "push" };
+            let scope = format!("repository:{}:{}", repository, action);
+
+            if request.header("Authorization") == Some(format!("Bearer token for {}", scope).as_str()) {
+                TestResponse::new(200, "")
+            } else {
+                let challenge = format!(r#"Bearer realm="{}/token",service="test",scope="{}""#, realm.lock().unwrap(), scope);
+                TestResponse::new(401, "").header("WWW-Authenticate", &challenge)
+            }
+        });
+        *base_url.lock().unwrap() = server.url.clone();
+
+        let storage = storage(&server.url);
+        storage.push_blob("crates/a", CONFIG).unwrap();
+        storage.push_blob("crates/b", CONFIG).unwrap();
+        storage.push_blob("crates/a", CONFIG).unwrap();
+
+        let token_requests = server.requests().into_iter().filter(|request| request.path.starts_with("/token?")).count();
+        assert_eq!(token_requests, 2);
+    }
+
+    #[test]
+    fn deletes_manifests_by_digest() {
+        let server = TestServer::start(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("HEAD", "/v2/crates/example/manifests/1.0.0_build") => {
+                    TestResponse::new(200, "").header("Docker-Content-Digest", "sha256:abc")
+                }
+                ("HEAD", _) => TestResponse::new(404, ""),
+                ("DELETE", "/v2/crates/example/manifests/sha256:abc") => TestResponse::new(202, ""),
+                _ => TestResponse::new(400, ""),
+            }
+        });
+        let storage = storage(&server.url);
+
+        assert!(storage.exists("Example", "1.0.0+build").unwrap());
+        assert!(!storage.exists("Example", "2.0.0").unwrap());
+        storage.delete("Example", "1.0.0+build").unwrap();
+        storage.delete("Example", "2.0.0").unwrap();
+
+        let deletes = server.requests().into_iter().filter(|request| request.method == "DELETE").count();
+        assert_eq!(deletes, 1);
+    }
+
+    /// Runs against a local registry, such as `docker run -p 5000:5000
+    /// registry:2`, or the registry in `OCI_REGISTRY_URL`.
+    #[test]
+    #[ignore]
+    fn registry_round_trip() {
+        let storage = storage(&env::var("OCI_REGISTRY_URL").unwrap_or("http://localhost:5000".to_string()));
+
+        let tar = format!("crate file {}", ::modules::authentication::tokens::now()).into_bytes();
+        storage.upload(&manifest("Example", "0.1.0+build.1"), &tar, None).unwrap();
+        assert_eq!(storage.download("Example", "0.1.0+build.1").unwrap(), tar);
+        assert!(storage.download("Example", "0.2.0").is_err());
+
+        // The registry needs REGISTRY_STORAGE_DELETE_ENABLED=true for this
+        assert!(storage.exists("Example", "0.1.0+build.1").unwrap());
+        storage.delete("Example", "0.1.0+build.1").unwrap();
+        assert!(!storage.exists("Example", "0.1.0+build.1").unwrap());
+    }
+}
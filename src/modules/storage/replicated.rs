@@ -0,0 +1,580 @@
+use errors::*;
+use modules::*;
+
+use std;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::Utc;
+use serde_json;
+
+// How long a backend which failed is skipped for when reading
+const UNHEALTHY_SECONDS: i64 = 60;
+
+/// How many of the backends must store a crate for an upload to succeed.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplicationPolicy {
+    All,
+    Majority,
+    Any,
+}
+
+/// A crate which is missing from some of the backends, because they failed
+/// when it was uploaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnderReplicatedCrate {
+    pub name: String,
+    pub vers: String,
+    pub missing: Vec<String>,
+    pub recorded: i64,
+
+    // Needed to copy the crate to the backends which are missing it
+    pub cksum: String,
+    pub manifest: serde_json::Value,
+    pub publisher: Option<String>,
+}
+
+/// Storage which writes each crate to several backends, so that an outage of
+/// one of them doesn't stop crates from being downloaded.
+pub struct ReplicatedCrateStorage {
+    backends: Vec<(String, Box<storage::CrateStorage>)>,
+    required: usize,
+
+    // When each backend last failed a read
+    failures: Mutex<HashMap<String, i64>>,
+
+    // Where the crates which need repairing are recorded
+    location: PathBuf,
+    under_replicated: Mutex<Vec<UnderReplicatedCrate>>,
+}
+
+impl ReplicatedCrateStorage {
+    pub fn new(config: &::config::CaesiumReplicationStorageConfig,
+               backends: Vec<(String, Box<storage::CrateStorage>)>) -> ReplicatedCrateStorage {
+        if backends.is_empty() {
+            panic!("Replicated storage needs at least one backend");
+        }
+
+        let required = match config.policy.unwrap_or(ReplicationPolicy::All) {
+            ReplicationPolicy::All => backends.len(),
+            ReplicationPolicy::Majority => backends.len() / 2 + 1,
+            ReplicationPolicy::Any => 1,
+        };
+
+        let location = PathBuf::from(&config.under_replicated_location);
+        let under_replicated = if std::fs::metadata(&location).is_ok() {
+            ReplicatedCrateStorage::load(&location).unwrap_or_else(|e| {
+                // The file is kept for repairing by hand, rather than being
+                // overwritten by the next crate recorded
+                let corrupt = PathBuf::from(format!("{}.corrupt", location.display()));
+                println!("Failed to read under-replicated crates from {}, moving it to {}: {}",
+                         location.display(), corrupt.display(), e);
+                if let Err(e) = std::fs::rename(&location, &corrupt) {
+                    println!("Failed to move {}: {}", location.display(), e);
+                }
+                Vec::new()
+            })
+        } else {
+            Vec::new()
+        };
+
+        ReplicatedCrateStorage {
+            backends: backends,
+            required: required,
+            failures: Mutex::new(HashMap::new()),
+            location: location,
+            under_replicated: Mutex::new(under_replicated),
+        }
+    }
+
+    fn load(location: &Path) -> Result<Vec<UnderReplicatedCrate>> {
+        let mut contents = String::new();
+        File::open(location).and_then(|mut f| f.read_to_string(&mut contents))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Writes the under-replicated crates to a temporary file which replaces
+    /// the old one, so that a failed write doesn't lose them.
+    fn save(&self, under_replicated: &[UnderReplicatedCrate]) -> Result<()> {
+        if let Some(parent) = self.location.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let temporary = PathBuf::from(format!("{}.tmp", self.location.display()));
+        {
+            let mut f = File::create(&temporary)?;
+            f.write_all(serde_json::to_string(under_replicated)?.as_bytes())?;
+            f.sync_all()?;
+        }
+        std::fs::rename(&temporary, &self.location)?;
+
+        Ok(())
+    }
+
+    /// Records that a crate is missing from some of the backends.
+    fn record(&self, manifest: &::registry::CargoManifest, tar: &[u8], publisher: Option<&str>, missing: Vec<String>) -> Result<()> {
+        let entry = UnderReplicatedCrate {
+            name: manifest.name.clone(),
+            vers: manifest.vers.clone(),
+            missing: missing,
+            recorded: Utc::now().timestamp(),
+            cksum: ::registry::checksum(tar),
+            manifest: serde_json::to_value(manifest)?,
+            publisher: publisher.map(|publisher| publisher.to_string()),
+        };
+
+        let mut under_replicated = self.under_replicated.lock().unwrap();
+        under_replicated.retain(|existing| !(existing.name == entry.name && existing.vers == entry.vers));
+        under_replicated.push(entry);
+
+        self.save(&under_replicated)
+    }
+
+    /// The crates which are missing from some of the backends, for a repair
+    /// task to copy to them.
+    pub fn under_replicated(&self) -> Vec<UnderReplicatedCrate> {
+        self.under_replicated.lock().unwrap().clone()
+    }
+
+    /// Records that a crate has been copied to some of the backends it was
+    /// missing from, it is no longer under-replicated once it is in all of
+    /// them.
+    pub fn clear_repaired(&self, name: &str, vers: &str, repaired: &[String]) -> Result<()> {
+        let mut under_replicated = self.under_replicated.lock().unwrap();
+
+        for entry in under_replicated.iter_mut().filter(|entry| entry.name == name && entry.vers == vers) {
+            entry.missing.retain(|backend| !repaired.contains(backend));
+        }
+        under_replicated.retain(|entry| !entry.missing.is_empty());
+
+        self.save(&under_replicated)
+    }
+
+    /// Whether a crate was recorded as missing from a backend.
+    fn is_missing(&self, name: &str, vers: &str, backend: &str) -> bool {
+        self.under_replicated.lock().unwrap().iter().any(|entry| {
+            entry.name == name && entry.vers == vers && entry.missing.iter().any(|missing| missing == backend)
+        })
+    }
+
+    /// Returns the backends in the order they should be read from, those which
+    /// failed recently are tried last.
+    fn read_order(&self) -> Vec<&(String, Box<storage::CrateStorage>)> {
+        let now = Utc::now().timestamp();
+        let failures = self.failures.lock().unwrap();
+
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) = self.backends.iter().partition(|&&(ref name, _)| {
+            failures.get(name).map_or(true, |failed| now - failed > UNHEALTHY_SECONDS)
+        });
+
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+
+    /// Reads from the first healthy backend which succeeds, skipping those
+    /// which are known to be missing the crate.
+    fn read<T, F>(&self, name: &str, vers: &str, read: F) -> Result<T>
+        where F: Fn(&storage::CrateStorage) -> Result<T>
+    {
+        let mut errors = Vec::new();
+
+        for &(ref backend_name, ref backend) in self.read_order() {
+            if self.is_missing(name, vers, backend_name) {
+                continue;
+            }
+
+            match read(backend.as_ref()) {
+                Ok(result) => {
+                    self.failures.lock().unwrap().remove(backend_name);
+                    return Ok(result);
+                }
+                Err(e) => {
+                    println!("Failed to read {} {} from {} storage: {}", name, vers, backend_name, e);
+                    errors.push(format!("{} storage: {}", backend_name, e));
+
+                    // A backend which doesn't have the crate is still healthy
+                    if backend.exists(name, vers).ok() != Some(false) {
+                        self.failures.lock().unwrap().insert(backend_name.clone(), Utc::now().timestamp());
+                    }
+                }
+            }
+        }
+
+        bail!("Failed to read {} {} from any storage ({})", name, vers, errors.join(", "))
+    }
+
+    /// Copies a crate from a backend which has it to those which are missing
+    /// it, returning the backends that it was copied to.
+    fn repair_crate(&self, entry: &UnderReplicatedCrate) -> Result<Vec<String>> {
+        let manifest: ::registry::CargoManifest = serde_json::from_value(entry.manifest.clone())?;
+        let tar = self.read(&entry.name, &entry.vers, |backend| backend.download_verified(&entry.name, &entry.vers, &entry.cksum))?;
+        if ::registry::checksum(&tar) != entry.cksum {
+            bail!("Crate file for {} {} does not match its checksum", entry.name, entry.vers);
+        }
+
+        let mut repaired = Vec::new();
+        for &(ref backend_name, ref backend) in self.backends.iter().filter(|&&(ref name, _)| entry.missing.contains(name)) {
+            match backend.upload(&manifest, &tar, entry.publisher.as_ref().map(|publisher| publisher.as_str())) {
+                Ok(()) => repaired.push(backend_name.clone()),
+                Err(e) => println!("Failed to copy {} {} to {} storage: {}", entry.name, entry.vers, backend_name, e),
+            }
+        }
+
+        Ok(repaired)
+    }
+}
+
+/// Replicated storage
+impl storage::CrateStorage for ReplicatedCrateStorage {
+    fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8], publisher: Option<&str>) -> Result<()> {
+        let mut stored = Vec::new();
+        let mut errors = Vec::new();
+
+        for &(ref backend_name, ref backend) in &self.backends {
+            match backend.upload(manifest, tar, publisher) {
+                Ok(()) => stored.push(backend_name),
+                Err(e) => {
+                    println!("Failed to upload {} {} to {} storage: {}", manifest.name, manifest.vers, backend_name, e);
+                    errors.push((backend_name.clone(), e));
+                }
+            }
+        }
+
+        if stored.len() < self.required {
+            // The crate won't be added to the index, so don't leave it in the
+            // backends which did store it
+            for &(ref backend_name, ref backend) in self.backends.iter().filter(|&&(ref name, _)| stored.contains(&name)) {
+                if let Err(e) = backend.delete(&manifest.name, &manifest.vers) {
+                    println!("Failed to remove {} {} from {} storage: {}", manifest.name, manifest.vers, backend_name, e);
+                }
+            }
+
+            bail!("{} {} was stored by {} storage backends but {} are required ({})",
+                  manifest.name,
+                  manifest.vers,
+                  stored.len(),
+                  self.required,
+                  errors.iter().map(|&(ref name, ref e)| format!("{} storage: {}", name, e)).collect::<Vec<_>>().join(", "));
+        }
+
+        // The crate is stored, so failing to record it shouldn't fail the
+        // publish
+        if !errors.is_empty() {
+            if let Err(e) = self.record(manifest, tar, publisher, errors.into_iter().map(|(name, _)| name).collect()) {
+                println!("Failed to record {} {} as under-replicated: {}", manifest.name, manifest.vers, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+        self.read(name, vers, |backend| backend.download(name, vers))
+    }
+
+    fn download_verified(&self, name: &str, vers: &str, cksum: &str) -> Result<Vec<u8>> {
+        self.read(name, vers, |backend| backend.download_verified(name, vers, cksum))
+    }
+
+    fn delete(&self, name: &str, vers: &str) -> Result<()> {
+        let mut errors = Vec::new();
+
+        for &(ref backend_name, ref backend) in &self.backends {
+            if let Err(e) = backend.delete(name, vers) {
+                errors.push(format!("{} storage: {}", backend_name, e));
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!("Failed to delete {} {} ({})", name, vers, errors.join(", "));
+        }
+
+        Ok(())
+    }
+
+    fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+        // A crate which is missing from one backend may be in another, so this
+        // doesn't stop at the first backend which answers
+        let mut answered = false;
+
+        for &(ref backend_name, ref backend) in self.read_order() {
+            match backend.exists(name, vers) {
+                Ok(true) => return Ok(true),
+                Ok(false) => answered = true,
+                Err(e) => {
+                    println!("Failed to check {} {} in {} storage: {}", name, vers, backend_name, e);
+                    self.failures.lock().unwrap().insert(backend_name.clone(), Utc::now().timestamp());
+                }
+            }
+        }
+
+        if !answered {
+            bail!("Failed to check {} {} in any storage", name, vers);
+        }
+
+        Ok(false)
+    }
+
+    fn download_url(&self, name: &str, vers: &str) -> Result<Option<String>> {
+        // Clients can't fall back to another backend, so they are only sent
+        // to one which is known to have the crate. Otherwise the crate is
+        // sent by Caesium, which can.
+        for &(ref backend_name, ref backend) in self.read_order() {
+            if self.is_missing(name, vers, backend_name) {
+                continue;
+            }
+
+            match backend.exists(name, vers) {
+                Ok(true) => return backend.download_url(name, vers),
+                Ok(false) => {}
+                Err(e) => {
+                    println!("Failed to check {} {} in {} storage: {}", name, vers, backend_name, e);
+                    self.failures.lock().unwrap().insert(backend_name.clone(), Utc::now().timestamp());
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn repair(&self) -> Result<storage::RepairSummary> {
+        let mut summary = storage::RepairSummary::default();
+
+        for entry in self.under_replicated() {
+            let crate_id = format!("{} {}", entry.name, entry.vers);
+
+            let repaired = match self.repair_crate(&entry) {
+                Ok(repaired) => repaired,
+                Err(e) => {
+                    println!("Failed to repair {}: {}", crate_id, e);
+                    Vec::new()
+                }
+            };
+            self.clear_repaired(&entry.name, &entry.vers, &repaired)?;
+
+            if repaired.len() == entry.missing.len() {
+                summary.repaired.push(crate_id);
+            } else {
+                summary.remaining.push(crate_id);
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::storage::CrateStorage;
+    use test_helpers::{manifest, test_dir};
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Storage which holds the given crates, and gives a URL for them.
+    struct StubStorage {
+        name: &'static str,
+        crates: Vec<&'static str>,
+    }
+
+    impl storage::CrateStorage for StubStorage {
+        fn upload(&self, _manifest: &::registry::CargoManifest, _tar: &[u8], _publisher: Option<&str>) -> Result<()> {
+            bail!("read only")
+        }
+
+        fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+            bail!("{} {} can't be downloaded", name, vers)
+        }
+
+        fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+            Ok(self.crates.contains(&format!("{} {}", name, vers).as_str()))
+        }
+
+        fn download_url(&self, name: &str, vers: &str) -> Result<Option<String>> {
+            Ok(Some(format!("https://{}/{}/{}", self.name, name, vers)))
+        }
+    }
+
+    /// Storage which keeps crates in memory, and can be taken down.
+    #[derive(Clone)]
+    struct MemoryStorage {
+        name: &'static str,
+        crates: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        available: Arc<AtomicBool>,
+        downloads: Arc<AtomicUsize>,
+    }
+
+    impl MemoryStorage {
+        fn new(name: &'static str) -> MemoryStorage {
+            MemoryStorage {
+                name: name,
+                crates: Arc::new(Mutex::new(HashMap::new())),
+                available: Arc::new(AtomicBool::new(true)),
+                downloads: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        fn check_available(&self) -> Result<()> {
+            if !self.available.load(Ordering::SeqCst) {
+                bail!("{} is down", self.name);
+            }
+            Ok(())
+        }
+    }
+
+    impl storage::CrateStorage for MemoryStorage {
+        fn upload(&self, manifest: &::registry::CargoManifest, tar: &[u8], _publisher: Option<&str>) -> Result<()> {
+            self.check_available()?;
+            self.crates.lock().unwrap().insert(format!("{} {}", manifest.name, manifest.vers), tar.to_vec());
+            Ok(())
+        }
+
+        fn download(&self, name: &str, vers: &str) -> Result<Vec<u8>> {
+            self.check_available()?;
+            self.downloads.fetch_add(1, Ordering::SeqCst);
+            match self.crates.lock().unwrap().get(&format!("{} {}", name, vers)) {
+                Some(tar) => Ok(tar.clone()),
+                None => bail!("{} {} not found", name, vers),
+            }
+        }
+
+        fn exists(&self, name: &str, vers: &str) -> Result<bool> {
+            self.check_available()?;
+            Ok(self.crates.lock().unwrap().contains_key(&format!("{} {}", name, vers)))
+        }
+    }
+
+    fn location(name: &str) -> PathBuf {
+        test_dir(&format!("replicated-{}", name)).join("under-replicated.json")
+    }
+
+    fn replicated(location: &Path, backends: Vec<StubStorage>) -> ReplicatedCrateStorage {
+        replicated_backends(location, backends.into_iter()
+                                              .map(|backend| (backend.name.to_string(), Box::new(backend) as Box<storage::CrateStorage>))
+                                              .collect())
+    }
+
+    fn replicated_in_memory(location: &Path, backends: &[MemoryStorage]) -> ReplicatedCrateStorage {
+        replicated_backends(location, backends.iter()
+                                              .map(|backend| (backend.name.to_string(), Box::new(backend.clone()) as Box<storage::CrateStorage>))
+                                              .collect())
+    }
+
+    fn replicated_backends(location: &Path, backends: Vec<(String, Box<storage::CrateStorage>)>) -> ReplicatedCrateStorage {
+        let config = ::config::CaesiumReplicationStorageConfig {
+            backends: backends.iter().map(|&(ref name, _)| name.clone()).collect(),
+            policy: Some(ReplicationPolicy::Any),
+            under_replicated_location: location.to_string_lossy().into_owned(),
+        };
+
+        ReplicatedCrateStorage::new(&config, backends)
+    }
+
+    #[test]
+    fn downloads_are_redirected_to_backends_with_the_crate() {
+        let location = location("redirect");
+        let storage = replicated(&location, vec![StubStorage { name: "first", crates: vec!["b 1.0.0"] },
+                                                 StubStorage { name: "second", crates: vec!["a 1.0.0", "b 1.0.0"] }]);
+        storage.record(&manifest("b", "1.0.0"), b"b", None, vec!["second".to_string()]).unwrap();
+
+        assert_eq!(storage.download_url("a", "1.0.0").unwrap(), Some("https://second/a/1.0.0".to_string()));
+        assert_eq!(storage.download_url("b", "1.0.0").unwrap(), Some("https://first/b/1.0.0".to_string()));
+        assert_eq!(storage.download_url("c", "1.0.0").unwrap(), None);
+    }
+
+    #[test]
+    fn repaired_crates_are_cleared() {
+        let location = location("repair");
+        let storage = replicated(&location, vec![StubStorage { name: "first", crates: Vec::new() }]);
+        storage.record(&manifest("a", "1.0.0"), b"a", None, vec!["first".to_string(), "second".to_string()]).unwrap();
+        storage.record(&manifest("b", "1.0.0"), b"b", None, vec!["second".to_string()]).unwrap();
+
+        storage.clear_repaired("a", "1.0.0", &["first".to_string()]).unwrap();
+        storage.clear_repaired("b", "1.0.0", &["second".to_string()]).unwrap();
+
+        let remaining = ReplicatedCrateStorage::load(&location).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!((remaining[0].name.as_str(), remaining[0].missing.clone()), ("a", vec!["second".to_string()]));
+        assert!(std::fs::metadata(format!("{}.tmp", location.display())).is_err());
+    }
+
+    #[test]
+    fn corrupt_records_are_moved_aside() {
+        let location = location("corrupt");
+        File::create(&location).unwrap().write_all(b"[{\"name\": ").unwrap();
+
+        let storage = replicated(&location, vec![StubStorage { name: "first", crates: Vec::new() }]);
+        assert!(storage.under_replicated().is_empty());
+        assert!(std::fs::metadata(format!("{}.corrupt", location.display())).is_ok());
+    }
+
+    #[test]
+    fn reads_skip_backends_missing_the_crate() {
+        let location = location("read-missing");
+        let first = MemoryStorage::new("first");
+        let second = MemoryStorage::new("second");
+        let storage = replicated_in_memory(&location, &[first.clone(), second.clone()]);
+
+        // Only the second backend stores the crate, but the first backend
+        // still has an old copy of it
+        first.available.store(false, Ordering::SeqCst);
+        storage.upload(&manifest("a", "1.0.0"), b"new", None).unwrap();
+        first.available.store(true, Ordering::SeqCst);
+        first.crates.lock().unwrap().insert("a 1.0.0".to_string(), b"old".to_vec());
+
+        assert_eq!(storage.download("a", "1.0.0").unwrap(), b"new".to_vec());
+        assert_eq!(first.downloads.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn missing_crates_are_not_failures() {
+        let location = location("read-not-found");
+        let first = MemoryStorage::new("first");
+        let second = MemoryStorage::new("second");
+        let storage = replicated_in_memory(&location, &[first.clone(), second.clone()]);
+        second.crates.lock().unwrap().insert("a 1.0.0".to_string(), b"a".to_vec());
+
+        assert_eq!(storage.download("a", "1.0.0").unwrap(), b"a".to_vec());
+        assert!(storage.failures.lock().unwrap().is_empty());
+
+        // A backend which is down is tried last for a while
+        first.available.store(false, Ordering::SeqCst);
+        assert_eq!(storage.download("a", "1.0.0").unwrap(), b"a".to_vec());
+        assert!(storage.failures.lock().unwrap().contains_key("first"));
+    }
+
+    #[test]
+    fn under_replicated_crates_are_repaired() {
+        let location = location("repair-copy");
+        let first = MemoryStorage::new("first");
+        let second = MemoryStorage::new("second");
+        let storage = replicated_in_memory(&location, &[first.clone(), second.clone()]);
+
+        second.available.store(false, Ordering::SeqCst);
+        storage.upload(&manifest("a", "1.0.0"), b"a", Some("alice")).unwrap();
+        storage.upload(&manifest("b", "1.0.0"), b"b", Some("alice")).unwrap();
+        assert_eq!(storage.under_replicated().len(), 2);
+
+        // Crates which can't be copied yet are kept for the next repair
+        let summary = storage.repair().unwrap();
+        assert_eq!(summary.remaining, vec!["a 1.0.0".to_string(), "b 1.0.0".to_string()]);
+        assert_eq!(storage.under_replicated().len(), 2);
+
+        second.available.store(true, Ordering::SeqCst);
+        first.crates.lock().unwrap().insert("b 1.0.0".to_string(), b"corrupt".to_vec());
+
+        let summary = storage.repair().unwrap();
+        assert_eq!(summary.repaired, vec!["a 1.0.0".to_string()]);
+        assert_eq!(summary.remaining, vec!["b 1.0.0".to_string()]);
+        assert_eq!(second.crates.lock().unwrap().get("a 1.0.0"), Some(&b"a".to_vec()));
+        assert!(second.crates.lock().unwrap().get("b 1.0.0").is_none());
+
+        let remaining = ReplicatedCrateStorage::load(&location).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].name, "b");
+    }
+}
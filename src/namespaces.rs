@@ -0,0 +1,56 @@
+use glob::Pattern;
+
+use config::CaesiumNamespacePolicyConfig;
+use errors::*;
+use modules::authentication::AuthenticationUserInfo;
+use validation;
+
+struct NamespaceRule {
+    pattern: Pattern,
+    users: Vec<String>,
+    groups: Vec<String>,
+}
+
+impl NamespaceRule {
+    fn allows(&self, userinfo: &AuthenticationUserInfo) -> bool {
+        self.users.contains(&userinfo.sub) ||
+            userinfo.groups.iter().any(|group| self.groups.contains(group))
+    }
+}
+
+/// Reserves crate names matching a glob for particular users or groups.
+pub struct NamespacePolicy {
+    rules: Vec<NamespaceRule>,
+}
+
+impl NamespacePolicy {
+    pub fn new(config: &[CaesiumNamespacePolicyConfig]) -> NamespacePolicy {
+        let rules = config.iter().map(|rule| {
+            NamespaceRule {
+                pattern: Pattern::new(&validation::normalize_name(&rule.pattern)).expect("Invalid namespace pattern"),
+                users: rule.users.clone().unwrap_or_default(),
+                groups: rule.groups.clone().unwrap_or_default(),
+            }
+        }).collect();
+
+        NamespacePolicy {
+            rules: rules,
+        }
+    }
+
+    /// Checks that the user is allowed to publish the crate by every rule
+    /// whose pattern matches the crate name.
+    pub fn authorize(&self, name: &str, userinfo: Option<&AuthenticationUserInfo>) -> Result<()> {
+        let normalized = validation::normalize_name(name);
+
+        for rule in self.rules.iter().filter(|rule| rule.pattern.matches(&normalized)) {
+            if !userinfo.map_or(false, |userinfo| rule.allows(userinfo)) {
+                bail!(ErrorKind::AuthorizationError(
+                    format!("crate name '{}' is reserved by the namespace rule '{}', which only allows users {:?} and groups {:?}",
+                            name, rule.pattern.as_str(), rule.users, rule.groups)));
+            }
+        }
+
+        Ok(())
+    }
+}
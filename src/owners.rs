@@ -0,0 +1,63 @@
+use modules::authentication::AuthenticationUserInfo;
+
+/// Owners are given to cargo as logins, teams are distinguished from users by
+/// this prefix, e.g. `team:billing`.
+const TEAM_PREFIX: &'static str = "team:";
+
+/// The owners of a crate, users are identified by their `sub` and teams by the
+/// name of their group.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CrateOwners {
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub teams: Vec<String>,
+}
+
+impl CrateOwners {
+    pub fn new(userinfo: &AuthenticationUserInfo) -> CrateOwners {
+        CrateOwners {
+            users: vec![userinfo.sub.clone()],
+            teams: Vec::new(),
+        }
+    }
+
+    /// Checks whether the user owns the crate, either directly or through a
+    /// team. The groups come from the user's token so team membership is
+    /// re-evaluated on every request.
+    pub fn is_owner(&self, userinfo: &AuthenticationUserInfo) -> bool {
+        self.users.contains(&userinfo.sub) ||
+            userinfo.groups.iter().any(|group| self.teams.contains(group))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.teams.is_empty()
+    }
+
+    pub fn logins(&self) -> Vec<String> {
+        self.users.iter()
+                  .cloned()
+                  .chain(self.teams.iter().map(|team| format!("{}{}", TEAM_PREFIX, team)))
+                  .collect()
+    }
+
+    pub fn add(&mut self, login: &str) {
+        let (owners, name) = self.owners_for(login);
+        if !owners.iter().any(|owner| owner == name) {
+            owners.push(name.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, login: &str) {
+        let (owners, name) = self.owners_for(login);
+        owners.retain(|owner| owner != name);
+    }
+
+    fn owners_for<'a>(&mut self, login: &'a str) -> (&mut Vec<String>, &'a str) {
+        if login.starts_with(TEAM_PREFIX) {
+            (&mut self.teams, &login[TEAM_PREFIX.len()..])
+        } else {
+            (&mut self.users, login)
+        }
+    }
+}
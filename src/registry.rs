@@ -11,8 +11,9 @@ use git2::build::RepoBuilder;
 use git2::{FetchOptions, Repository};
 
 use errors::*;
+use owners::CrateOwners;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CargoManifest {
     pub name: String,
     pub vers: String,
@@ -31,7 +32,7 @@ pub struct CargoManifest {
     pub badges: HashMap<String, HashMap<String, String>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CargoManifestDependency {
     pub optional: bool,
     pub default_features: bool,
@@ -43,7 +44,7 @@ pub struct CargoManifestDependency {
     pub registry: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RegistryIndexEntry {
     pub name: String,
     pub vers: String,
@@ -53,7 +54,7 @@ pub struct RegistryIndexEntry {
     pub yanked: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RegistryIndexDependency {
     pub name: String,
     pub vers: String,
@@ -108,12 +109,25 @@ impl From<CargoManifestDependency> for RegistryIndexDependency {
     }
 }
 
+/// The SHA256 of a crate file, as recorded in the index.
+pub fn checksum(crate_tar: &[u8]) -> String {
+    let mut sha = Sha256::new();
+    sha.input(crate_tar);
+    sha.result_str()
+}
+
 pub struct Registry {
     index_repo: Repository,
 }
 
 impl Registry {
     pub fn new(registry_index: &str) -> Registry {
+        Registry::clone_to(registry_index, Path::new("./repo"))
+    }
+
+    /// Clones the index into the given directory, replacing anything that is
+    /// already there.
+    pub fn clone_to(registry_index: &str, registry_path: &Path) -> Registry {
         let mut cb = git2::RemoteCallbacks::new();
         cb.credentials(|_url, username, _allowed| {
             git2::Cred::ssh_key_from_agent(username.unwrap())
@@ -122,8 +136,6 @@ impl Registry {
         let mut fo = FetchOptions::new();
         fo.remote_callbacks(cb);
 
-        let registry_path = Path::new("./repo");
-
         // Try and remove the repo directory before we clone
         let _ = std::fs::remove_dir_all(registry_path);
 
@@ -152,6 +164,172 @@ impl Registry {
         }
     }
 
+    /// Returns all of the versions of a crate which are in the index, this
+    /// will be empty if the crate has never been published.
+    pub fn crate_entries(&self, name: &str) -> Result<Vec<RegistryIndexEntry>> {
+        let index_file = self.index_file(name);
+        if std::fs::metadata(&index_file).is_err() {
+            return Ok(Vec::new());
+        }
+
+        let mut contents = String::new();
+        std::fs::File::open(&index_file).and_then(|mut f| f.read_to_string(&mut contents))?;
+
+        let mut entries = Vec::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            entries.push(serde_json::from_str(line)?);
+        }
+
+        Ok(entries)
+    }
+
+    /// Returns the index file of a crate as cargo reads it, or `None` if the
+    /// crate has never been published.
+    pub fn index_contents(&self, name: &str) -> Result<Option<String>> {
+        let index_file = self.index_file(name);
+        if std::fs::metadata(&index_file).is_err() {
+            return Ok(None);
+        }
+
+        let mut contents = String::new();
+        std::fs::File::open(&index_file).and_then(|mut f| f.read_to_string(&mut contents))?;
+
+        Ok(Some(contents))
+    }
+
+    /// Returns the `config.json` at the root of the index.
+    pub fn index_config(&self) -> Result<serde_json::Map<String, serde_json::Value>> {
+        let config_file = self.index_repo.workdir().unwrap().join("config.json");
+
+        let mut contents = String::new();
+        std::fs::File::open(&config_file).and_then(|mut f| f.read_to_string(&mut contents))?;
+
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Returns the names of all of the crates in the index.
+    pub fn crate_names(&self) -> Result<Vec<String>> {
+        fn visit(dir: &Path, names: &mut Vec<String>) -> Result<()> {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    visit(&path, names)?;
+                } else if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+            Ok(())
+        }
+
+        let base = self.index_repo.workdir().unwrap();
+        let mut names = Vec::new();
+
+        for entry in std::fs::read_dir(base)? {
+            let path = entry?.path();
+            let is_index_dir = path.file_name()
+                                   .and_then(|name| name.to_str())
+                                   .map_or(false, |name| !name.starts_with('.'));
+            if path.is_dir() && is_index_dir {
+                visit(&path, &mut names)?;
+            }
+        }
+
+        Ok(names)
+    }
+
+    // Owners are kept in the index repository so that they have the same
+    // history as the crates, under a directory which cargo never reads.
+    fn owners_file(&self, name: &str) -> PathBuf {
+        let base = self.index_repo.workdir().unwrap();
+
+        base.join(".owners").join(format!("{}.json", name.to_lowercase()))
+    }
+
+    /// Returns the owners of a crate, or `None` if the crate has no recorded
+    /// owners (such as crates published before owners were recorded).
+    pub fn crate_owners(&self, name: &str) -> Result<Option<CrateOwners>> {
+        let owners_file = self.owners_file(name);
+        if std::fs::metadata(&owners_file).is_err() {
+            return Ok(None);
+        }
+
+        let mut contents = String::new();
+        std::fs::File::open(&owners_file).and_then(|mut f| f.read_to_string(&mut contents))?;
+
+        Ok(Some(serde_json::from_str(&contents)?))
+    }
+
+    fn write_crate_owners(&self, name: &str, owners: &CrateOwners) -> Result<PathBuf> {
+        let owners_file = self.owners_file(name);
+        std::fs::create_dir_all(owners_file.parent().unwrap())?;
+        let mut f = std::fs::File::create(&owners_file)?;
+        f.write_all(serde_json::to_string(owners)?.as_bytes())?;
+        f.write_all(b"\n")?;
+
+        Ok(owners_file)
+    }
+
+    pub fn set_crate_owners(&self, name: &str, owners: &CrateOwners, message: String) -> Result<()> {
+        let owners_file = self.write_crate_owners(name, owners)?;
+
+        self.commit(&[owners_file], message)?;
+
+        self.push()?;
+
+        Ok(())
+    }
+
+    /// Sets whether a version of a crate is yanked, then commits and pushes
+    /// the change with the given message.
+    pub fn set_yanked(&self, name: &str, vers: &str, yanked: bool, message: String) -> Result<()> {
+        let index_file = self.index_file(name);
+        if std::fs::metadata(&index_file).is_err() {
+            bail!(ErrorKind::ValidationError(format!("crate '{}' does not exist", name)));
+        }
+
+        let mut contents = String::new();
+        std::fs::File::open(&index_file).and_then(|mut f| f.read_to_string(&mut contents))?;
+
+        // Edit the entries as JSON values so that any fields which we do not
+        // know about are kept.
+        let mut found = false;
+        let mut new = String::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let mut entry: serde_json::Value = serde_json::from_str(line)?;
+            if entry["vers"].as_str() == Some(vers) {
+                entry["yanked"] = serde_json::Value::Bool(yanked);
+                found = true;
+            }
+            new.push_str(&serde_json::to_string(&entry)?);
+            new.push('\n');
+        }
+
+        if !found {
+            bail!(ErrorKind::ValidationError(format!("crate '{}' has no version '{}'", name, vers)));
+        }
+
+        std::fs::File::create(&index_file)?.write_all(new.as_bytes())?;
+
+        self.commit(&[index_file], message)?;
+
+        self.push()?;
+
+        Ok(())
+    }
+
+    /// Removes the lock file left behind if git was interrupted while
+    /// updating the index, returning whether there was a lock to remove.
+    pub fn force_unlock(&self) -> Result<bool> {
+        let lock_file = self.index_repo.path().join("index.lock");
+        if std::fs::metadata(&lock_file).is_err() {
+            return Ok(false);
+        }
+
+        std::fs::remove_file(&lock_file)?;
+
+        Ok(true)
+    }
+
     fn update_crate_index(&self, dst: &PathBuf, entry: &RegistryIndexEntry) -> Result<()> {
         std::fs::create_dir_all(dst.parent().unwrap())?;
         let mut prev = String::new();
@@ -167,13 +345,19 @@ impl Registry {
         Ok(())
     }
 
-    fn commit(&self, index_file: &PathBuf, message: String) -> Result<()> {
-        let mut index = self.index_repo.index()?;
+    // The path of a file relative to the root of the repository
+    fn repo_path(&self, file: &Path) -> PathBuf {
         let mut repo_path = self.index_repo.workdir().unwrap().iter();
-        let dst = index_file.iter()
+        file.iter()
             .skip_while(|s| Some(*s) == repo_path.next())
-            .collect::<PathBuf>();
-        index.add_path(&dst)?;
+            .collect::<PathBuf>()
+    }
+
+    fn commit(&self, files: &[PathBuf], message: String) -> Result<()> {
+        let mut index = self.index_repo.index()?;
+        for file in files {
+            index.add_path(&self.repo_path(file))?;
+        }
         index.write().unwrap();
         let tree_id = index.write_tree()?;
         let tree = self.index_repo.find_tree(tree_id)?;
@@ -192,6 +376,34 @@ impl Registry {
         Ok(())
     }
 
+    /// Puts files back as they are in HEAD, in both the working directory and
+    /// git's index, so that a change which failed part way through is not
+    /// picked up by the next commit. Files which are not in HEAD are removed.
+    fn restore(&self, files: &[PathBuf]) -> Result<()> {
+        let head = self.index_repo.head()?;
+        let head = self.index_repo.find_commit(head.target().unwrap())?;
+        let tree = head.tree()?;
+
+        let paths = files.iter().map(|file| self.repo_path(file)).collect::<Vec<_>>();
+        self.index_repo.reset_default(Some(head.as_object()), paths.iter().map(|path| path.as_path()))?;
+
+        for (file, path) in files.iter().zip(&paths) {
+            match tree.get_path(path) {
+                Ok(entry) => {
+                    let blob = self.index_repo.find_blob(entry.id())?;
+                    std::fs::File::create(file)?.write_all(blob.content())?;
+                }
+                Err(_) => {
+                    if std::fs::metadata(file).is_ok() {
+                        std::fs::remove_file(file)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn push(&self) -> Result<()> {
         let mut ref_status = None;
         let mut origin = self.index_repo.find_remote("origin")?;
@@ -216,21 +428,42 @@ impl Registry {
         }
     }
 
-    pub fn add_crate(&self, manifest: &CargoManifest, crate_tar: &[u8]) -> Result<()> {
-
-        let mut sha = Sha256::new();
-        sha.input(crate_tar);
+    /// Adds a crate to the index, `owners` should be set when this is the
+    /// first version of the crate so that they are committed with it.
+    pub fn add_crate(&self, manifest: &CargoManifest, crate_tar: &[u8], owners: Option<&CrateOwners>) -> Result<()> {
 
         // Convert the manifest into the registry index
-        let entry = RegistryIndexEntry::new(manifest, sha.result_str());
+        let entry = RegistryIndexEntry::new(manifest, checksum(crate_tar));
 
         let index_file = self.index_file(&entry.name);
+        let mut files = vec![index_file.clone()];
+        if owners.is_some() {
+            files.push(self.owners_file(&entry.name));
+        }
 
-        self.update_crate_index(&index_file, &entry)?;
-
-        self.commit(&index_file, format!("Adding {} {}", manifest.name, manifest.vers))?;
+        let committed = self.update_crate_index(&index_file, &entry)
+            .and_then(|()| {
+                match owners {
+                    Some(owners) => self.write_crate_owners(&entry.name, owners).map(|_| ()),
+                    None => Ok(()),
+                }
+            })
+            .and_then(|()| self.commit(&files, format!("Adding {} {}", manifest.name, manifest.vers)));
+
+        // Nothing was committed, so the files are put back as they were,
+        // otherwise the change would be included in the next commit.
+        if let Err(e) = committed {
+            if let Err(restore_error) = self.restore(&files) {
+                println!("Failed to restore the index after failing to add {} {}: {}", manifest.name, manifest.vers, restore_error);
+            }
+            return Err(e);
+        }
 
-        self.push()?;
+        // The version is in the index once it is committed, so this is
+        // reported separately
+        if let Err(e) = self.push() {
+            bail!(ErrorKind::IndexPushError(e.to_string()));
+        }
 
         Ok(())
     }
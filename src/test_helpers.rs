@@ -0,0 +1,37 @@
+//! Helpers shared by the tests of several modules.
+
+use std;
+use std::path::PathBuf;
+
+use serde_json;
+
+use registry::CargoManifest;
+
+/// Where a test keeps its files, which is unique to the test's name and this
+/// run of the tests.
+pub fn test_location(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("caesium-{}-{}", name, std::process::id()))
+}
+
+/// An empty directory for a test, anything left from an earlier test with
+/// the same name is removed.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = test_location(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The manifest that cargo sends when publishing a crate without any
+/// dependencies.
+pub fn manifest_json(name: &str, vers: &str) -> String {
+    format!(r#"{{"name": "{}", "vers": "{}", "deps": [], "features": {{}}, "authors": [],
+                 "description": null, "documentation": null, "homepage": null, "readme": null,
+                 "keywords": [], "categories": [], "license": null, "license_file": null,
+                 "repository": null, "badges": {{}}}}"#,
+            name, vers)
+}
+
+pub fn manifest(name: &str, vers: &str) -> CargoManifest {
+    serde_json::from_str(&manifest_json(name, vers)).unwrap()
+}
@@ -0,0 +1,144 @@
+//! A small HTTP server for testing the modules which call other services.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Clone)]
+pub struct TestRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+                    .find(|&&(ref header, _)| header.eq_ignore_ascii_case(name))
+                    .map(|&(_, ref value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn new(status: u16, body: &str) -> TestResponse {
+        TestResponse {
+            status: status,
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn json(body: &str) -> TestResponse {
+        TestResponse::new(200, body).header("Content-Type", "application/json")
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> TestResponse {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Answers every request on a local port with the given handler, and records
+/// the requests so tests can check what was sent.
+pub struct TestServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<TestRequest>>>,
+}
+
+impl TestServer {
+    pub fn start<F>(handler: F) -> TestServer
+        where F: Fn(&TestRequest) -> TestResponse + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let request = match read_request(&mut BufReader::new(&mut stream)) {
+                    Some(request) => request,
+                    None => continue,
+                };
+                recorded.lock().unwrap().push(request.clone());
+
+                let response = handler(&request);
+                let mut head = format!("HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+                                       response.status,
+                                       response.body.len());
+                for &(ref name, ref value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                head.push_str("\r\n");
+
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(&response.body);
+            }
+        });
+
+        TestServer {
+            url: url,
+            requests: requests,
+        }
+    }
+
+    /// The requests received so far.
+    pub fn requests(&self) -> Vec<TestRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// A URL which nothing is listening on.
+pub fn unreachable_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://{}", listener.local_addr().unwrap())
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Option<TestRequest> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.trim_right().splitn(3, ' ');
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_right();
+        if line.is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        let name = header.next()?.trim().to_string();
+        let value = header.next().unwrap_or("").trim().to_string();
+        headers.push((name, value));
+    }
+
+    let length = headers.iter()
+                        .find(|&&(ref name, _)| name.eq_ignore_ascii_case("Content-Length"))
+                        .and_then(|&(_, ref value)| value.parse::<usize>().ok())
+                        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(TestRequest {
+        method: method,
+        path: path,
+        headers: headers,
+        body: body,
+    })
+}
@@ -0,0 +1,166 @@
+use errors::*;
+
+use openssl::nid;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SslRef};
+use openssl::ssl::{SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_PEER};
+use openssl::x509::{X509NameRef, X509Ref, X509_FILETYPE_PEM};
+
+/// The details of a verified certificate that a client connected with.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub common_name: Option<String>,
+    pub organizational_units: Vec<String>,
+    pub dns_names: Vec<String>,
+}
+
+fn name_entries(name: &X509NameRef, nid: nid::Nid) -> Vec<String> {
+    name.entries_by_nid(nid)
+        .filter_map(|entry| entry.data().as_utf8().ok())
+        .map(|value| value.to_string())
+        .collect()
+}
+
+/// Builds the acceptor for TLS connections from the server config. When a CA
+/// bundle is configured clients are asked for a certificate, which must be
+/// signed by one of the CAs.
+pub fn create_acceptor(config: &::config::CaesiumTlsConfig) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptorBuilder::mozilla_intermediate_raw(SslMethod::tls())?;
+
+    {
+        let context = builder.builder_mut();
+        context.set_certificate_chain_file(&config.certificate)?;
+        context.set_private_key_file(&config.key, X509_FILETYPE_PEM)?;
+        context.check_private_key()?;
+
+        if let Some(ref client_ca) = config.client_ca {
+            context.set_ca_file(client_ca)?;
+
+            if config.client_certificate_required.unwrap_or(false) {
+                context.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT);
+            } else {
+                context.set_verify(SSL_VERIFY_PEER);
+            }
+        }
+    }
+
+    Ok(builder.build())
+}
+
+impl ClientCertificate {
+    pub fn new(certificate: &X509Ref) -> ClientCertificate {
+        let dns_names = certificate.subject_alt_names()
+            .map(|names| names.iter().filter_map(|name| name.dnsname().map(|name| name.to_string())).collect())
+            .unwrap_or_default();
+
+        ClientCertificate {
+            common_name: name_entries(certificate.subject_name(), nid::COMMONNAME).into_iter().next(),
+            organizational_units: name_entries(certificate.subject_name(), nid::ORGANIZATIONALUNITNAME),
+            dns_names: dns_names,
+        }
+    }
+}
+
+/// Returns the certificate that the client connected with, this has already
+/// been verified during the handshake.
+pub fn client_certificate(ssl: &SslRef) -> Option<ClientCertificate> {
+    ssl.peer_certificate().map(|certificate| ClientCertificate::new(&certificate))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use test_helpers::test_dir;
+
+    use std::fs::File;
+    use std::io::Write;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509, X509Builder, X509NameBuilder};
+    use openssl::x509::extension::SubjectAlternativeName;
+
+    /// Generates a self signed certificate with the given subject and DNS
+    /// names, along with its private key.
+    pub fn generate_certificate(common_name: Option<&str>, units: &[&str], dns_names: &[&str]) -> (X509, PKey) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        if let Some(common_name) = common_name {
+            name.append_entry_by_nid(nid::COMMONNAME, common_name).unwrap();
+        }
+        for unit in units {
+            name.append_entry_by_nid(nid::ORGANIZATIONALUNITNAME, unit).unwrap();
+        }
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+
+        if !dns_names.is_empty() {
+            let mut alt_names = SubjectAlternativeName::new();
+            for dns_name in dns_names {
+                alt_names.dns(dns_name);
+            }
+            let extension = alt_names.build(&builder.x509v3_context(None, None)).unwrap();
+            builder.append_extension(extension).unwrap();
+        }
+
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    #[test]
+    fn certificate_names_are_read() {
+        let (certificate, _) = generate_certificate(Some("build-bot"),
+                                                    &["deployers", "engineering"],
+                                                    &["build.example.com", "ci.example.com"]);
+        let certificate = ClientCertificate::new(&certificate);
+
+        assert_eq!(certificate.common_name, Some("build-bot".to_string()));
+        assert_eq!(certificate.organizational_units, vec!["deployers".to_string(), "engineering".to_string()]);
+        assert_eq!(certificate.dns_names, vec!["build.example.com".to_string(), "ci.example.com".to_string()]);
+    }
+
+    #[test]
+    fn missing_names_are_empty() {
+        let (certificate, _) = generate_certificate(None, &[], &[]);
+        let certificate = ClientCertificate::new(&certificate);
+
+        assert_eq!(certificate.common_name, None);
+        assert!(certificate.organizational_units.is_empty());
+        assert!(certificate.dns_names.is_empty());
+    }
+
+    #[test]
+    fn acceptors_are_created_from_files() {
+        let dir = test_dir("tls-acceptor");
+
+        let (server, server_key) = generate_certificate(Some("caesium.example.com"), &[], &["caesium.example.com"]);
+        let (client_ca, _) = generate_certificate(Some("Example CA"), &[], &[]);
+        let write = |name: &str, pem: Vec<u8>| {
+            let path = dir.join(name);
+            File::create(&path).unwrap().write_all(&pem).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+
+        let mut config = ::config::CaesiumTlsConfig {
+            certificate: write("server.pem", server.to_pem().unwrap()),
+            key: write("server.key", server_key.private_key_to_pem().unwrap()),
+            client_ca: Some(write("ca.pem", client_ca.to_pem().unwrap())),
+            client_certificate_required: Some(true),
+        };
+        assert!(create_acceptor(&config).is_ok());
+
+        // The key has to match the certificate
+        let (_, other_key) = generate_certificate(Some("other"), &[], &[]);
+        config.key = write("other.key", other_key.private_key_to_pem().unwrap());
+        assert!(create_acceptor(&config).is_err());
+    }
+}
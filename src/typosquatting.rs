@@ -0,0 +1,349 @@
+use std;
+use std::cmp;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use serde_json;
+
+use config::CaesiumTyposquattingPolicyConfig;
+use errors::*;
+use owners::CrateOwners;
+use registry::CargoManifest;
+use semver;
+use validation;
+
+const DEFAULT_MAX_DISTANCE: usize = 1;
+
+// Names shorter than this are too close to too many other names for the edit
+// distance to be meaningful, so they are only compared exactly.
+const MIN_DISTANCE_LENGTH: usize = 4;
+
+const PREFIXES: &'static [&'static str] = &["rust-", "rs-", "lib-"];
+const SUFFIXES: &'static [&'static str] = &["-rs", "-rust", "-lib", "-crate"];
+
+// Characters (or sequences) which look alike, mapped to a single form.
+const HOMOGLYPHS: &'static [(&'static str, &'static str)] = &[
+    ("rn", "m"),
+    ("vv", "w"),
+    ("0", "o"),
+    ("1", "l"),
+    ("i", "l"),
+    ("5", "s"),
+];
+
+/// What to do with a new crate whose name looks like another crate.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TyposquattingAction {
+    Reject,
+    Hold,
+}
+
+pub struct TyposquattingDetector {
+    popular_names: Vec<String>,
+    approved_names: Vec<String>,
+    max_distance: usize,
+    action: TyposquattingAction,
+    held_crates: Option<HeldCrates>,
+}
+
+impl TyposquattingDetector {
+    pub fn new(config: &CaesiumTyposquattingPolicyConfig) -> TyposquattingDetector {
+        let mut popular_names = config.popular_names.clone().unwrap_or_default();
+
+        if let Some(ref popular_names_file) = config.popular_names_file {
+            let mut contents = String::new();
+            std::fs::File::open(popular_names_file).and_then(|mut f| f.read_to_string(&mut contents))
+                                                   .expect("Failed to read popular names file");
+            popular_names.extend(contents.lines()
+                                         .map(|line| line.trim())
+                                         .filter(|line| !line.is_empty())
+                                         .map(|line| line.to_string()));
+        }
+
+        let action = config.action.unwrap_or(TyposquattingAction::Reject);
+        let held_crates = config.hold_location.as_ref().map(|location| HeldCrates::new(location));
+        if action == TyposquattingAction::Hold && held_crates.is_none() {
+            panic!("hold_location must be set to hold crates for approval");
+        }
+
+        TyposquattingDetector {
+            popular_names: popular_names,
+            approved_names: config.approved.clone().unwrap_or_default(),
+            max_distance: config.max_distance.unwrap_or(DEFAULT_MAX_DISTANCE),
+            action: action,
+            held_crates: held_crates,
+        }
+    }
+
+    /// Checks a new crate name against the existing and popular names. Based
+    /// on the configured action this either rejects the crate, or returns the
+    /// reason that it should be held for approval.
+    pub fn check(&self, name: &str, existing_names: &[String]) -> Result<Option<String>> {
+        let normalized = validation::normalize_name(name);
+        if self.approved_names.iter().any(|approved| validation::normalize_name(approved) == normalized) {
+            return Ok(None);
+        }
+
+        let similar = match self.find_similar(name, existing_names) {
+            Some(similar) => similar,
+            None => return Ok(None),
+        };
+
+        let reason = format!("crate name '{}' is similar to '{}'", name, similar);
+        match self.action {
+            TyposquattingAction::Reject => Err(ErrorKind::ValidationError(reason).into()),
+            TyposquattingAction::Hold => Ok(Some(reason)),
+        }
+    }
+
+    /// Stores a crate which has been held, so that an administrator can
+    /// review it and publish it as the user who uploaded it.
+    pub fn hold(&self, manifest: &CargoManifest, tar: &[u8], publisher: Option<&str>, owners: Option<&CrateOwners>) -> Result<()> {
+        match self.held_crates {
+            Some(ref held_crates) => held_crates.store(manifest, tar, publisher, owners),
+            None => bail!("No location configured for held crates"),
+        }
+    }
+
+    pub fn held_crates(&self) -> Option<&HeldCrates> {
+        self.held_crates.as_ref()
+    }
+
+    /// Returns the first of the existing or popular names which `name` looks
+    /// like it is imitating. Only the crate's own entry in the existing names
+    /// is skipped, a new crate taking a popular name exactly is similar.
+    pub fn find_similar(&self, name: &str, existing_names: &[String]) -> Option<String> {
+        let name_skeleton = skeleton(name);
+
+        existing_names.iter()
+                      .filter(|candidate| candidate.as_str() != name)
+                      .chain(self.popular_names.iter())
+                      .find(|candidate| self.is_similar(&name_skeleton, &skeleton(candidate)))
+                      .cloned()
+    }
+
+    fn is_similar(&self, a: &str, b: &str) -> bool {
+        if a == b {
+            return true;
+        }
+
+        if a.len() < MIN_DISTANCE_LENGTH || b.len() < MIN_DISTANCE_LENGTH {
+            return false;
+        }
+
+        edit_distance(a, b) <= self.max_distance
+    }
+}
+
+/// Crates which are waiting for approval, stored on disk using the same
+/// layout as the file storage, with the manifest and publisher alongside the
+/// crate.
+pub struct HeldCrates {
+    location: PathBuf,
+}
+
+/// A crate which is waiting for approval.
+pub struct HeldCrate {
+    pub manifest: CargoManifest,
+    pub tar: Vec<u8>,
+    pub publisher: Option<String>,
+    pub owners: Option<CrateOwners>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HeldPublisher {
+    sub: Option<String>,
+    owners: Option<CrateOwners>,
+}
+
+impl HeldCrates {
+    pub fn new(location: &String) -> HeldCrates {
+        HeldCrates {
+            location: PathBuf::from(location),
+        }
+    }
+
+    fn store(&self, manifest: &CargoManifest, tar: &[u8], publisher: Option<&str>, owners: Option<&CrateOwners>) -> Result<()> {
+        let dir = self.location.join(&manifest.name).join(&manifest.vers);
+        std::fs::create_dir_all(&dir)?;
+
+        let publisher = HeldPublisher {
+            sub: publisher.map(|sub| sub.to_string()),
+            owners: owners.cloned(),
+        };
+
+        File::create(dir.join("manifest.json"))?.write_all(serde_json::to_string(manifest)?.as_bytes())?;
+        File::create(dir.join("publisher.json"))?.write_all(serde_json::to_string(&publisher)?.as_bytes())?;
+        File::create(dir.join("download"))?.write_all(tar)?;
+
+        Ok(())
+    }
+
+    /// The directory of a held crate, the name and version come from the
+    /// request so they are checked before being used as a path.
+    fn dir(&self, name: &str, vers: &str) -> Result<PathBuf> {
+        validation::validate_name(name)?;
+        if semver::Version::parse(vers).is_err() {
+            bail!(ErrorKind::ValidationError(format!("invalid version '{}'", vers)));
+        }
+
+        let dir = self.location.join(name).join(vers);
+        if !dir.join("download").exists() {
+            bail!(ErrorKind::ValidationError(format!("crate '{}' v{} is not held for approval", name, vers)));
+        }
+
+        Ok(dir)
+    }
+
+    pub fn load(&self, name: &str, vers: &str) -> Result<HeldCrate> {
+        let dir = self.dir(name, vers)?;
+
+        let mut manifest = String::new();
+        File::open(dir.join("manifest.json"))?.read_to_string(&mut manifest)?;
+        let mut publisher = String::new();
+        File::open(dir.join("publisher.json"))?.read_to_string(&mut publisher)?;
+        let publisher: HeldPublisher = serde_json::from_str(&publisher)?;
+        let mut tar = Vec::new();
+        File::open(dir.join("download"))?.read_to_end(&mut tar)?;
+
+        Ok(HeldCrate {
+            manifest: serde_json::from_str(&manifest)?,
+            tar: tar,
+            publisher: publisher.sub,
+            owners: publisher.owners,
+        })
+    }
+
+    pub fn remove(&self, name: &str, vers: &str) -> Result<()> {
+        std::fs::remove_dir_all(self.dir(name, vers)?)?;
+
+        // Only removed once no other versions are held
+        let _ = std::fs::remove_dir(self.location.join(name));
+
+        Ok(())
+    }
+}
+
+/// Reduces a name to a form where separators, case, look-alike characters and
+/// common prefixes or suffixes no longer distinguish it from other names.
+fn skeleton(name: &str) -> String {
+    let mut name = name.to_lowercase().replace('_', "-");
+
+    for prefix in PREFIXES {
+        if name.starts_with(prefix) && name.len() > prefix.len() {
+            name = name[prefix.len()..].to_string();
+        }
+    }
+
+    for suffix in SUFFIXES {
+        if name.ends_with(suffix) && name.len() > suffix.len() {
+            let len = name.len() - suffix.len();
+            name.truncate(len);
+        }
+    }
+
+    let mut name = name.replace('-', "");
+    for &(from, to) in HOMOGLYPHS {
+        name = name.replace(from, to);
+    }
+
+    name
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(cmp::min(substitution, cmp::min(previous[j + 1], current[j]) + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toml;
+
+    fn detector(config: &str) -> TyposquattingDetector {
+        TyposquattingDetector::new(&toml::from_str(config).unwrap())
+    }
+
+    #[test]
+    fn skeleton_ignores_case_separators_and_affixes() {
+        assert_eq!(skeleton("Serde_JSON"), "serdejson");
+        assert_eq!(skeleton("rust-serde-json"), "serdejson");
+        assert_eq!(skeleton("serde-json-rs"), "serdejson");
+        assert_eq!(skeleton("lib-serde-json-crate"), "serdejson");
+
+        // A name which is only a prefix or suffix is kept
+        assert_eq!(skeleton("rust-"), "rust");
+        assert_eq!(skeleton("-rs"), "rs");
+    }
+
+    #[test]
+    fn skeleton_replaces_homoglyphs() {
+        assert_eq!(skeleton("rnodern"), skeleton("modem"));
+        assert_eq!(skeleton("vvasm"), skeleton("wasm"));
+        assert_eq!(skeleton("t0kio"), skeleton("tokio"));
+        assert_eq!(skeleton("1og"), skeleton("log"));
+        assert_eq!(skeleton("tokio"), skeleton("tokl0"));
+        assert_eq!(skeleton("5erde"), skeleton("serde"));
+    }
+
+    #[test]
+    fn edit_distance_counts_insertions_deletions_and_substitutions() {
+        assert_eq!(edit_distance("serde", "serde"), 0);
+        assert_eq!(edit_distance("serdejsn", "serdejson"), 1);
+        assert_eq!(edit_distance("serdejson", "serdejsn"), 1);
+        assert_eq!(edit_distance("serde", "sarde"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "tokio"), 5);
+    }
+
+    #[test]
+    fn similar_names_are_found() {
+        let typosquatting = detector("popular_names = [\"serde-json\", \"serde\", \"log\"]");
+        let existing = vec!["our-core-utils".to_string()];
+
+        assert_eq!(typosquatting.find_similar("serde-jsn", &existing), Some("serde-json".to_string()));
+        assert_eq!(typosquatting.find_similar("our_core-utils", &existing), Some("our-core-utils".to_string()));
+        assert_eq!(typosquatting.find_similar("rust-serde", &existing), Some("serde".to_string()));
+        assert_eq!(typosquatting.find_similar("LOG", &existing), Some("log".to_string()));
+
+        // Short names are only compared exactly
+        assert_eq!(typosquatting.find_similar("lag", &existing), None);
+        assert_eq!(typosquatting.find_similar("tokio", &existing), None);
+    }
+
+    #[test]
+    fn exact_popular_names_are_similar() {
+        let popular = detector("popular_names = [\"serde\"]");
+        assert_eq!(popular.find_similar("serde", &[]), Some("serde".to_string()));
+
+        // But a crate's own entry in the index is not
+        let existing = detector("");
+        assert_eq!(existing.find_similar("serde", &["serde".to_string()]), None);
+    }
+
+    #[test]
+    fn approved_names_are_allowed() {
+        let typosquatting = detector("popular_names = [\"serde-json\"]\napproved = [\"serde_jsn\"]");
+        assert_eq!(typosquatting.check("serde-jsn", &[]).unwrap(), None);
+
+        let e = typosquatting.check("serde-jsom", &[]).unwrap_err();
+        match *e.kind() {
+            ErrorKind::ValidationError(_) => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std;
+use std::path::PathBuf;
+
+use validation;
+
+/// An offline copy of an upstream index (such as crates.io), either a clone
+/// of the git index or a sparse mirror, both of which use the same layout.
+pub struct UpstreamIndex {
+    location: PathBuf,
+}
+
+impl UpstreamIndex {
+    pub fn new(location: &str) -> UpstreamIndex {
+        let location = PathBuf::from(location);
+
+        if !location.is_dir() {
+            panic!("Upstream index not found at {}", location.display());
+        }
+
+        UpstreamIndex {
+            location: location,
+        }
+    }
+
+    /// Returns the name of the upstream crate that collides with `name`, using
+    /// the same rules as crates.io where case and '-'/'_' are ignored.
+    pub fn find(&self, name: &str) -> Option<String> {
+        let normalized = validation::normalize_name(name);
+
+        for dir in self.candidate_dirs(&normalized) {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                if let Some(file_name) = entry.file_name().to_str() {
+                    if validation::normalize_name(file_name) == normalized {
+                        return Some(file_name.to_string());
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    // The index directories are derived from the start of the name, so when
+    // that includes separators the crate could be in any of the variants.
+    fn candidate_dirs(&self, name: &str) -> Vec<PathBuf> {
+        match name.len() {
+            1 => vec![self.location.join("1")],
+            2 => vec![self.location.join("2")],
+            3 => vec![self.location.join("3").join(&name[..1])],
+            _ => separator_variants(&name[..4]).iter()
+                                               .map(|prefix| self.location.join(&prefix[0..2]).join(&prefix[2..4]))
+                                               .collect(),
+        }
+    }
+}
+
+fn separator_variants(prefix: &str) -> Vec<String> {
+    let mut variants = vec![String::new()];
+
+    for c in prefix.chars() {
+        let separators = if c == '-' { vec!['-', '_'] } else { vec![c] };
+        variants = variants.iter()
+                           .flat_map(|variant| separators.iter().map(move |s| format!("{}{}", variant, s)))
+                           .collect();
+    }
+
+    variants
+}
@@ -0,0 +1,191 @@
+use std::path::{Component, Path};
+
+use flate2::read::GzDecoder;
+use semver;
+use tar;
+
+use errors::*;
+use registry::{CargoManifest, Registry};
+use upstream::UpstreamIndex;
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Warnings returned to cargo alongside a successful publish, this mirrors the
+/// structure used by crates.io so that cargo displays them to the user.
+#[derive(Debug, Default, Serialize)]
+pub struct PublishWarnings {
+    pub invalid_categories: Vec<String>,
+    pub invalid_badges: Vec<String>,
+    pub other: Vec<String>,
+}
+
+/// Normalizes a crate name in the same way as crates.io does when checking
+/// whether two names refer to the same crate.
+pub fn normalize_name(name: &str) -> String {
+    name.to_lowercase().replace('_', "-")
+}
+
+fn invalid(message: String) -> Error {
+    ErrorKind::ValidationError(message).into()
+}
+
+/// Checks that the crate name only uses the characters allowed by crates.io.
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(invalid(format!("crate name must be between 1 and {} characters", MAX_NAME_LENGTH)));
+    }
+
+    if !name.chars().next().map_or(false, |c| c.is_ascii_alphabetic()) {
+        return Err(invalid(format!("crate name '{}' must start with a letter", name)));
+    }
+
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(invalid(format!("crate name '{}' may only contain letters, numbers, '-' or '_'", name)));
+    }
+
+    Ok(())
+}
+
+/// Checks that the version is valid semver and has not already been
+/// published to the index.
+pub fn validate_version(manifest: &CargoManifest, registry: &Registry) -> Result<()> {
+    let version = semver::Version::parse(&manifest.vers)
+        .map_err(|e| invalid(format!("invalid version '{}': {}", manifest.vers, e)))?;
+
+    for entry in registry.crate_entries(&manifest.name)? {
+        if entry.name != manifest.name {
+            return Err(invalid(format!("crate already exists with the name '{}'", entry.name)));
+        }
+
+        match semver::Version::parse(&entry.vers) {
+            Ok(ref existing) if *existing == version => {
+                return Err(invalid(format!("crate version '{}' is already uploaded", entry.vers)));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn same_registry(a: &str, b: &str) -> bool {
+    fn normalize(url: &str) -> &str {
+        let url = url.trim_right_matches('/');
+        if url.ends_with(".git") {
+            &url[..url.len() - 4]
+        } else {
+            url
+        }
+    }
+
+    normalize(a) == normalize(b)
+}
+
+/// Checks that a new crate does not share its name with a crate in the
+/// upstream index, unless it has been explicitly allowed. This prevents a
+/// public crate of the same name being picked up instead of ours.
+pub fn validate_upstream_name(manifest: &CargoManifest,
+                              registry: &Registry,
+                              upstream: &UpstreamIndex,
+                              allowed: &[String]) -> Result<()> {
+    if !registry.crate_entries(&manifest.name)?.is_empty() {
+        return Ok(());
+    }
+
+    let normalized = normalize_name(&manifest.name);
+    if allowed.iter().any(|name| normalize_name(name) == normalized) {
+        return Ok(());
+    }
+
+    match upstream.find(&manifest.name) {
+        Some(upstream_name) => Err(invalid(format!("crate name '{}' collides with the upstream crate '{}'",
+                                                   manifest.name, upstream_name))),
+        None => Ok(()),
+    }
+}
+
+/// Checks that every dependency comes from an allowed registry, and that
+/// dependencies on this registry can actually be satisfied by the index.
+pub fn validate_dependencies(manifest: &CargoManifest,
+                             registry: &Registry,
+                             index: &str,
+                             allowed_registries: &[String]) -> Result<()> {
+    for dep in &manifest.deps {
+        match dep.registry {
+            Some(ref dep_registry) if !same_registry(dep_registry, index) => {
+                if !allowed_registries.iter().any(|allowed| same_registry(allowed, dep_registry)) {
+                    return Err(invalid(format!("dependency '{}' is from registry '{}' which is not allowed",
+                                               dep.name, dep_registry)));
+                }
+            }
+            _ => {
+                // The name is used to find the crate's file in the index
+                validate_name(&dep.name)?;
+
+                let req = semver::VersionReq::parse(&dep.version_req)
+                    .map_err(|e| invalid(format!("dependency '{}' has invalid version requirement '{}': {}",
+                                                 dep.name, dep.version_req, e)))?;
+
+                let satisfied = registry.crate_entries(&dep.name)?.iter().any(|entry| {
+                    !entry.yanked && entry.name == dep.name &&
+                        semver::Version::parse(&entry.vers).map(|vers| req.matches(&vers)).unwrap_or(false)
+                });
+
+                if !satisfied {
+                    return Err(invalid(format!("dependency '{} {}' does not match any crate in this registry",
+                                               dep.name, dep.version_req)));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the uploaded tarball is a gzipped tar archive with all files
+/// under the `{name}-{vers}` directory, as produced by `cargo package`.
+pub fn validate_tarball(manifest: &CargoManifest, crate_tar: &[u8]) -> Result<()> {
+    let prefix = format!("{}-{}", manifest.name, manifest.vers);
+    let mut archive = tar::Archive::new(GzDecoder::new(crate_tar));
+    let mut has_manifest = false;
+
+    let entries = archive.entries()
+        .map_err(|e| invalid(format!("failed to read crate tarball: {}", e)))?;
+
+    for entry in entries {
+        let entry = entry.map_err(|e| invalid(format!("failed to read crate tarball: {}", e)))?;
+        let path = entry.path()
+            .map_err(|e| invalid(format!("invalid path in crate tarball: {}", e)))?
+            .into_owned();
+
+        if !path.starts_with(&prefix) || path.components().any(|c| c == Component::ParentDir) {
+            return Err(invalid(format!("invalid path in crate tarball: {}", path.display())));
+        }
+
+        if path == Path::new(&prefix).join("Cargo.toml") {
+            has_manifest = true;
+        }
+    }
+
+    if !has_manifest {
+        return Err(invalid(format!("crate tarball does not contain {}/Cargo.toml", prefix)));
+    }
+
+    Ok(())
+}
+
+/// Collects warnings about metadata which should be present, but whose
+/// absence does not stop the crate from being published.
+pub fn metadata_warnings(manifest: &CargoManifest) -> PublishWarnings {
+    let mut warnings = PublishWarnings::default();
+
+    if manifest.description.is_none() {
+        warnings.other.push("manifest has no description".to_string());
+    }
+
+    if manifest.license.is_none() && manifest.license_file.is_none() {
+        warnings.other.push("manifest has no license or license-file".to_string());
+    }
+
+    warnings
+}